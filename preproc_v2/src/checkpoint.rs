@@ -0,0 +1,170 @@
+//! Periodic checkpoints of long XML scans.
+//!
+//! A checkpoint holds the byte offset the scan has reached in its input, together with the
+//! in-memory state built from everything before that offset. Resuming seeks the input to the
+//! offset and continues with the saved state. Checkpoints are removed once their layer finishes.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use clap::Args;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+
+#[derive(Args, Clone)]
+pub struct CheckpointArgs {
+    /// Checkpoint file; defaults to OUTFILE with `.ckpt` appended
+    #[arg(long = "checkpoint", conflicts_with = "memory_budget")]
+    pub checkpoint: Option<PathBuf>,
+    /// Bytes of input scanned between checkpoints, 0 to disable
+    #[arg(long = "checkpoint-interval", default_value_t = 4 << 30, conflicts_with = "memory_budget")]
+    pub checkpoint_interval: u64,
+    /// Continue from the checkpoint of an interrupted run
+    #[arg(long = "resume", conflicts_with = "memory_budget")]
+    pub resume: bool,
+}
+
+impl CheckpointArgs {
+    pub fn disabled() -> Self {
+        CheckpointArgs {
+            checkpoint: None,
+            checkpoint_interval: 0,
+            resume: false,
+        }
+    }
+
+    /// The checkpointer for a scan of `input` by a layer writing `outfile`.
+    pub fn checkpointer(&self, input: &Path, outfile: &Path) -> Result<Checkpointer> {
+        let path = self.checkpoint.clone().unwrap_or_else(|| {
+            let mut path = OsString::from(outfile);
+            path.push(".ckpt");
+            PathBuf::from(path)
+        });
+        Ok(Checkpointer {
+            path,
+            input: input.to_path_buf(),
+            input_len: std::fs::metadata(input)?.len(),
+            interval: self.checkpoint_interval,
+            resume: self.resume,
+            last_offset: 0,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Checkpoint<S> {
+    input: PathBuf,
+    input_len: u64,
+    offset: u64,
+    state: S,
+}
+
+pub struct Checkpointer {
+    path: PathBuf,
+    input: PathBuf,
+    input_len: u64,
+    interval: u64,
+    resume: bool,
+    last_offset: u64,
+}
+
+impl Checkpointer {
+    /// Loads the saved `(offset, state)` when resuming and a checkpoint exists.
+    pub fn resume<S: DeserializeOwned>(&mut self) -> Result<Option<(u64, S)>> {
+        if !self.resume || !self.path.exists() {
+            return Ok(None);
+        }
+        let reader = BufReader::new(File::open(&self.path)?);
+        let checkpoint: Checkpoint<S> = bincode::deserialize_from(reader)
+            .map_err(|e| Error::Checkpoint(format!("failed to read {}: {e}", self.path.display())))?;
+        if checkpoint.input_len != self.input_len {
+            return Err(Error::Checkpoint(format!(
+                "{} was taken over {} ({} bytes), but {} is {} bytes",
+                self.path.display(), checkpoint.input.display(), checkpoint.input_len,
+                self.input.display(), self.input_len,
+            )));
+        }
+        println!("Resuming from byte {} of {} ({})", checkpoint.offset, self.input.display(), self.path.display());
+        self.last_offset = checkpoint.offset;
+        Ok(Some((checkpoint.offset, checkpoint.state)))
+    }
+
+    /// Saves `state` if the scan has advanced at least one interval past the last checkpoint.
+    pub fn maybe_save<S: Serialize>(&mut self, offset: u64, state: &S) -> Result<()> {
+        if self.interval == 0 || offset < self.last_offset + self.interval {
+            return Ok(());
+        }
+        self.save(offset, state)
+    }
+
+    /// Saves `state` as of `offset`. The previous checkpoint is only replaced once the new one
+    /// has been written in full.
+    pub fn save<S: Serialize>(&mut self, offset: u64, state: &S) -> Result<()> {
+        if self.interval == 0 {
+            return Ok(());
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let checkpoint = Checkpoint {
+            input: self.input.clone(),
+            input_len: self.input_len,
+            offset,
+            state,
+        };
+        bincode::serialize_into(&mut writer, &checkpoint)
+            .map_err(|e| Error::Checkpoint(format!("failed to write {}: {e}", self.path.display())))?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.last_offset = offset;
+        Ok(())
+    }
+
+    /// Saves `state` as of the end of the input, so that resuming skips the scan altogether.
+    pub fn save_finished<S: Serialize>(&mut self, state: &S) -> Result<()> {
+        self.save(self.input_len, state)
+    }
+
+    /// Removes the checkpoint after its layer has finished.
+    pub fn clear(self) -> Result<()> {
+        if self.path.exists() {
+            std::fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    #[test]
+    fn checkpoints_resume_the_saved_state() {
+        let dir = TestDir::new("checkpoint");
+        let input = PathBuf::from(dir.write("Votes.xml", "0123456789"));
+        let outfile = dir.path().join("layer3.tsv");
+        let saved = dir.path().join("layer3.tsv.ckpt");
+        let args = CheckpointArgs { checkpoint: None, checkpoint_interval: 4, resume: true };
+        let mut checkpoints = args.checkpointer(&input, &outfile).unwrap();
+        assert_eq!(checkpoints.resume::<Vec<u32>>().unwrap(), None);
+
+        // only once the scan has gone an interval past the last checkpoint
+        checkpoints.maybe_save(3, &vec![1]).unwrap();
+        assert!(!saved.exists());
+        checkpoints.maybe_save(5, &vec![1, 2]).unwrap();
+        let mut resumed = args.checkpointer(&input, &outfile).unwrap();
+        assert_eq!(resumed.resume::<Vec<u32>>().unwrap(), Some((5, vec![1, 2])));
+
+        // not without --resume, nor over an input of another length
+        let fresh = CheckpointArgs { resume: false, ..args.clone() };
+        assert_eq!(fresh.checkpointer(&input, &outfile).unwrap().resume::<Vec<u32>>().unwrap(), None);
+        std::fs::write(&input, "0123").unwrap();
+        assert!(matches!(args.checkpointer(&input, &outfile).unwrap().resume::<Vec<u32>>(), Err(Error::Checkpoint(_))));
+
+        resumed.clear().unwrap();
+        assert!(!saved.exists());
+    }
+}
@@ -0,0 +1,166 @@
+//! Parallel scanning of a dump in byte ranges.
+//!
+//! A dump file is cut into chunks of roughly `--chunk-size` bytes, each starting at a `<row `
+//! element (or at the start of the file), so every chunk can be read on its own with a
+//! [`RowReader`]. Chunks are mapped on a thread pool `--jobs` at a time, and their results are
+//! merged back in file order, which keeps the output identical to a single-threaded scan.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom, Take};
+use std::ops::Range;
+use std::path::Path;
+use clap::Args;
+use indicatif::{ProgressBar, ProgressBarIter};
+use rayon::prelude::*;
+use crate::error::Result;
+use crate::xml::RowReader;
+
+/// Every row element starts with this; `<` is always escaped inside attribute values.
+const ROW_START: &[u8] = b"<row ";
+
+#[derive(Args, Clone)]
+pub struct ChunkArgs {
+    /// Chunks scanned in parallel, 0 for one per core
+    #[arg(long = "jobs", default_value_t = 0)]
+    pub jobs: usize,
+    /// Approximate size in bytes of each chunk of the input
+    #[arg(long = "chunk-size", default_value_t = 64 << 20)]
+    pub chunk_size: u64,
+}
+
+impl ChunkArgs {
+    pub fn sequential() -> Self {
+        ChunkArgs {
+            jobs: 1,
+            chunk_size: u64::MAX,
+        }
+    }
+
+    /// Cuts `path` from byte `start` (a row boundary) to its end into chunks.
+    pub fn chunks(&self, path: &Path, start: u64) -> Result<Vec<Range<u64>>> {
+        let mut file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut chunks = Vec::new();
+        let mut chunk_start = start;
+        while chunk_start < len {
+            let chunk_end = match chunk_start.checked_add(self.chunk_size.max(1)) {
+                Some(end) if end < len => next_row_start(&mut file, end)?.unwrap_or(len),
+                _ => len,
+            };
+            chunks.push(chunk_start..chunk_end);
+            chunk_start = chunk_end;
+        }
+        Ok(chunks)
+    }
+
+    /// Maps `chunks` in parallel and merges each result into `state`, in file order. The chunks
+    /// of a batch are all mapped against the same `state` before any of them is merged.
+    pub fn for_each_chunk<S, T, M, F>(&self, chunks: &[Range<u64>], state: &mut S, map: M, mut merge: F) -> Result<()>
+    where
+        S: Sync,
+        T: Send,
+        M: Fn(&S, &Range<u64>) -> Result<T> + Sync,
+        F: FnMut(&mut S, &Range<u64>, T) -> Result<()>,
+    {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.jobs)
+            .build()
+            .map_err(std::io::Error::other)?;
+        for batch in chunks.chunks(pool.current_num_threads()) {
+            let mapped = {
+                let state = &*state;
+                pool.install(|| batch.par_iter().map(|chunk| map(state, chunk)).collect::<Vec<_>>())
+            };
+            for (chunk, result) in batch.iter().zip(mapped) {
+                merge(state, chunk, result?)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Offset of the first row element at or after `offset`, if any.
+fn next_row_start(file: &mut File, offset: u64) -> Result<Option<u64>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut reader = BufReader::new(file);
+    let mut buf = Vec::new();
+    let mut buf_offset = offset;
+    loop {
+        let mut block = [0; 64 << 10];
+        let n = reader.read(&mut block)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&block[..n]);
+        if let Some(i) = buf.windows(ROW_START.len()).position(|w| w == ROW_START) {
+            return Ok(Some(buf_offset + i as u64));
+        }
+        // keep the tail in case a row start straddles two blocks
+        let keep = ROW_START.len() - 1;
+        let drop = buf.len().saturating_sub(keep);
+        buf.drain(..drop);
+        buf_offset += drop as u64;
+    }
+}
+
+/// Progress bar over the bytes of `path`, starting at `offset`, for the chunks to advance.
+pub fn chunk_progress_bar(path: &Path, offset: u64) -> Result<ProgressBar> {
+    let pb = crate::progress_bar_bytes(std::fs::metadata(path)?.len());
+    pb.set_position(offset);
+    Ok(pb)
+}
+
+/// Reads the rows of one chunk of `path`, advancing `pb` by the bytes read.
+pub fn open_chunk(path: &Path, chunk: &Range<u64>, pb: &ProgressBar) -> Result<RowReader<BufReader<ProgressBarIter<Take<File>>>>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(chunk.start))?;
+    let reader = BufReader::new(pb.wrap_read(file.take(chunk.end - chunk.start)));
+    Ok(RowReader::resume_at(reader, chunk.start))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+    use crate::testing::{TestDir, VOTES_XML};
+
+    #[test]
+    fn chunks_start_at_rows_and_cover_the_dump() {
+        let dir = TestDir::new("chunks");
+        let votes = PathBuf::from(dir.write("Votes.xml", VOTES_XML));
+        let len = VOTES_XML.len() as u64;
+        // the XML declaration and root element, then a chunk for each of the 4 rows
+        let chunks = ChunkArgs { jobs: 3, chunk_size: 1 }.chunks(&votes, 0).unwrap();
+        assert_eq!(chunks.len(), 5);
+        assert_eq!((chunks[0].start, chunks[4].end), (0, len));
+        for pair in chunks.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+            assert!(VOTES_XML[pair[1].start as usize..].starts_with("<row "));
+        }
+        assert_eq!(ChunkArgs::sequential().chunks(&votes, 0).unwrap(), vec![0..len]);
+        // a resumed scan is cut from its offset
+        assert_eq!(ChunkArgs::sequential().chunks(&votes, chunks[2].start).unwrap(), vec![chunks[2].start..len]);
+    }
+
+    #[test]
+    fn chunk_results_merge_in_file_order() {
+        let dir = TestDir::new("chunks-merge");
+        let votes = PathBuf::from(dir.write("Votes.xml", VOTES_XML));
+        let args = ChunkArgs { jobs: 3, chunk_size: 1 };
+        let chunks = args.chunks(&votes, 0).unwrap();
+        let pb = ProgressBar::hidden();
+        let mut merged = Vec::new();
+        args.for_each_chunk(&chunks, &mut merged, |_, chunk| {
+            let mut rows = open_chunk(&votes, chunk, &pb)?;
+            Ok(std::iter::from_fn(|| rows.next_map(|_| Ok(Some(())))).map(|row| row.unwrap().0).collect::<Vec<_>>())
+        }, |merged, _, offsets| {
+            merged.extend(offsets);
+            Ok(())
+        }).unwrap();
+
+        let mut rows = RowReader::new(VOTES_XML.as_bytes());
+        let offsets = std::iter::from_fn(|| rows.next_map(|_| Ok(Some(())))).map(|row| row.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(offsets.len(), 4);
+        assert_eq!(merged, offsets);
+    }
+}
@@ -0,0 +1,257 @@
+//! Errors raised while processing a dump, and the per-row policy for handling them.
+//!
+//! Row-level problems (a malformed attribute, a missing one, a bad TSV line) are reported
+//! as a [`RowError`] carrying the byte offset of the row in its input. Whether such a row aborts
+//! the layer or is skipped and recorded in a rejects file is decided by [`Rejects`], according
+//! to the `--on-error` policy. I/O errors always abort.
+
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use clap::{Args, ValueEnum};
+use crate::tsv::{RowParseError, TsvWriter};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Xml(quick_xml::Error),
+    /// An XML attribute that is present but cannot be interpreted.
+    BadAttribute {
+        name: &'static str,
+        value: String,
+        reason: String,
+    },
+    MissingAttribute(&'static str),
+    Row(RowParseError),
+    /// A checkpoint that cannot be written, read or resumed from.
+    Checkpoint(String),
+    /// A PostHistory.xml index that cannot be built or used.
+    Index(String),
+    /// A file of rows spilled past `--memory-budget` that cannot be written or read back.
+    Spill(String),
+    /// Arguments that do not go together.
+    Usage(String),
+    /// A dump file whose sampled rows lack an attribute the layer needs.
+    Schema {
+        input: PathBuf,
+        attribute: &'static str,
+    },
+    /// A row error that aborted a layer.
+    Aborted {
+        layer: &'static str,
+        input: PathBuf,
+        offset: u64,
+        source: Box<Error>,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn bad_attribute(name: &'static str, value: &[u8], reason: impl Display) -> Self {
+        Error::BadAttribute {
+            name,
+            value: String::from_utf8_lossy(value).into_owned(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Whether no policy may skip past this error.
+    fn is_fatal(&self) -> bool {
+        matches!(self, Error::Io(_) | Error::Schema { .. } | Error::Aborted { .. })
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {e}"),
+            Error::Xml(e) => write!(f, "XML error: {e}"),
+            Error::BadAttribute { name, value, reason } => write!(f, "bad {name} {value:?}: {reason}"),
+            Error::MissingAttribute(name) => write!(f, "missing {name}"),
+            Error::Row(e) => write!(f, "{e}"),
+            Error::Checkpoint(reason) => write!(f, "checkpoint: {reason}"),
+            Error::Index(reason) => write!(f, "index: {reason}"),
+            Error::Spill(reason) => write!(f, "spilled rows: {reason}"),
+            Error::Usage(reason) => write!(f, "{reason}"),
+            Error::Schema { input, attribute } => {
+                write!(f, "{} has no {attribute} attribute in its first {} rows", input.display(), crate::schema::SCHEMA_SAMPLE_ROWS)
+            }
+            Error::Aborted { layer, input, offset, source } => {
+                write!(f, "{layer} aborted at byte {offset} of {}: {source}", input.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Xml(e) => Some(e),
+            Error::Row(e) => Some(e),
+            Error::Aborted { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<quick_xml::Error> for Error {
+    fn from(e: quick_xml::Error) -> Self {
+        match e {
+            quick_xml::Error::Io(e) => Error::Io(std::io::Error::new(e.kind(), e.to_string())),
+            e => Error::Xml(e),
+        }
+    }
+}
+
+impl From<quick_xml::events::attributes::AttrError> for Error {
+    fn from(e: quick_xml::events::attributes::AttrError) -> Self {
+        Error::Xml(e.into())
+    }
+}
+
+impl From<RowParseError> for Error {
+    fn from(e: RowParseError) -> Self {
+        Error::Row(e)
+    }
+}
+
+/// An error in the row starting at byte `offset` of an input.
+#[derive(Debug)]
+pub struct RowError {
+    pub offset: u64,
+    pub error: Error,
+}
+
+impl RowError {
+    pub fn new(offset: u64, error: impl Into<Error>) -> Self {
+        RowError { offset, error: error.into() }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ErrorPolicy {
+    /// Record the row in the rejects file (if any) and carry on
+    Skip,
+    /// Stop the layer at the first bad row
+    Abort,
+}
+
+#[derive(Args, Clone)]
+pub struct ErrorArgs {
+    #[arg(long = "on-error", value_enum, default_value_t = ErrorPolicy::Abort)]
+    pub on_error: ErrorPolicy,
+    /// File that skipped rows are appended to, as `layer<TAB>input<TAB>offset<TAB>reason`
+    #[arg(long = "rejects-file")]
+    pub rejects_file: Option<PathBuf>,
+}
+
+impl ErrorArgs {
+    pub fn abort() -> Self {
+        ErrorArgs {
+            on_error: ErrorPolicy::Abort,
+            rejects_file: None,
+        }
+    }
+
+    /// Opens the rejects file (if any) for the rows `layer` reads from `input`.
+    pub fn rejects(&self, layer: &'static str, input: &Path) -> Result<Rejects> {
+        let writer = match &self.rejects_file {
+            Some(path) => Some(TsvWriter::new(OpenOptions::new()
+                .create(true).append(true).open(path)?, 0)),
+            None => None,
+        };
+        Ok(Rejects {
+            layer,
+            input: input.to_path_buf(),
+            policy: self.on_error,
+            writer,
+            count: 0,
+        })
+    }
+}
+
+struct Reject<'a> {
+    layer: &'static str,
+    input: &'a Path,
+    offset: u64,
+    error: &'a Error,
+}
+
+impl Display for Reject<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let reason = self.error.to_string().replace(['\t', '\r', '\n'], " ");
+        write!(f, "{}\t{}\t{}\t{}", self.layer, self.input.display(), self.offset, reason)
+    }
+}
+
+/// Applies the error policy of one layer to its row errors.
+pub struct Rejects {
+    layer: &'static str,
+    input: PathBuf,
+    policy: ErrorPolicy,
+    writer: Option<TsvWriter<File>>,
+    count: u64,
+}
+
+impl Rejects {
+    /// A policy that aborts at the first bad row, for library callers.
+    pub fn abort(layer: &'static str) -> Self {
+        Rejects {
+            layer,
+            input: PathBuf::new(),
+            policy: ErrorPolicy::Abort,
+            writer: None,
+            count: 0,
+        }
+    }
+
+    /// Passes a good row through, and either skips (returning `None`) or aborts on a bad one.
+    pub fn check<T>(&mut self, row: std::result::Result<T, RowError>) -> Result<Option<T>> {
+        let RowError { offset, error } = match row {
+            Ok(row) => return Ok(Some(row)),
+            Err(e) => e,
+        };
+        if error.is_fatal() {
+            return Err(error);
+        }
+        match self.policy {
+            ErrorPolicy::Abort => Err(Error::Aborted {
+                layer: self.layer,
+                input: self.input.clone(),
+                offset,
+                source: Box::new(error),
+            }),
+            ErrorPolicy::Skip => {
+                if let Some(writer) = &mut self.writer {
+                    writer.write_row(&Reject { layer: self.layer, input: &self.input, offset, error: &error })?;
+                }
+                self.count += 1;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Number of rows skipped so far.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Flushes the rejects file and reports the number of skipped rows.
+    pub fn finish(self) -> Result<u64> {
+        if self.count > 0 {
+            println!("Skipped {} bad rows of {} in {}.", self.count, self.input.display(), self.layer);
+        }
+        if let Some(writer) = self.writer {
+            writer.finish()?;
+        }
+        Ok(self.count)
+    }
+}
@@ -0,0 +1,386 @@
+//! On-disk index of the rows of PostHistory.xml by PostId.
+//!
+//! The index lists every row of the dump as the post it belongs to, where it lies in the dump
+//! and its PostHistoryTypeId, sorted by post id and then by offset. Entries have a fixed size,
+//! so the rows of one post are found by binary search without loading the index, while a layer
+//! that needs the rows of many posts reads it front to back. The index records the length of
+//! the dump it was built from and a hash of its first megabyte, and refuses to serve a dump
+//! that differs in either.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use crate::chunks::{chunk_progress_bar, open_chunk, ChunkArgs};
+use crate::error::{Error, ErrorArgs, Result};
+use crate::input::is_compressed;
+use crate::PostId;
+use crate::schema::detect_schema;
+use crate::spill::MemoryArgs;
+use crate::xml::parse_attribute;
+
+#[derive(Args)]
+pub struct IndexArgs {
+    /// PostHistory.xml, extracted: a compressed dump cannot be read at an offset
+    #[arg(long = "in-file", required=true)]
+    pub infile: PathBuf,
+    /// Index file; defaults to IN_FILE with `.idx` appended
+    #[arg(long = "index")]
+    pub index: Option<PathBuf>,
+    /// Print the rows of these posts (comma-separated) through an existing index instead of
+    /// building one
+    #[arg(long = "lookup", value_delimiter = ',')]
+    pub lookup: Vec<PostId>,
+    #[command(flatten)]
+    pub errors: ErrorArgs,
+    #[command(flatten)]
+    pub chunks: ChunkArgs,
+    #[command(flatten)]
+    pub memory: MemoryArgs,
+}
+
+const MAGIC: &[u8; 8] = b"PHIDX\0\0\x02";
+/// The magic, the length of the dump, the hash of its head and the number of entries.
+const HEADER_LEN: u64 = 32;
+const ENTRY_LEN: u64 = 24;
+/// Bytes at the start of the dump that the index hashes.
+const HEAD_LEN: u64 = 1 << 20;
+
+/// Where a row of PostHistory.xml lies in the dump.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub post_id: PostId,
+    /// Byte offset of the row element.
+    pub offset: u64,
+    /// Length of the row element in bytes.
+    pub length: u32,
+    pub history_type: u8,
+}
+
+impl IndexEntry {
+    fn to_bytes(self) -> [u8; ENTRY_LEN as usize] {
+        let mut bytes = [0; ENTRY_LEN as usize];
+        bytes[0..8].copy_from_slice(&self.post_id.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.length.to_le_bytes());
+        bytes[20] = self.history_type;
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; ENTRY_LEN as usize]) -> Self {
+        IndexEntry {
+            post_id: PostId::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            offset: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            length: u32::from_le_bytes(bytes[16..20].try_into().unwrap()),
+            history_type: bytes[20],
+        }
+    }
+
+    /// Reads the row element from the dump.
+    pub fn read_row<R: Read + Seek>(&self, dump: &mut R) -> Result<Vec<u8>> {
+        dump.seek(SeekFrom::Start(self.offset))?;
+        let mut row = vec![0; self.length as usize];
+        dump.read_exact(&mut row)?;
+        Ok(row)
+    }
+}
+
+/// Path of the index of the dump at `infile`, when none is given.
+pub fn default_index_path(infile: &Path) -> PathBuf {
+    let mut path = OsString::from(infile);
+    path.push(".idx");
+    PathBuf::from(path)
+}
+
+/// The length of the dump at `path` and the FNV-1a hash of its first [`HEAD_LEN`] bytes, which
+/// tell the dump an index was built from apart from another.
+fn dump_fingerprint(path: &Path) -> Result<(u64, u64)> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut head = Vec::new();
+    file.take(HEAD_LEN).read_to_end(&mut head)?;
+    let hash = head.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    Ok((len, hash))
+}
+
+/// An index file opened for lookups.
+pub struct PostHistoryIndex {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+
+impl PostHistoryIndex {
+    /// Opens the index at `path`, checking that it was built from `dump`.
+    pub fn open(path: &Path, dump: &Path) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let mut header = [0; HEADER_LEN as usize];
+        file.read_exact(&mut header)
+            .map_err(|e| Error::Index(format!("failed to read {}: {e}", path.display())))?;
+        if header[0..7] != MAGIC[0..7] {
+            return Err(Error::Index(format!("{} is not a PostHistory.xml index", path.display())));
+        }
+        if header[7] != MAGIC[7] {
+            return Err(Error::Index(format!("{} was built by another version; rebuild it", path.display())));
+        }
+        let dump_len = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let dump_hash = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let len = u64::from_le_bytes(header[24..32].try_into().unwrap());
+        let (actual_len, actual_hash) = dump_fingerprint(dump)?;
+        if dump_len != actual_len {
+            return Err(Error::Index(format!("{} was built from a {dump_len} byte dump, not {}; rebuild it",
+                path.display(), dump.display())));
+        }
+        if dump_hash != actual_hash {
+            return Err(Error::Index(format!("{} was built from another dump than {}; rebuild it",
+                path.display(), dump.display())));
+        }
+        if file.metadata()?.len() != HEADER_LEN + len * ENTRY_LEN {
+            return Err(Error::Index(format!("{} is truncated", path.display())));
+        }
+        Ok(PostHistoryIndex { path: path.to_path_buf(), file, len })
+    }
+
+    /// Number of rows indexed.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn entry(&mut self, i: u64) -> Result<IndexEntry> {
+        self.file.seek(SeekFrom::Start(HEADER_LEN + i * ENTRY_LEN))?;
+        let mut bytes = [0; ENTRY_LEN as usize];
+        self.file.read_exact(&mut bytes)?;
+        Ok(IndexEntry::from_bytes(&bytes))
+    }
+
+    /// The rows of `post_id`, in dump order.
+    pub fn lookup(&mut self, post_id: PostId) -> Result<Vec<IndexEntry>> {
+        // the first entry of a post at or after it
+        let (mut lo, mut hi) = (0, self.len);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if self.entry(mid)?.post_id < post_id {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let mut entries = Vec::new();
+        for i in lo..self.len {
+            let entry = self.entry(i)?;
+            if entry.post_id != post_id {
+                break;
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Every entry, in post id and dump order.
+    pub fn entries(&self) -> Result<impl Iterator<Item = Result<IndexEntry>>> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(HEADER_LEN))?;
+        let mut reader = BufReader::new(file);
+        let mut remaining = self.len;
+        Ok(std::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let mut bytes = [0; ENTRY_LEN as usize];
+            Some(reader.read_exact(&mut bytes)
+                .map(|()| IndexEntry::from_bytes(&bytes))
+                .map_err(Error::from))
+        }))
+    }
+}
+
+/// The post id and PostHistoryTypeId of a row.
+fn index_parse_row(element: &quick_xml::events::BytesStart) -> Result<Option<(PostId, u8)>> {
+    let mut post_id = None;
+    let mut history_type = None;
+    for attr in element.attributes() {
+        let attr = attr?;
+        match attr.key.as_ref() {
+            b"PostId" => post_id = Some(parse_attribute("PostId", &attr.value)?),
+            b"PostHistoryTypeId" => history_type = Some(parse_attribute("PostHistoryTypeId", &attr.value)?),
+            _ => (),
+        }
+        if post_id.is_some() && history_type.is_some() {
+            break;
+        }
+    }
+    Ok(Some((
+        post_id.ok_or(Error::MissingAttribute("PostId"))?,
+        history_type.ok_or(Error::MissingAttribute("PostHistoryTypeId"))?,
+    )))
+}
+
+/// Indexes the rows of the PostHistory.xml at `infile` into `index`. Returns the number of rows
+/// indexed.
+fn index_build(args: &IndexArgs, index: &Path) -> Result<u64> {
+    if is_compressed(&args.infile) {
+        return Err(Error::Index(format!("{} is compressed; extract it to index it", args.infile.display())));
+    }
+    detect_schema(&args.infile, &["PostId", "PostHistoryTypeId"], &[])?;
+    let mut rejects = args.errors.rejects("index", &args.infile)?;
+    let chunks = args.chunks.chunks(&args.infile, 0)?;
+    let pb = chunk_progress_bar(&args.infile, 0)?;
+    println!("Indexing {} in {} chunks", args.infile.display(), chunks.len());
+
+    let mut entries = args.memory.sorter("index", |entry: &IndexEntry| *entry);
+    args.chunks.for_each_chunk(&chunks, &mut (), |_, chunk| {
+        let mut rows = open_chunk(&args.infile, chunk, &pb)?;
+        Ok(std::iter::from_fn(|| {
+            let row = rows.next_map(index_parse_row)?;
+            let end = rows.position();
+            Some(row.map(|(offset, (post_id, history_type))| IndexEntry {
+                post_id,
+                offset,
+                length: (end - offset) as u32,
+                history_type,
+            }))
+        }).collect::<Vec<_>>())
+    }, |_, _, rows| {
+        for row in rows {
+            if let Some(entry) = rejects.check(row)? {
+                entries.push(entry)?;
+            }
+        }
+        Ok(())
+    })?;
+    pb.finish();
+    rejects.finish()?;
+
+    println!("Sorting {} rows by post id, spilling {} runs to disk", entries.len(), entries.runs());
+    let len = entries.len();
+    let (dump_len, dump_hash) = dump_fingerprint(&args.infile)?;
+
+    let mut tmp_path = index.as_os_str().to_os_string();
+    tmp_path.push(".tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&dump_len.to_le_bytes())?;
+    writer.write_all(&dump_hash.to_le_bytes())?;
+    writer.write_all(&len.to_le_bytes())?;
+    for entry in entries.finish()? {
+        writer.write_all(&entry?.to_bytes())?;
+    }
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&tmp_path, index)?;
+    println!("Wrote the index to {}", index.display());
+
+    Ok(len)
+}
+
+/// Prints the rows of the `--lookup` posts. Returns the number of rows printed.
+fn index_lookup(args: &IndexArgs, index: &Path) -> Result<u64> {
+    let mut index = PostHistoryIndex::open(index, &args.infile)?;
+    let mut dump = BufReader::new(File::open(&args.infile)?);
+    let mut printed = 0;
+    for &post_id in &args.lookup {
+        for entry in index.lookup(post_id)? {
+            let row = entry.read_row(&mut dump)?;
+            println!("{}", String::from_utf8_lossy(&row));
+            printed += 1;
+        }
+    }
+    Ok(printed)
+}
+
+/// Builds the index of `--in-file`, or looks up the `--lookup` posts in it. Returns the number
+/// of rows indexed or printed.
+pub fn index_run(args: &IndexArgs) -> Result<u64> {
+    let index = args.index.clone().unwrap_or_else(|| default_index_path(&args.infile));
+    if args.lookup.is_empty() {
+        index_build(args, &index)
+    } else {
+        index_lookup(args, &index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer_2::layer2_filter;
+    use crate::testing::{parse_args, TestDir, LAYER1_TSV, LAYER2_TSV, POST_HISTORY_XML};
+
+    #[test]
+    fn indexes_find_the_rows_of_a_post() {
+        let dir = TestDir::new("index");
+        let post_history = dir.write("PostHistory.xml", POST_HISTORY_XML);
+        // built a row per chunk, the rows of a post still come out in dump order
+        let args = ["--in-file", &post_history, "--chunk-size", "1", "--jobs", "3"];
+        assert_eq!(index_run(&parse_args(&args)).unwrap(), 14);
+        let index_path = default_index_path(Path::new(&post_history));
+        let mut index = PostHistoryIndex::open(&index_path, Path::new(&post_history)).unwrap();
+        assert_eq!(index.len(), 14);
+
+        let entries = index.lookup(1).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.history_type).collect::<Vec<_>>(), [1, 2, 3, 5]);
+        let mut dump = File::open(&post_history).unwrap();
+        let row = String::from_utf8(entries[3].read_row(&mut dump).unwrap()).unwrap();
+        assert!(row.starts_with("<row Id=\"5\"") && row.ends_with("/>"), "{row}");
+        assert!(index.lookup(6).unwrap().is_empty());
+        assert!(index.lookup(0).unwrap().is_empty());
+
+        let entries = index.entries().unwrap().collect::<Result<Vec<_>>>().unwrap();
+        assert!(entries.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(entries.len(), 14);
+
+        let args = ["--in-file", &post_history, "--lookup", "3,4"];
+        assert_eq!(index_run(&parse_args(&args)).unwrap(), 5);
+    }
+
+    #[test]
+    fn indexes_refuse_another_dump() {
+        let dir = TestDir::new("index-other");
+        let post_history = dir.write("PostHistory.xml", POST_HISTORY_XML);
+        let index = dir.file("PostHistory.idx");
+        let args = ["--in-file", &post_history, "--index", &index];
+        index_run(&parse_args(&args)).unwrap();
+        let open = |dump: &str| PostHistoryIndex::open(Path::new(&index), Path::new(dump));
+        assert!(open(&post_history).is_ok());
+
+        // the same length with another head, and another length
+        let edited = dir.write("Edited.xml", &POST_HISTORY_XML.replacen("Title one", "Title One", 1));
+        assert!(matches!(open(&edited), Err(Error::Index(_))));
+        let longer = dir.write("Longer.xml", &format!("{POST_HISTORY_XML}\n"));
+        assert!(matches!(open(&longer), Err(Error::Index(_))));
+        std::fs::write(&index, b"not an index").unwrap();
+        assert!(matches!(open(&post_history), Err(Error::Index(_))));
+
+        let compressed = dir.write("PostHistory.xml.gz", "");
+        let args = ["--in-file", &compressed];
+        assert!(matches!(index_run(&parse_args(&args)), Err(Error::Index(_))));
+    }
+
+    #[test]
+    fn layers_read_the_rows_of_their_posts_through_an_index() {
+        let dir = TestDir::new("index-layer2");
+        let post_history = dir.write("PostHistory.xml", POST_HISTORY_XML);
+        let index = dir.file("PostHistory.idx");
+        index_run(&parse_args(&["--in-file", &post_history, "--index", &index])).unwrap();
+        let layer1 = dir.write("layer1.tsv", LAYER1_TSV);
+        let out = dir.file("layer2.tsv");
+        for budget in [None, Some("1")] {
+            let mut args = vec!["--in-file", &post_history, "--in-layer-1", &layer1, "--out-file", &out, "--index", &index];
+            args.extend(budget.map(|budget| ["--memory-budget", budget]).into_iter().flatten());
+            assert_eq!(layer2_filter(&parse_args(&args)).unwrap(), 2);
+            assert_eq!(std::fs::read_to_string(&out).unwrap(), LAYER2_TSV);
+        }
+
+        // an index of another dump is refused rather than read at the wrong offsets
+        let other = dir.write("Other.xml", &format!("{POST_HISTORY_XML}\n"));
+        let args = ["--in-file", &other, "--in-layer-1", &layer1, "--out-file", &out, "--index", &index];
+        assert!(matches!(layer2_filter(&parse_args(&args)), Err(Error::Index(_))));
+    }
+}
@@ -0,0 +1,225 @@
+//! Transparent decompression of dump files.
+//!
+//! Inputs are recognised by extension: `.gz`, `.zst`, `.bz2` and `.7z` are decompressed while
+//! reading, anything else is read as plain XML. Compressed inputs can only be read front to back,
+//! so they are scanned sequentially rather than in chunks, and resuming from a checkpoint means
+//! decompressing (and discarding) everything before the checkpoint again.
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read};
+use std::path::Path;
+use std::sync::mpsc::{sync_channel, Receiver};
+use indicatif::ProgressBar;
+use sevenz_rust::{Password, SevenZReader};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Bzip2,
+    SevenZ,
+}
+
+impl Compression {
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(OsStr::to_str) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            Some("bz2") => Compression::Bzip2,
+            Some("7z") => Compression::SevenZ,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// Whether `path` has to be read as a stream.
+pub fn is_compressed(path: &Path) -> bool {
+    Compression::of(path) != Compression::None
+}
+
+/// Opens `path` for reading its decompressed contents. The progress bar is sized to and advanced
+/// by the bytes read from the file itself, compressed or not.
+pub fn open_input(path: &Path) -> io::Result<(Box<dyn BufRead + Send>, ProgressBar)> {
+    let file = File::open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+    let len = file.metadata()?.len();
+    let pb = crate::progress_bar_bytes(len);
+    let file = pb.wrap_read(file);
+    let reader: Box<dyn BufRead + Send> = match Compression::of(path) {
+        Compression::None => Box::new(BufReader::new(file)),
+        Compression::Gzip => Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(BufReader::new(file)))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::new(file)?)),
+        Compression::Bzip2 => Box::new(BufReader::new(bzip2::read::MultiBzDecoder::new(BufReader::new(file)))),
+        Compression::SevenZ => {
+            let entry = sevenz_entry(path)?;
+            let archive = SevenZReader::new(file, len, Password::empty()).map_err(sevenz_error)?;
+            Box::new(BufReader::new(SevenZEntryReader::spawn(archive, entry)))
+        }
+    };
+    Ok((reader, pb))
+}
+
+/// Like [`open_input`], but discards the first `offset` decompressed bytes.
+pub fn open_input_from(path: &Path, offset: u64) -> io::Result<(Box<dyn BufRead + Send>, ProgressBar)> {
+    let (mut reader, pb) = open_input(path)?;
+    let skipped = io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+    if skipped < offset {
+        return Err(io::Error::new(ErrorKind::UnexpectedEof,
+            format!("{} ends at byte {skipped}, before {offset}", path.display())));
+    }
+    Ok((reader, pb))
+}
+
+/// The XML file to read from a `.7z` archive: its only entry, or else the one named after the
+/// archive (`Posts.xml` in `stackoverflow.com-Posts.7z`).
+fn sevenz_entry(path: &Path) -> io::Result<String> {
+    let archive = SevenZReader::open(path, Password::empty()).map_err(sevenz_error)?;
+    let names = archive.archive().files.iter()
+        .filter(|entry| entry.has_stream())
+        .map(|entry| entry.name().to_string())
+        .collect::<Vec<_>>();
+    if let [name] = names.as_slice() {
+        return Ok(name.clone());
+    }
+    let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+    let wanted = format!("{}.xml", stem.rsplit('-').next().unwrap_or(stem));
+    names.into_iter()
+        .find(|name| name.rsplit('/').next() == Some(wanted.as_str()))
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound,
+            format!("{} has no single XML entry and no {wanted}", path.display())))
+}
+
+fn sevenz_error(e: sevenz_rust::Error) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+/// Streams one entry of a 7z archive, which is decoded on a separate thread since the archive
+/// reader only hands entries to a callback.
+struct SevenZEntryReader {
+    blocks: Receiver<io::Result<Vec<u8>>>,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl SevenZEntryReader {
+    fn spawn<R: Read + io::Seek + Send + 'static>(mut archive: SevenZReader<R>, entry: String) -> Self {
+        let (sender, blocks) = sync_channel(16);
+        std::thread::spawn(move || {
+            let mut found = false;
+            let result = archive.for_each_entries(|e, reader| {
+                if e.name() != entry {
+                    return Ok(true);
+                }
+                found = true;
+                loop {
+                    let mut block = vec![0; 1 << 20];
+                    let n = reader.read(&mut block).map_err(sevenz_rust::Error::io)?;
+                    if n == 0 {
+                        return Ok(false);
+                    }
+                    block.truncate(n);
+                    if sender.send(Ok(block)).is_err() {
+                        // the reader was dropped
+                        return Ok(false);
+                    }
+                }
+            });
+            let error = match result {
+                Err(e) => sevenz_error(e),
+                Ok(()) if !found => io::Error::new(ErrorKind::NotFound, format!("no {entry} in archive")),
+                Ok(()) => return,
+            };
+            let _ = sender.send(Err(error));
+        });
+        SevenZEntryReader {
+            blocks,
+            block: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for SevenZEntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.block.len() {
+            match self.blocks.recv() {
+                Ok(block) => {
+                    self.block = block?;
+                    self.pos = 0;
+                }
+                // the decoder thread finished the entry
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use super::*;
+    use crate::layer_2::layer2_filter;
+    use crate::testing::{parse_args, TestDir, LAYER1_TSV, LAYER2_TSV, POST_HISTORY_XML};
+
+    /// `POST_HISTORY_XML` compressed as `file` is, by its extension.
+    fn compressed(file: &str) -> Vec<u8> {
+        let xml = POST_HISTORY_XML.as_bytes();
+        match Compression::of(Path::new(file)) {
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(xml).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Zstd => zstd::encode_all(xml, 0).unwrap(),
+            Compression::Bzip2 => {
+                let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+                encoder.write_all(xml).unwrap();
+                encoder.finish().unwrap()
+            }
+            _ => xml.to_vec(),
+        }
+    }
+
+    #[test]
+    fn compressed_dumps_read_as_plain_xml() {
+        let dir = TestDir::new("input");
+        for file in ["PostHistory.xml", "PostHistory.xml.gz", "PostHistory.xml.zst", "PostHistory.xml.bz2"] {
+            let path = dir.path().join(file);
+            std::fs::write(&path, compressed(file)).unwrap();
+            assert_eq!(is_compressed(&path), !file.ends_with("xml"), "{file}");
+
+            let (mut reader, _) = open_input(&path).unwrap();
+            let mut xml = String::new();
+            reader.read_to_string(&mut xml).unwrap();
+            assert_eq!(xml, POST_HISTORY_XML, "{file}");
+
+            let (mut reader, _) = open_input_from(&path, 100).unwrap();
+            let mut rest = String::new();
+            reader.read_to_string(&mut rest).unwrap();
+            assert_eq!(rest, POST_HISTORY_XML[100..], "{file}");
+            let past_the_end = POST_HISTORY_XML.len() as u64 + 1;
+            assert_eq!(open_input_from(&path, past_the_end).err().unwrap().kind(), ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn layers_read_compressed_dumps() {
+        let dir = TestDir::new("input-layer2");
+        let post_history = dir.file("PostHistory.xml.gz");
+        std::fs::write(&post_history, compressed(&post_history)).unwrap();
+        let layer1 = dir.write("layer1.tsv", LAYER1_TSV);
+        let out = dir.file("layer2.tsv");
+        // compressed dumps are scanned as a stream even when asked for chunks
+        for jobs in ["1", "3"] {
+            let args = ["--in-file", &post_history, "--in-layer-1", &layer1, "--out-file", &out, "--jobs", jobs];
+            assert_eq!(layer2_filter(&parse_args(&args)).unwrap(), 2);
+            assert_eq!(std::fs::read_to_string(&out).unwrap(), LAYER2_TSV);
+        }
+    }
+}
@@ -1,91 +1,584 @@
-use core::slice::SlicePattern;
-use std::fs::OpenOptions;
-use std::io::{BufWriter, Write};
+use std::convert::Infallible;
+use std::fmt::{Display, Formatter};
+use std::io::BufRead;
 use std::path::PathBuf;
 use std::str::FromStr;
-use clap::Args;
-use quick_xml::events::Event;
-use quick_xml::Reader;
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::{Args, ValueEnum};
+use quick_xml::events::attributes::Attribute;
+use quick_xml::events::BytesStart;
+use serde::{Deserialize, Serialize};
+use crate::PostId;
+use crate::schema::detect_schema;
+use crate::chunks::{chunk_progress_bar, open_chunk, ChunkArgs};
+use crate::input::{is_compressed, open_input};
+use crate::output::CompressArgs;
+use crate::error::{Error, ErrorArgs, Result, RowError};
+use crate::xml::{parse_attribute, parse_date_attribute, RowReader};
+use crate::tsv::{parse_column, split_columns, RowParseError, TsvReader, TsvWriter};
 
 #[derive(Args)]
 pub struct Layer1Args {
     #[arg(long = "in-file", required=true)]
-    infile: PathBuf,
+    pub infile: PathBuf,
     #[arg(long = "out-file", required=true)]
-    outfile: PathBuf,
-    #[arg(long = "pcount", default_value_t=115443102)]
-    p_count: u64,
+    pub outfile: PathBuf,
     #[arg(long = "flush-interval", default_value_t=1_000_000)]
-    flush_interval: usize,
-}
-
-pub fn layer1_filter(args: &Layer1Args) {
-    let mut reader = Reader::from_file(&args.infile)
-        .expect("Failed to open INFILE for reading");
-    let underlying_stream = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&args.outfile)
-        .expect("Failed to open OUTFILE for writing");
-    let mut writer = BufWriter::new(underlying_stream);
-    let bar = crate::progress_bar(args.p_count);
-
-    let mut buf = Vec::new();
-
-    loop {
-        bar.inc(1);
-        match reader.read_event_into(&mut buf) {
-            Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-            Ok(Event::Eof) => break,
-            Ok(Event::Empty(element)) => {
-                if element.name().as_ref() == b"row" {
-                    let attrs = element.attributes();
-                    let mut post_id = -1;
-                    let mut author_id = -1;
-                    let mut last_editor_user_id = -1;
-                    let mut required_fields = 0;
-                    const REQUIRED_CHECKS: i32 = 4;
-                    for attr in attrs.map(Result::unwrap) {
-                        let attr_key = attr.key.as_ref();
-                        if attr_key == b"Id" {
-                            required_fields += 1;
-                            post_id = i64::from_str(std::str::from_utf8(attr.value.as_slice()).unwrap()).unwrap();
-                        }
-                        if attr_key == b"PostTypeId" {
-                            if attr.value.as_ref() == b"1" {
-                                required_fields += 1;
-                            } else {
-                                break
-                            }
-                        }
-                        if attr_key == b"OwnerUserId" {
-                            author_id = i32::from_str(std::str::from_utf8(attr.value.as_slice()).unwrap()).unwrap();
-                            required_fields += 1;
-                        }
-                        if attr_key == b"LastEditorUserId" {
-                            last_editor_user_id = i32::from_str(std::str::from_utf8(attr.value.as_slice()).unwrap()).unwrap();
-                            required_fields += 1;
-                        }
-                        if required_fields == REQUIRED_CHECKS {
-                            break
-                        }
-                    }
-                    debug_assert!(required_fields <= REQUIRED_CHECKS);
-                    if required_fields == REQUIRED_CHECKS
-                        && last_editor_user_id != author_id
-                    {
-                        writeln!(writer, "{post_id}\t{author_id}").unwrap();
-                        if writer.buffer().len() >= args.flush_interval {
-                            writer.flush().unwrap();
-                        }
-                    }
+    pub flush_interval: usize,
+    #[command(flatten)]
+    pub select: SelectArgs,
+    /// Posts.xml attributes to carry through to the final splits (comma-separated)
+    #[arg(long = "carry", value_enum, value_delimiter = ',')]
+    pub carry: Vec<PostAttribute>,
+    #[command(flatten)]
+    pub errors: ErrorArgs,
+    #[command(flatten)]
+    pub output: CompressArgs,
+    #[command(flatten)]
+    pub chunks: ChunkArgs,
+}
+
+/// Which posts Layer1 selects.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum PostTypes {
+    #[default]
+    Questions,
+    Answers,
+    Both,
+}
+
+impl PostTypes {
+    fn selects(self, post_type_id: &[u8]) -> bool {
+        match self {
+            PostTypes::Questions => post_type_id == b"1",
+            PostTypes::Answers => post_type_id == b"2",
+            PostTypes::Both => post_type_id == b"1" || post_type_id == b"2",
+        }
+    }
+}
+
+// Which posts Layer1 selects, besides having an owner and having been edited.
+// Tags, answers, accepted answers, closing and community ownership are only recorded on
+// questions, so those predicates leave answers out when given.
+#[derive(Args, Clone, Default)]
+pub struct SelectArgs {
+    /// Posts to build edit pairs from; answers are paired with their question as context
+    #[arg(long = "post-types", value_enum, default_value_t = PostTypes::Questions)]
+    pub post_types: PostTypes,
+    #[arg(long = "min-score")]
+    pub min_score: Option<i64>,
+    #[arg(long = "max-score")]
+    pub max_score: Option<i64>,
+    /// Keep questions with any of these tags (repeatable or comma-separated)
+    #[arg(long = "tag", value_delimiter = ',')]
+    pub tags: Vec<String>,
+    /// Drop questions with any of these tags (repeatable or comma-separated)
+    #[arg(long = "exclude-tag", value_delimiter = ',')]
+    pub exclude_tags: Vec<String>,
+    /// Keep posts created on or after this date (YYYY-MM-DD)
+    #[arg(long = "created-from")]
+    pub created_from: Option<NaiveDate>,
+    /// Keep posts created before this date (YYYY-MM-DD)
+    #[arg(long = "created-before")]
+    pub created_before: Option<NaiveDate>,
+    #[arg(long = "min-answers")]
+    pub min_answers: Option<u32>,
+    #[arg(long = "max-answers")]
+    pub max_answers: Option<u32>,
+    /// Keep only questions with (true) or without (false) an accepted answer
+    #[arg(long = "has-accepted-answer")]
+    pub has_accepted_answer: Option<bool>,
+    /// Keep only questions that are (true) or are not (false) currently closed; a reopened
+    /// question is not
+    #[arg(long = "closed")]
+    pub closed: Option<bool>,
+    /// Keep only questions that are (true) or are not (false) community owned
+    #[arg(long = "community-owned")]
+    pub community_owned: Option<bool>,
+    /// Keep posts whose owner's account was deleted, identified by their display name
+    #[arg(long = "keep-deleted-owners")]
+    pub keep_deleted_owners: bool,
+}
+
+impl SelectArgs {
+    /// Whether any predicate needs more than the attributes every selected post is checked for.
+    fn filters(&self) -> bool {
+        self.min_score.is_some() || self.max_score.is_some()
+            || !self.tags.is_empty() || !self.exclude_tags.is_empty()
+            || self.created_from.is_some() || self.created_before.is_some()
+            || self.question_only()
+    }
+
+    /// Whether any predicate is on an attribute only questions have (besides tags).
+    fn question_only(&self) -> bool {
+        self.min_answers.is_some() || self.max_answers.is_some()
+            || self.has_accepted_answer.is_some() || self.closed.is_some()
+            || self.community_owned.is_some()
+    }
+
+    fn accepts(&self, post: &PostAttributes, tags: &Tags, is_answer: bool) -> bool {
+        if is_answer && (self.question_only() || !self.tags.is_empty()) {
+            return false;
+        }
+        if !within(post.score, self.min_score, self.max_score) {
+            return false;
+        }
+        if !self.tags.is_empty() && !tags.0.iter().any(|tag| self.tags.contains(tag)) {
+            return false;
+        }
+        if tags.0.iter().any(|tag| self.exclude_tags.contains(tag)) {
+            return false;
+        }
+        let created = post.created.map(|created| created.date());
+        let last_created = self.created_before.and_then(|before| before.pred_opt());
+        if !within(created, self.created_from, last_created)
+            || !within(post.answer_count, self.min_answers, self.max_answers)
+        {
+            return false;
+        }
+        self.has_accepted_answer.is_none_or(|wanted| post.has_accepted_answer == wanted)
+            && self.closed.is_none_or(|wanted| post.closed == wanted)
+            && self.community_owned.is_none_or(|wanted| post.community_owned == wanted)
+    }
+}
+
+/// Whether `value` lies between `min` and `max` inclusive, where given; a missing value only
+/// passes without bounds.
+fn within<T: PartialOrd>(value: Option<T>, min: Option<T>, max: Option<T>) -> bool {
+    match value {
+        Some(value) => min.is_none_or(|min| value >= min) && max.is_none_or(|max| value <= max),
+        None => min.is_none() && max.is_none(),
+    }
+}
+
+/// The Posts.xml attributes [`SelectArgs`] looks at.
+#[derive(Default)]
+struct PostAttributes {
+    score: Option<i64>,
+    created: Option<NaiveDateTime>,
+    answer_count: Option<u32>,
+    has_accepted_answer: bool,
+    closed: bool,
+    community_owned: bool,
+}
+
+/// A Posts.xml attribute that can be carried through the layers with `--carry`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PostAttribute {
+    Title,
+    Score,
+    ViewCount,
+    AnswerCount,
+    CommentCount,
+    FavoriteCount,
+    CreationDate,
+    LastActivityDate,
+    ClosedDate,
+    CommunityOwnedDate,
+    AcceptedAnswerId,
+    ContentLicense,
+}
+
+impl PostAttribute {
+    fn of_key(key: &[u8]) -> Option<Self> {
+        Some(match key {
+            b"Title" => PostAttribute::Title,
+            b"Score" => PostAttribute::Score,
+            b"ViewCount" => PostAttribute::ViewCount,
+            b"AnswerCount" => PostAttribute::AnswerCount,
+            b"CommentCount" => PostAttribute::CommentCount,
+            b"FavoriteCount" => PostAttribute::FavoriteCount,
+            b"CreationDate" => PostAttribute::CreationDate,
+            b"LastActivityDate" => PostAttribute::LastActivityDate,
+            b"ClosedDate" => PostAttribute::ClosedDate,
+            b"CommunityOwnedDate" => PostAttribute::CommunityOwnedDate,
+            b"AcceptedAnswerId" => PostAttribute::AcceptedAnswerId,
+            b"ContentLicense" => PostAttribute::ContentLicense,
+            _ => return None,
+        })
+    }
+}
+
+/// The carried attributes of a post; those not carried, or missing from the post, are `None`.
+/// Written as a JSON object of the attributes that are present, or an empty column if none are.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PostMetadata {
+    pub title: Option<String>,
+    pub score: Option<i64>,
+    pub view_count: Option<u64>,
+    pub answer_count: Option<u32>,
+    pub comment_count: Option<u32>,
+    pub favorite_count: Option<u32>,
+    pub creation_date: Option<NaiveDateTime>,
+    pub last_activity_date: Option<NaiveDateTime>,
+    pub closed_date: Option<NaiveDateTime>,
+    pub community_owned_date: Option<NaiveDateTime>,
+    pub accepted_answer_id: Option<PostId>,
+    pub content_license: Option<String>,
+}
+
+impl PostMetadata {
+    fn set(&mut self, attribute: PostAttribute, attr: &Attribute) -> Result<()> {
+        let value = attr.value.as_ref();
+        match attribute {
+            PostAttribute::Title => self.title = Some(attr.unescape_value()?.into_owned()),
+            PostAttribute::Score => self.score = Some(parse_attribute("Score", value)?),
+            PostAttribute::ViewCount => self.view_count = Some(parse_attribute("ViewCount", value)?),
+            PostAttribute::AnswerCount => self.answer_count = Some(parse_attribute("AnswerCount", value)?),
+            PostAttribute::CommentCount => self.comment_count = Some(parse_attribute("CommentCount", value)?),
+            PostAttribute::FavoriteCount => self.favorite_count = Some(parse_attribute("FavoriteCount", value)?),
+            PostAttribute::CreationDate => self.creation_date = Some(parse_date_attribute("CreationDate", value)?),
+            PostAttribute::LastActivityDate => self.last_activity_date = Some(parse_date_attribute("LastActivityDate", value)?),
+            PostAttribute::ClosedDate => self.closed_date = Some(parse_date_attribute("ClosedDate", value)?),
+            PostAttribute::CommunityOwnedDate => self.community_owned_date = Some(parse_date_attribute("CommunityOwnedDate", value)?),
+            PostAttribute::AcceptedAnswerId => self.accepted_answer_id = Some(parse_attribute("AcceptedAnswerId", value)?),
+            PostAttribute::ContentLicense => self.content_license = Some(attr.unescape_value()?.into_owned()),
+        }
+        Ok(())
+    }
+}
+
+impl Display for PostMetadata {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if *self == PostMetadata::default() {
+            return Ok(());
+        }
+        let mut value = serde_json::to_value(self).map_err(|_| std::fmt::Error)?;
+        if let Some(object) = value.as_object_mut() {
+            object.retain(|_, value| !value.is_null());
+            if object.is_empty() {
+                return Ok(());
+            }
+        }
+        write!(f, "{value}")
+    }
+}
+
+impl FromStr for PostMetadata {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(PostMetadata::default());
+        }
+        serde_json::from_str(s)
+    }
+}
+
+/// Author id of posts whose owner's account was deleted.
+pub const DELETED_USER_ID: i32 = -1;
+
+/// A question or answer selected from Posts.xml; one line of the Layer1 output. Answers have no
+/// tags and carry the id of their question. The author name is only set (to the owner's display
+/// name, if the dump kept it) for posts whose owner was deleted.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PostRow {
+    pub post_id: PostId,
+    pub author_id: i32,
+    pub author_name: String,
+    pub tags: Tags,
+    pub parent_id: Option<PostId>,
+    pub metadata: PostMetadata,
+}
+
+impl Display for PostRow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}\t{}\t{}\t{}\t", self.post_id, self.author_id, self.author_name, self.tags)?;
+        if let Some(parent_id) = self.parent_id {
+            write!(f, "{parent_id}")?;
+        }
+        write!(f, "\t{}", self.metadata)
+    }
+}
+
+impl FromStr for PostRow {
+    type Err = RowParseError;
+
+    fn from_str(line: &str) -> std::result::Result<Self, Self::Err> {
+        const ROW: &str = "layer1";
+        let [post_id, author_id, author_name, tags, parent_id, metadata] = split_columns(ROW, line)?;
+        Ok(PostRow {
+            post_id: parse_column(ROW, "post id", post_id)?,
+            author_id: parse_column(ROW, "author id", author_id)?,
+            author_name: author_name.to_string(),
+            tags: parse_column(ROW, "tags", tags)?,
+            parent_id: match parent_id {
+                "" => None,
+                parent_id => Some(parse_column(ROW, "parent id", parent_id)?),
+            },
+            metadata: parse_column(ROW, "metadata", metadata)?,
+        })
+    }
+}
+
+/// The tags of a question, in the order the dump lists them; written as `python|list`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tags(pub Vec<String>);
+
+impl Tags {
+    /// Parses a Posts.xml `Tags` value, either `<python><list>` (older dumps) or `|python|list|`.
+    pub fn from_attribute(value: &str) -> Self {
+        Tags(value.split(['<', '>', '|'])
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// The first tag, which is usually the language or framework the question is about.
+    pub fn primary(&self) -> Option<&str> {
+        self.0.first().map(String::as_str)
+    }
+}
+
+impl Display for Tags {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.join("|"))
+    }
+}
+
+impl FromStr for Tags {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Tags(s.split('|')
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_string)
+            .collect()))
+    }
+}
+
+/// Iterator over the posts in a Posts.xml stream that have an owner, were edited and pass the
+/// [`SelectArgs`].
+pub struct PostRows<'a, R: BufRead> {
+    rows: RowReader<R>,
+    select: &'a SelectArgs,
+    carry: &'a [PostAttribute],
+}
+
+impl<'a, R: BufRead> PostRows<'a, R> {
+    pub fn new(reader: R, select: &'a SelectArgs, carry: &'a [PostAttribute]) -> Self {
+        PostRows {
+            rows: RowReader::new(reader),
+            select,
+            carry,
+        }
+    }
+
+    pub fn from_rows(rows: RowReader<R>, select: &'a SelectArgs, carry: &'a [PostAttribute]) -> Self {
+        PostRows { rows, select, carry }
+    }
+}
+
+impl<R: BufRead> Iterator for PostRows<'_, R> {
+    type Item = std::result::Result<PostRow, RowError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (select, carry) = (self.select, self.carry);
+        let row = self.rows.next_map(|element| layer1_row_filter(element, select, carry))?;
+        Some(row.map(|(_, row)| row))
+    }
+}
+
+fn layer1_row_filter(element: &BytesStart, select: &SelectArgs, carry: &[PostAttribute]) -> Result<Option<PostRow>> {
+    let attrs = element.attributes();
+    // the predicates and carried attributes may need attributes that come late in the row, or
+    // whose absence counts
+    let full_row = select.filters() || !carry.is_empty();
+    let mut post = PostAttributes::default();
+    let mut metadata = PostMetadata::default();
+    let mut post_id = -1;
+    let mut author_id = -1;
+    let mut has_owner = false;
+    let mut author_name = String::new();
+    let mut tags = None;
+    let mut is_answer = false;
+    let mut parent_id = None;
+    let mut required_fields = 0;
+    const REQUIRED_CHECKS: i32 = 4;
+    for attr in attrs {
+        let attr = attr?;
+        let attr_key = attr.key.as_ref();
+        if attr_key == b"Id" {
+            required_fields += 1;
+            post_id = parse_attribute("Id", &attr.value)?;
+        }
+        if attr_key == b"PostTypeId" {
+            if select.post_types.selects(&attr.value) {
+                is_answer = attr.value.as_ref() == b"2";
+                required_fields += 1;
+            } else {
+                break
+            }
+        }
+        if attr_key == b"OwnerUserId" {
+            author_id = parse_attribute("OwnerUserId", &attr.value)?;
+            has_owner = true;
+            required_fields += 1;
+        }
+        if attr_key == b"OwnerDisplayName" && select.keep_deleted_owners {
+            author_name = attr.unescape_value()?.replace(['\t', '\r', '\n'], " ");
+        }
+        // who edited the post is only known per revision, in layer2
+        if attr_key == b"LastEditDate" {
+            required_fields += 1;
+        }
+        if attr_key == b"ParentId" {
+            parent_id = Some(parse_attribute("ParentId", &attr.value)?);
+        }
+        if attr_key == b"Tags" {
+            tags = Some(Tags::from_attribute(&attr.unescape_value()?));
+        }
+        if full_row {
+            if let Some(attribute) = PostAttribute::of_key(attr_key).filter(|attribute| carry.contains(attribute)) {
+                metadata.set(attribute, &attr)?;
+            }
+            match attr_key {
+                b"Score" => post.score = Some(parse_attribute("Score", &attr.value)?),
+                b"CreationDate" => post.created = Some(parse_date_attribute("CreationDate", &attr.value)?),
+                b"AnswerCount" => post.answer_count = Some(parse_attribute("AnswerCount", &attr.value)?),
+                b"AcceptedAnswerId" => post.has_accepted_answer = true,
+                b"ClosedDate" => post.closed = true,
+                b"CommunityOwnedDate" => post.community_owned = true,
+                _ => {}
+            }
+        } else if required_fields == REQUIRED_CHECKS && (tags.is_some() || parent_id.is_some()) {
+            break
+        }
+    }
+    // a deleted owner leaves at most a display name; their own edits are found in layer2
+    if !has_owner && select.keep_deleted_owners && required_fields == REQUIRED_CHECKS - 1 {
+        author_id = DELETED_USER_ID;
+        required_fields += 1;
+    } else {
+        author_name.clear();
+    }
+    debug_assert!(required_fields <= REQUIRED_CHECKS);
+    if required_fields == REQUIRED_CHECKS {
+        if is_answer && parent_id.is_none() {
+            return Err(Error::MissingAttribute("ParentId"));
+        }
+        let tags = tags.unwrap_or_default();
+        if !select.accepts(&post, &tags, is_answer) {
+            return Ok(None);
+        }
+        Ok(Some(PostRow { post_id, author_id, author_name, tags, parent_id, metadata }))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Reads back a Layer1 output file.
+pub fn read_layer1<R: BufRead>(reader: R) -> TsvReader<R, PostRow> {
+    TsvReader::new(reader)
+}
+
+/// Returns the number of post rows written to OUTFILE.
+pub fn layer1_filter(args: &Layer1Args) -> Result<u64> {
+    detect_schema(&args.infile, &["Id", "PostTypeId", "OwnerUserId", "LastEditDate"], &["Tags", "ParentId", "OwnerDisplayName"])?;
+    let mut rejects = args.errors.rejects("layer1", &args.infile)?;
+    let underlying_stream = args.output.create(&args.output.output_path(&args.outfile))?;
+    let mut writer = TsvWriter::new(underlying_stream, args.flush_interval);
+
+    let bar = if is_compressed(&args.infile) {
+        // a stream cannot be cut into chunks
+        let (reader, bar) = open_input(&args.infile)?;
+        for row in PostRows::new(reader, &args.select, &args.carry) {
+            if let Some(row) = rejects.check(row)? {
+                writer.write_row(&row)?;
+            }
+        }
+        bar
+    } else {
+        let chunks = args.chunks.chunks(&args.infile, 0)?;
+        let bar = chunk_progress_bar(&args.infile, 0)?;
+        args.chunks.for_each_chunk(&chunks, &mut (), |_, chunk| {
+            Ok(PostRows::from_rows(open_chunk(&args.infile, chunk, &bar)?, &args.select, &args.carry).collect::<Vec<_>>())
+        }, |_, _, rows| {
+            for row in rows {
+                if let Some(row) = rejects.check(row)? {
+                    writer.write_row(&row)?;
                 }
             }
-            _ => (),
+            Ok(())
+        })?;
+        bar
+    };
+
+    bar.finish();
+    rejects.finish()?;
+
+    Ok(writer.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{parse_args, TestDir, POSTS_XML};
+
+    #[test]
+    fn post_rows_round_trip() {
+        let created = NaiveDateTime::parse_from_str("2010-01-01T00:00:00.000", crate::DATE_FORMAT).unwrap();
+        let rows = [
+            PostRow {
+                post_id: 1,
+                author_id: 8,
+                author_name: String::new(),
+                tags: Tags::from_attribute("<python><list>"),
+                parent_id: None,
+                metadata: PostMetadata {
+                    title: Some("T1".to_string()),
+                    score: Some(5),
+                    creation_date: Some(created),
+                    ..PostMetadata::default()
+                },
+            },
+            PostRow {
+                post_id: 2,
+                author_id: 10,
+                author_name: String::new(),
+                tags: Tags::default(),
+                parent_id: Some(1),
+                metadata: PostMetadata::default(),
+            },
+            PostRow {
+                post_id: 4,
+                author_id: DELETED_USER_ID,
+                author_name: "ghost".to_string(),
+                tags: Tags::from_attribute("|rust|"),
+                parent_id: None,
+                metadata: PostMetadata::default(),
+            },
+        ];
+        for row in rows {
+            assert_eq!(row.to_string().parse::<PostRow>().unwrap(), row);
         }
-        buf.clear();
     }
 
-    writer.flush().unwrap();
-}
\ No newline at end of file
+    #[test]
+    fn tags_parse_both_dump_forms() {
+        let tags = Tags::from_attribute("<python><list>");
+        assert_eq!(tags, Tags::from_attribute("|python|list|"));
+        assert_eq!(tags.to_string(), "python|list");
+        assert_eq!(tags.to_string().parse::<Tags>().unwrap(), tags);
+    }
+
+    #[test]
+    fn layer1_selects_edited_posts_with_an_owner() {
+        let dir = TestDir::new("layer1");
+        let posts = dir.write("Posts.xml", POSTS_XML);
+        let out = dir.file("layer1.tsv");
+        let written = layer1_filter(&parse_args(&["--in-file", &posts, "--out-file", &out, "--post-types", "both", "--carry", "score"])).unwrap();
+        assert_eq!(written, 4);
+        let rows = read_layer1(dir.open("layer1.tsv")).collect::<std::result::Result<Vec<_>, _>>().unwrap();
+        assert_eq!(rows.iter().map(|row| (row.post_id, row.parent_id)).collect::<Vec<_>>(), [(1, None), (2, Some(1)), (3, None), (5, None)]);
+        assert_eq!(rows[0].tags, Tags::from_attribute("<python><list>"));
+        assert_eq!(rows[0].metadata.score, Some(5));
+
+        // the question whose owner was deleted, by name
+        layer1_filter(&parse_args(&["--in-file", &posts, "--out-file", &out, "--keep-deleted-owners"])).unwrap();
+        let rows = read_layer1(dir.open("layer1.tsv")).collect::<std::result::Result<Vec<_>, _>>().unwrap();
+        assert_eq!(rows.iter().map(|row| (row.post_id, row.author_id, row.author_name.as_str())).collect::<Vec<_>>(),
+            [(1, 8, ""), (3, 11, ""), (4, DELETED_USER_ID, "ghost"), (5, 13, "")]);
+    }
+}
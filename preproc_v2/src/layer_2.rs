@@ -1,175 +1,1002 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::iter::Peekable;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use chrono::NaiveDateTime;
-use clap::Args;
-use indicatif::ProgressIterator;
+use clap::{Args, ValueEnum};
+use indicatif::ProgressBar;
 use quick_xml::events::{BytesStart, Event};
 use quick_xml::Reader;
+use serde::{Deserialize, Serialize};
+use crate::checkpoint::{CheckpointArgs, Checkpointer};
+use crate::chunks::{chunk_progress_bar, open_chunk, ChunkArgs};
+use crate::index::{IndexEntry, PostHistoryIndex};
+use crate::input::{is_compressed, open_input, open_input_from};
+use crate::output::{CompressArgs, OutputFile};
+use crate::error::{Error, ErrorArgs, Rejects, Result, RowError};
+use crate::layer_1::{read_layer1, PostMetadata, PostRow, Tags, DELETED_USER_ID};
+use crate::PostId;
+use crate::schema::detect_schema;
+use crate::spill::MemoryArgs;
+use crate::xml::{parse_attribute, parse_date_attribute, parse_guid_attribute, RowReader};
+use crate::tsv::{parse_column, split_columns, RowParseError, TsvReader, TsvWriter};
 
 #[derive(Args)]
 pub struct Layer2Args {
     #[arg(long = "in-file", required=true)]
-    infile: PathBuf,
+    pub infile: PathBuf,
     #[arg(long = "in-layer-1", required=true)]
-    layer1: PathBuf,
+    pub layer1: PathBuf,
     #[arg(long = "out-file", required=true)]
-    outfile: PathBuf,
-    #[arg(long = "l1-count", default_value_t=7064714)]
-    layer1_size: u64,
-    #[arg(long = "hcount", default_value_t=234510258)]
-    h_count: u64,
+    pub outfile: PathBuf,
     #[arg(long = "flush-interval", default_value_t=1_000_000)]
-    flush_interval: usize,
+    pub flush_interval: usize,
+    /// Whose edits to pair with the original post
+    #[arg(long = "editor", value_enum, default_value_t = Editors::Other)]
+    pub editors: Editors,
+    /// Which revisions of a post to pair
+    #[arg(long = "revisions", value_enum, default_value_t = Revisions::Single)]
+    pub revisions: Revisions,
+    /// Fields whose edits to pair (comma-separated)
+    #[arg(long = "fields", value_enum, value_delimiter = ',', default_value = "body")]
+    pub fields: Vec<Field>,
+    /// What to do with edits that were later rolled back
+    #[arg(long = "rollbacks", value_enum, default_value_t = Rollbacks::Drop)]
+    pub rollbacks: Rollbacks,
+    /// Keep only edits made at least this long after the post, e.g. `90s`, `30m`, `12h`, `7d`,
+    /// `4w` or `2y`
+    #[arg(long = "min-edit-delay")]
+    pub min_edit_delay: Option<EditDelay>,
+    /// Keep only edits made at most this long after the post
+    #[arg(long = "max-edit-delay")]
+    pub max_edit_delay: Option<EditDelay>,
+    /// Index of IN_FILE built by the `index` subcommand, to read only the rows of the Layer1
+    /// posts (and their questions) instead of scanning all of it
+    #[arg(long = "index")]
+    pub index: Option<PathBuf>,
+    #[command(flatten)]
+    pub errors: ErrorArgs,
+    #[command(flatten)]
+    pub output: CompressArgs,
+    #[command(flatten)]
+    pub checkpoints: CheckpointArgs,
+    #[command(flatten)]
+    pub chunks: ChunkArgs,
+    #[command(flatten)]
+    pub memory: MemoryArgs,
 }
 
+/// Whose edits Layer2 pairs with an earlier revision (see [`Revisions`]). Edits by deleted users
+/// other than the owner cannot be attributed to anyone, so they are never paired, and a post
+/// with one is left out in single mode.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum Editors {
+    /// The owner of the post
+    #[value(name = "self")]
+    Owner,
+    /// Anyone but the owner of the post
+    #[default]
+    Other,
+    /// Anyone
+    Any,
+}
+
+impl Editors {
+    fn accepts(self, by_owner: bool) -> bool {
+        match self {
+            Editors::Owner => by_owner,
+            Editors::Other => !by_owner,
+            Editors::Any => true,
+        }
+    }
+}
+
+/// Which revisions of a post Layer2 pairs.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum Revisions {
+    /// The original and the only edit, skipping posts edited more than once
+    #[default]
+    Single,
+    /// Every revision and the next, for the edits by the chosen editors
+    Consecutive,
+    /// The original and the final revision, for posts edited by the chosen editors only
+    Endpoints,
+}
+
+/// What Layer2 does with pairs whose later revision was reverted by a rollback.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Rollbacks {
+    /// Leave them out
+    #[default]
+    Drop,
+    /// Keep them, marked in the reverted column
+    Label,
+}
+
+/// A span of time between the creation of a post and an edit, given as seconds or with a unit:
+/// `90s`, `30m`, `12h`, `7d`, `4w` or `2y` (of 365 days).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EditDelay(pub i64);
+
+impl FromStr for EditDelay {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+            Some(i) => s.split_at(i),
+            None => (s, "s"),
+        };
+        let seconds = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            "y" => 365 * 24 * 60 * 60,
+            _ => return Err(format!("unknown unit {unit:?} in {s:?}, expected one of s, m, h, d, w or y")),
+        };
+        let number = number.parse::<i64>()
+            .map_err(|e| format!("bad delay {s:?}: {e}"))?;
+        number.checked_mul(seconds)
+            .map(EditDelay)
+            .ok_or_else(|| format!("delay {s:?} is too long"))
+    }
+}
+
+impl EditDelay {
+    /// Whether an edit `delay` seconds after the post, if known, is within `min..=max`.
+    fn admits(delay: Option<i64>, min: Option<EditDelay>, max: Option<EditDelay>) -> bool {
+        match delay {
+            Some(delay) => min.is_none_or(|min| delay >= min.0) && max.is_none_or(|max| delay <= max.0),
+            None => min.is_none() && max.is_none(),
+        }
+    }
+}
+
+/// The fields of a post that PostHistory.xml records revisions of. Layer4 writes a dataset for
+/// each: body edits, title rewrites and retaggings.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize)]
+pub enum Field {
+    Body,
+    Title,
+    Tags,
+}
+
+impl Field {
+    /// The field a PostHistoryTypeId from 1 to 9 is a revision of: originals (1 to 3), edits (4
+    /// to 6) and rollbacks (7 to 9) come in title, body, tags order.
+    fn of_history_type(history_type: u8) -> Field {
+        match history_type % 3 {
+            1 => Field::Title,
+            2 => Field::Body,
+            _ => Field::Tags,
+        }
+    }
+}
+
+impl Display for Field {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Field::Body => "body",
+            Field::Title => "title",
+            Field::Tags => "tags",
+        })
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "body" => Ok(Field::Body),
+            "title" => Ok(Field::Title),
+            "tags" => Ok(Field::Tags),
+            _ => Err(format!("unknown field {s:?}")),
+        }
+    }
+}
+
+/// A revision of a field of a post (tags written like [`Tags`]) and a later one, along with the
+/// editor, the post's author, tags and carried attributes from Layer1 and, for an answer, its
+/// question; one line of the Layer2 output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RevisionPair {
+    pub post_id: PostId,
+    /// The number of edits up to and including the later revision.
+    pub revision: u32,
+    pub field: Field,
+    pub before_text: String,
+    pub before_date: NaiveDateTime,
+    /// The ContentLicense of the earlier revision, empty if the dump does not record it.
+    pub before_license: String,
+    pub after_text: String,
+    pub after_date: NaiveDateTime,
+    pub after_license: String,
+    /// Seconds from the original revision of the post to the later revision, if the original is
+    /// known.
+    pub edit_delay: Option<i64>,
+    /// Seconds from the original revision of the question (for an answer, of the question it
+    /// belongs to) to the later revision, if known.
+    pub question_age: Option<i64>,
+    /// The comment the editor left on the later revision (escaped, like the texts).
+    pub comment: String,
+    /// Whether a rollback reverted the later revision.
+    pub reverted: bool,
+    pub author_id: i32,
+    pub author_name: String,
+    /// The user who made the later revision (or, for endpoints, the last edit they could be
+    /// attributed to), [`DELETED_USER_ID`] for a deleted owner.
+    pub editor_id: i32,
+    pub tags: Tags,
+    pub context: Option<QuestionContext>,
+    pub metadata: PostMetadata,
+}
+
+/// The original title and body of the question an answer belongs to. Both are empty if
+/// PostHistory.xml has no record of them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuestionContext {
+    pub post_id: PostId,
+    pub title: String,
+    pub body: String,
+}
+
+impl Display for RevisionPair {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t",
+            self.post_id,
+            self.revision,
+            self.field,
+            self.before_text.replace('\t', " "),
+            self.before_date.format(crate::DATE_FORMAT),
+            self.before_license,
+            self.after_text.replace('\t', " "),
+            self.after_date.format(crate::DATE_FORMAT),
+            self.after_license,
+            self.edit_delay.map(|delay| delay.to_string()).unwrap_or_default(),
+            self.question_age.map(|age| age.to_string()).unwrap_or_default(),
+            self.comment.replace('\t', " "),
+            self.reverted,
+            self.author_id,
+            self.author_name,
+            self.editor_id,
+            self.tags,
+        )?;
+        match &self.context {
+            Some(context) => write!(f, "{}\t{}\t{}",
+                context.post_id,
+                context.title.replace('\t', " "),
+                context.body.replace('\t', " "),
+            )?,
+            None => write!(f, "\t\t")?,
+        }
+        write!(f, "\t{}", self.metadata)
+    }
+}
+
+impl FromStr for RevisionPair {
+    type Err = RowParseError;
+
+    fn from_str(line: &str) -> std::result::Result<Self, Self::Err> {
+        const ROW: &str = "layer2";
+        let [post_id, revision, field, before_text, before_date, before_license, after_text, after_date, after_license, edit_delay, question_age, comment, reverted, author_id, author_name, editor_id, tags, question_id, question_title, question_body, metadata] = split_columns(ROW, line)?;
+        let parse_date = |name, value| NaiveDateTime::parse_from_str(value, crate::DATE_FORMAT)
+            .map_err(|e| RowParseError::new(ROW, format!("bad {name} {value:?}: {e}")));
+        Ok(RevisionPair {
+            post_id: parse_column(ROW, "post id", post_id)?,
+            revision: parse_column(ROW, "revision", revision)?,
+            field: parse_column(ROW, "field", field)?,
+            before_text: before_text.to_string(),
+            before_date: parse_date("before date", before_date)?,
+            before_license: before_license.to_string(),
+            after_text: after_text.to_string(),
+            after_date: parse_date("after date", after_date)?,
+            after_license: after_license.to_string(),
+            edit_delay: match edit_delay {
+                "" => None,
+                edit_delay => Some(parse_column(ROW, "edit delay", edit_delay)?),
+            },
+            question_age: match question_age {
+                "" => None,
+                question_age => Some(parse_column(ROW, "question age", question_age)?),
+            },
+            comment: comment.to_string(),
+            reverted: parse_column(ROW, "reverted", reverted)?,
+            author_id: parse_column(ROW, "author id", author_id)?,
+            author_name: author_name.to_string(),
+            editor_id: parse_column(ROW, "editor id", editor_id)?,
+            tags: parse_column(ROW, "tags", tags)?,
+            context: match question_id {
+                "" => None,
+                question_id => Some(QuestionContext {
+                    post_id: parse_column(ROW, "question id", question_id)?,
+                    title: question_title.to_string(),
+                    body: question_body.to_string(),
+                }),
+            },
+            metadata: parse_column(ROW, "metadata", metadata)?,
+        })
+    }
+}
+
+/// Reads back a Layer2 output file.
+pub fn read_layer2<R: BufRead>(reader: R) -> TsvReader<R, RevisionPair> {
+    TsvReader::new(reader)
+}
+
+#[derive(Serialize, Deserialize)]
 struct QInfo {
     delete: bool,
     author_id: i32,
-    before_position: u64,
-    after_position: u64,
+    author_name: String,
+    tags: Tags,
+    parent_id: Option<PostId>,
+    metadata: PostMetadata,
+    /// Positions of the original revision of every field, indexed by [`Field`].
+    original: [u64; 3],
+    original_guid: Option<u128>,
+    /// The date of the original revisions, when the post was created.
+    created: Option<NaiveDateTime>,
+    /// The edits of the chosen fields of the post, and in the revision chain modes the rollbacks
+    /// between them, in the order of PostHistory.xml.
+    edits: Vec<EditInfo>,
 }
 
-fn load_layer_1(args: &Layer2Args) -> BTreeMap<i64, QInfo> {
-    // format is {post_id}\t{author_id}
-    println!("Loading question index from {}", args.layer1.display());
-    let pb = crate::progress_bar(args.layer1_size);
-    let f = OpenOptions::new()
-        .read(true)
-        .open(&args.layer1)
-        .expect("Failed to open layer1 data file for reading");
-    let reader = BufReader::new(f);
-    let dataset = reader.lines()
-        .progress_with(pb.clone())
-        .map(|line| {
-            let line = line.unwrap();
-            let splits: Vec<_> = line.split('\t').collect();
-            assert_eq!(splits.len(), 2);
-            (
-                i64::from_str(splits[0]).unwrap(),
-                QInfo {
-                    author_id: i32::from_str(splits[1]).unwrap(),
-                    delete: false,
-                    before_position: u64::MAX,
-                    after_position: u64::MAX,
-                }
-            )
-        }).collect::<BTreeMap<i64, QInfo>>();
-    pb.finish();
-    println!("Loaded {} question items from Layer1.", dataset.len());
-    dataset
+/// The PostHistory.xml rows of an edit or rollback, which share its RevisionGUID.
+#[derive(Serialize, Deserialize)]
+struct EditInfo {
+    guid: Option<u128>,
+    /// Positions of the revisions of the fields the edit changed, indexed by [`Field`].
+    positions: [u64; 3],
+    editor_id: i32,
+    /// Whether the edit is by one of the chosen editors; never true of rollbacks.
+    chosen: bool,
+    /// Whether a later rollback reverted the edit.
+    reverted: bool,
 }
 
-enum Layer2ScanFilterAction {
-    Ignore,
-    Delete(i64),
-    AddBefore(i64),
-    AddAfter(i64),
+/// The revisions of a field of a post to pair.
+struct PairPositions {
+    field: Field,
+    before: u64,
+    after: u64,
+    revision: u32,
+    editor_id: i32,
+    reverted: bool,
 }
 
-fn layer2_scan(args: &Layer2Args, l1: &mut BTreeMap<i64, QInfo>) -> u64 {
-    let mut total_items = l1.len() as u64;
+/// Positions of the original title and body of a question that Layer1 answers belong to.
+#[derive(Clone, Serialize, Deserialize)]
+struct ParentInfo {
+    title_position: u64,
+    body_position: u64,
+    created: Option<NaiveDateTime>,
+}
 
-    let mut reader = Reader::from_file(&args.infile)
-        .expect("Failed to open INFILE for reading");
-    let mut xml_buf = Vec::new();
-    let pb = crate::progress_bar(args.h_count);
+#[derive(Serialize, Deserialize)]
+enum RevisionKind {
+    /// `has_user` is false for revisions by deleted users.
+    Original { field: Field, has_user: bool, guid: Option<u128>, date: Option<NaiveDateTime> },
+    Edit { field: Field, editor: Editor, guid: Option<u128> },
+    /// A rollback to the revision `target`, if its comment names it.
+    Rollback { field: Field, editor: Editor, guid: Option<u128>, target: Option<u128> },
+}
 
-    println!("Loading question histories from {}", args.infile.display());
+impl RevisionKind {
+    fn field(&self) -> Field {
+        match self {
+            RevisionKind::Original { field, .. }
+            | RevisionKind::Edit { field, .. }
+            | RevisionKind::Rollback { field, .. } => *field,
+        }
+    }
 
-    loop {
-        pb.inc(1);
+    fn guid(&self) -> Option<u128> {
+        match self {
+            RevisionKind::Original { guid, .. }
+            | RevisionKind::Edit { guid, .. }
+            | RevisionKind::Rollback { guid, .. } => *guid,
+        }
+    }
+}
 
-        let pre_buf_pos = reader.buffer_position();
-
-        match reader.read_event_into(&mut xml_buf) {
-            Err(e) => panic!("Error at position {}: {:?}", reader.buffer_position(), e),
-            Ok(Event::Eof) => break,
-            Ok(Event::Empty(element)) => {
-                if element.name().as_ref() == b"row" {
-                    match layer2_scan_filter(element, l1) {
-                        Layer2ScanFilterAction::Ignore => {}
-                        Layer2ScanFilterAction::Delete(y) => {
-                            l1.get_mut(&y).unwrap().delete = true;
-                            total_items -= 1;
-                        }
-                        Layer2ScanFilterAction::AddBefore(y) => {
-                            l1.get_mut(&y).unwrap().before_position = pre_buf_pos as u64;
+#[derive(Serialize, Deserialize)]
+enum Editor {
+    User(i32),
+    /// A deleted user, with their display name if the dump kept it.
+    Deleted(Option<String>),
+}
+
+impl Editor {
+    fn user_id(&self) -> i32 {
+        match self {
+            Editor::User(user_id) => *user_id,
+            Editor::Deleted(_) => DELETED_USER_ID,
+        }
+    }
+}
+
+impl QInfo {
+    fn new(row: PostRow) -> Self {
+        QInfo {
+            author_id: row.author_id,
+            author_name: row.author_name,
+            tags: row.tags,
+            parent_id: row.parent_id,
+            metadata: row.metadata,
+            delete: false,
+            original: [u64::MAX; 3],
+            original_guid: None,
+            created: None,
+            edits: Vec::new(),
+        }
+    }
+
+    /// Whether the post has an edit by a chosen editor recorded.
+    fn is_recorded(&self) -> bool {
+        !self.delete && self.edits.iter().any(|edit| edit.chosen)
+    }
+
+    /// Positions of the revisions recorded for the post and, for an answer, of its `parent`.
+    fn positions<'a>(&'a self, parent: Option<&'a ParentInfo>) -> impl Iterator<Item = u64> + 'a {
+        self.original.into_iter()
+            .chain(self.edits.iter().flat_map(|edit| edit.positions))
+            .chain(parent.into_iter().flat_map(|parent| [parent.title_position, parent.body_position]))
+            .filter(|&position| position != u64::MAX)
+    }
+
+    /// Whether `editor` is the owner of the post. Deleted users are told apart by display name
+    /// only, so deleted editors only match owners that were deleted under the same name.
+    fn is_author(&self, editor: &Editor) -> bool {
+        match editor {
+            Editor::User(user_id) => *user_id == self.author_id,
+            Editor::Deleted(name) => {
+                self.author_id == DELETED_USER_ID
+                    && !self.author_name.is_empty()
+                    && name.as_deref() == Some(self.author_name.as_str())
+            }
+        }
+    }
+}
+
+impl ParentInfo {
+    fn new() -> Self {
+        ParentInfo {
+            title_position: u64::MAX,
+            body_position: u64::MAX,
+            created: None,
+        }
+    }
+}
+
+/// A revision of a Layer1 post or of the question of a Layer1 answer. Problems with the row
+/// found after its post id are kept in `kind`, so that they only count against posts still being
+/// considered.
+struct ScannedRevision {
+    post_id: PostId,
+    kind: Result<RevisionKind>,
+}
+
+/// Revision positions in PostHistory.xml for a set of Layer1 posts.
+#[derive(Serialize, Deserialize)]
+pub struct QuestionHistory {
+    questions: BTreeMap<PostId, QInfo>,
+    parents: BTreeMap<PostId, ParentInfo>,
+    editors: Editors,
+    revisions: Revisions,
+    fields: Vec<Field>,
+    candidates: u64,
+    /// Whether the revisions have a ContentLicense, which older dumps lack.
+    licensed: bool,
+}
+
+impl QuestionHistory {
+    pub fn new(questions: impl IntoIterator<Item = PostRow>, editors: Editors, revisions: Revisions, mut fields: Vec<Field>) -> Self {
+        fields.sort_unstable();
+        fields.dedup();
+        let questions = questions.into_iter()
+            .map(|row| (row.post_id, QInfo::new(row)))
+            .collect::<BTreeMap<PostId, QInfo>>();
+        let parents = questions.values()
+            .filter_map(|qinfo| qinfo.parent_id)
+            .map(|parent_id| (parent_id, ParentInfo::new()))
+            .collect();
+        let candidates = questions.len() as u64;
+        QuestionHistory { questions, parents, editors, revisions, fields, candidates, licensed: true }
+    }
+
+    /// Adds a Layer1 post to the questions whose revisions to record.
+    fn insert(&mut self, row: PostRow) {
+        self.questions.insert(row.post_id, QInfo::new(row));
+        self.candidates += 1;
+    }
+
+    /// Whether revisions of `post_id` of PostHistoryTypeId `history_type` (if known yet) are of
+    /// interest.
+    fn tracks(&self, post_id: PostId, history_type: Option<u8>) -> bool {
+        let field = history_type.map(Field::of_history_type);
+        (field.is_none_or(|field| self.fields.contains(&field)) && self.questions.contains_key(&post_id))
+            || (history_type.is_none_or(|history_type| history_type <= 2) && self.parents.contains_key(&post_id))
+    }
+
+    /// Number of questions loaded from Layer1.
+    pub fn len(&self) -> usize {
+        self.questions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.questions.is_empty()
+    }
+
+    /// Number of questions not (yet) ruled out by [`QuestionHistory::scan`].
+    pub fn candidates(&self) -> u64 {
+        self.candidates
+    }
+
+    /// Records the original and edited revisions of the chosen fields of every post in a
+    /// PostHistory.xml stream, ruling out posts whose edits cannot be paired as chosen, and the
+    /// original title and body of the questions of answers. The stream starts at byte `offset`
+    /// of the dump, where an earlier scan left off.
+    pub fn scan<R: BufRead>(&mut self, reader: R, offset: u64, rejects: &mut Rejects, checkpoints: &mut Checkpointer) -> Result<()> {
+        let mut rows = RowReader::resume_at(reader, offset);
+
+        while let Some(row) = rows.next_map(|element| layer2_scan_filter(element, self)) {
+            self.apply(row, rejects)?;
+            checkpoints.maybe_save(rows.position(), self)?;
+        }
+
+        checkpoints.save(rows.position(), self)
+    }
+
+    /// Like [`QuestionHistory::scan`], but reads `chunks` of the PostHistory.xml at `path` in
+    /// parallel.
+    pub fn scan_chunks(
+        &mut self,
+        path: &Path,
+        chunks: &[Range<u64>],
+        args: &ChunkArgs,
+        pb: &ProgressBar,
+        rejects: &mut Rejects,
+        checkpoints: &mut Checkpointer,
+    ) -> Result<()> {
+        args.for_each_chunk(chunks, self, |history, chunk| {
+            let mut rows = open_chunk(path, chunk, pb)?;
+            Ok(std::iter::from_fn(|| rows.next_map(|element| layer2_scan_filter(element, history)))
+                .collect::<Vec<_>>())
+        }, |history, chunk, rows| {
+            for row in rows {
+                history.apply(row, rejects)?;
+            }
+            checkpoints.maybe_save(chunk.end, history)
+        })?;
+        checkpoints.save_finished(self)
+    }
+
+    /// Like [`QuestionHistory::scan`], but reads only the rows of tracked posts from the
+    /// PostHistory.xml at `path`, finding them through its `index`.
+    pub fn scan_index(&mut self, index: &PostHistoryIndex, path: &Path, pb: &ProgressBar, rejects: &mut Rejects) -> Result<()> {
+        let mut dump = BufReader::new(File::open(path)?);
+        for entry in index.entries()? {
+            let entry = entry?;
+            pb.inc(1);
+            if !(1..=9).contains(&entry.history_type) || !self.tracks(entry.post_id, Some(entry.history_type)) {
+                continue;
+            }
+            let row = entry.read_row(&mut dump)?;
+            let mut rows = RowReader::resume_at(row.as_slice(), entry.offset);
+            if let Some(row) = rows.next_map(|element| layer2_scan_filter(element, self)) {
+                self.apply(row, rejects)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, row: std::result::Result<(u64, ScannedRevision), RowError>, rejects: &mut Rejects) -> Result<()> {
+        let Some((position, ScannedRevision { post_id, kind })) = rejects.check(row)? else {
+            return Ok(());
+        };
+        let question = self.questions.get_mut(&post_id).filter(|qinfo| !qinfo.delete);
+        let parent = self.parents.get_mut(&post_id);
+        if question.is_none() && parent.is_none() {
+            return Ok(());
+        }
+        let Some(kind) = rejects.check(kind.map_err(|e| RowError::new(position, e)))? else {
+            return Ok(());
+        };
+        if let Some(parent) = parent {
+            if let RevisionKind::Original { date: Some(date), .. } = kind {
+                parent.created.get_or_insert(date);
+            }
+            match kind {
+                RevisionKind::Original { field: Field::Title, .. } if parent.title_position == u64::MAX => {
+                    parent.title_position = position;
+                }
+                RevisionKind::Original { field: Field::Body, .. } if parent.body_position == u64::MAX => {
+                    parent.body_position = position;
+                }
+                _ => {}
+            }
+        }
+        let Some(qinfo) = question.filter(|_| self.fields.contains(&kind.field())) else {
+            return Ok(());
+        };
+        match kind {
+            // the original of a post whose owner was deleted has no user either
+            RevisionKind::Original { field, has_user, guid, date } => {
+                if let Some(date) = date {
+                    qinfo.created.get_or_insert(date);
+                }
+                if has_user || qinfo.author_id == DELETED_USER_ID {
+                    qinfo.original[field as usize] = position;
+                    qinfo.original_guid = guid;
+                }
+            }
+            // the other fields the same edit or rollback changed
+            RevisionKind::Edit { field, .. } | RevisionKind::Rollback { field, .. }
+                if kind.guid().is_some() && qinfo.edits.last().is_some_and(|edit| edit.guid == kind.guid()) =>
+            {
+                qinfo.edits.last_mut().unwrap().positions[field as usize] = position;
+            }
+            RevisionKind::Rollback { field, editor, guid, target } => {
+                // the edits after the target, or the last one if the target is not known
+                let first_reverted = match target {
+                    Some(target) if Some(target) == qinfo.original_guid => 0,
+                    _ => match qinfo.edits.iter().rposition(|edit| target.is_some() && edit.guid == target) {
+                        Some(i) => i + 1,
+                        None => qinfo.edits.len().saturating_sub(1),
+                    },
+                };
+                for edit in &mut qinfo.edits[first_reverted..] {
+                    edit.reverted = true;
+                }
+                // a rollback is no edit, but later edits start from the revision it restored
+                if self.revisions != Revisions::Single {
+                    let mut positions = [u64::MAX; 3];
+                    positions[field as usize] = position;
+                    qinfo.edits.push(EditInfo { guid, positions, editor_id: editor.user_id(), chosen: false, reverted: false });
+                }
+            }
+            RevisionKind::Edit { field, editor, guid } => {
+                let by_owner = qinfo.is_author(&editor);
+                // other deleted editors are indistinguishable from each other, so their edits
+                // only link the revisions of a chain
+                let attributed = by_owner || matches!(editor, Editor::User(_));
+                let chosen = attributed && self.editors.accepts(by_owner);
+                // wrong editor, or too many edits; in single mode, the changes of an edit by a
+                // deleted user would otherwise be put on the editor of the next
+                let delete = match self.revisions {
+                    Revisions::Single => !chosen || !qinfo.edits.is_empty(),
+                    Revisions::Consecutive => false,
+                    Revisions::Endpoints => attributed && !chosen,
+                };
+                if delete {
+                    qinfo.delete = true;
+                    self.candidates -= 1;
+                } else {
+                    let mut positions = [u64::MAX; 3];
+                    positions[field as usize] = position;
+                    qinfo.edits.push(EditInfo { guid, positions, editor_id: editor.user_id(), chosen, reverted: false });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reloads the recorded revisions from the same PostHistory.xml that was scanned, in post id
+    /// order.
+    pub fn revision_pairs<'a, R: BufRead + Seek>(&'a self, reader: &'a mut R) -> impl Iterator<Item = std::result::Result<RevisionPair, RowError>> + 'a {
+        self.pairs_with(move |position| layer2_load_revision(position, reader))
+    }
+
+    /// Like [`QuestionHistory::revision_pairs`], for a PostHistory.xml stream that cannot seek.
+    /// The recorded revisions are read in a single forward pass and held in memory until the
+    /// pairs are assembled, so this needs about as much memory as the Layer2 output is large.
+    pub fn revision_pairs_sequential<R: BufRead>(&self, reader: R) -> Result<impl Iterator<Item = std::result::Result<RevisionPair, RowError>> + '_> {
+        let mut positions = self.recorded()
+            .flat_map(|(_, qinfo)| qinfo.positions(qinfo.parent_id.and_then(|parent_id| self.parents.get(&parent_id))))
+            .collect::<Vec<_>>();
+        positions.sort_unstable();
+        // answers to the same question share its revisions
+        positions.dedup();
+
+        let mut revisions = HashMap::with_capacity(positions.len());
+        layer2_read_rows_at(reader, positions.into_iter().map(|position| Ok((position, ()))), |position, (), line| {
+            revisions.insert(position, line.to_string());
+            Ok(())
+        })?;
+
+        Ok(self.pairs_with(move |position| layer2_parse_revision(revisions.get(&position)
+            .expect("every recorded revision is loaded"))))
+    }
+
+    /// Questions with an edit by a chosen editor recorded.
+    fn recorded(&self) -> impl Iterator<Item = (&PostId, &QInfo)> {
+        self.questions.iter()
+            .filter(|(_, qinfo)| qinfo.is_recorded())
+    }
+
+    /// The revisions of a recorded question to pair.
+    fn pair_positions(&self, qinfo: &QInfo) -> Vec<PairPositions> {
+        let edits = &qinfo.edits;
+        let mut pairs = Vec::new();
+        match self.revisions {
+            Revisions::Single => {
+                for &field in &self.fields {
+                    pairs.push(PairPositions {
+                        field,
+                        before: qinfo.original[field as usize],
+                        after: edits[0].positions[field as usize],
+                        revision: 1,
+                        editor_id: edits[0].editor_id,
+                        reverted: edits[0].reverted,
+                    });
+                }
+            }
+            Revisions::Consecutive => {
+                for (i, edit) in edits.iter().enumerate().filter(|(_, edit)| edit.chosen) {
+                    for &field in &self.fields {
+                        let before = edits[..i].iter().rev()
+                            .map(|edit| edit.positions[field as usize])
+                            .find(|&position| position != u64::MAX)
+                            .unwrap_or(qinfo.original[field as usize]);
+                        pairs.push(PairPositions {
+                            field,
+                            before,
+                            after: edit.positions[field as usize],
+                            revision: i as u32 + 1,
+                            editor_id: edit.editor_id,
+                            reverted: edit.reverted,
+                        });
+                    }
+                }
+            }
+            Revisions::Endpoints => {
+                let editor = edits.iter().rev().find(|edit| edit.chosen).unwrap();
+                for &field in &self.fields {
+                    let Some(last) = edits.iter().rev().find(|edit| edit.positions[field as usize] != u64::MAX) else {
+                        continue;
+                    };
+                    pairs.push(PairPositions {
+                        field,
+                        before: qinfo.original[field as usize],
+                        after: last.positions[field as usize],
+                        revision: edits.len() as u32,
+                        editor_id: editor.editor_id,
+                        reverted: last.reverted,
+                    });
+                }
+            }
+        }
+        // fields the edits did not change, or whose original is unknown
+        pairs.retain(|pair| pair.before != u64::MAX && pair.after != u64::MAX);
+        pairs
+    }
+
+    fn pairs_with<'a>(&'a self, mut load: impl FnMut(u64) -> Result<Option<Revision>> + 'a) -> impl Iterator<Item = std::result::Result<RevisionPair, RowError>> + 'a {
+        self.recorded()
+            .flat_map(move |(post_id, qinfo)| {
+                let mut load = |position| load(position)
+                    .map_err(|e| RowError::new(position, e));
+                let context = match qinfo.parent_id {
+                    Some(parent_id) => match self.question_context(parent_id, &mut load) {
+                        Ok(context) => Some(context),
+                        Err(e) => return vec![Err(e)],
+                    },
+                    None => None,
+                };
+                let question_created = match qinfo.parent_id {
+                    Some(parent_id) => self.parents.get(&parent_id).and_then(|parent| parent.created),
+                    None => qinfo.created,
+                };
+                let seconds_since = |created: Option<NaiveDateTime>, date: NaiveDateTime| {
+                    created.map(|created| (date - created).num_seconds())
+                };
+                let mut pairs = Vec::new();
+                for PairPositions { field, before, after, revision, editor_id, reverted } in self.pair_positions(qinfo) {
+                    let after_position = after;
+                    // revisions without text are not paired
+                    let (mut before, mut after) = match (load(before), load(after)) {
+                        (Ok(Some(before)), Ok(Some(after))) => (before, after),
+                        (Err(e), _) | (_, Err(e)) => {
+                            pairs.push(Err(e));
+                            continue;
                         }
-                        Layer2ScanFilterAction::AddAfter(y) => {
-                            l1.get_mut(&y).unwrap().after_position = pre_buf_pos as u64;
+                        _ => continue,
+                    };
+                    // the text of revisions stays escaped, but tags are written like Layer1's
+                    if field == Field::Tags {
+                        match (unescape_tags(&before.text), unescape_tags(&after.text)) {
+                            (Ok(before_tags), Ok(after_tags)) => (before.text, after.text) = (before_tags, after_tags),
+                            (Err(e), _) | (_, Err(e)) => {
+                                pairs.push(Err(RowError::new(after_position, e)));
+                                continue;
+                            }
                         }
                     }
+                    pairs.push(Ok(RevisionPair {
+                        post_id: *post_id,
+                        revision,
+                        field,
+                        before_text: before.text,
+                        before_date: before.date,
+                        before_license: before.license,
+                        after_text: after.text,
+                        after_date: after.date,
+                        after_license: after.license,
+                        edit_delay: seconds_since(qinfo.created, after.date),
+                        question_age: seconds_since(question_created, after.date),
+                        comment: after.comment,
+                        reverted,
+                        author_id: qinfo.author_id,
+                        author_name: qinfo.author_name.clone(),
+                        editor_id,
+                        tags: qinfo.tags.clone(),
+                        context: context.clone(),
+                        metadata: qinfo.metadata.clone(),
+                    }));
                 }
-            },
-            _ => (),
+                pairs
+            })
+    }
+
+    /// The question of an answer, from the revisions recorded for it.
+    fn question_context(&self, parent_id: PostId, load: &mut impl FnMut(u64) -> std::result::Result<Option<Revision>, RowError>) -> std::result::Result<QuestionContext, RowError> {
+        let mut context = QuestionContext {
+            post_id: parent_id,
+            ..QuestionContext::default()
+        };
+        let Some(parent) = self.parents.get(&parent_id) else {
+            return Ok(context);
+        };
+        if parent.title_position != u64::MAX {
+            context.title = load(parent.title_position)?.map(|title| title.text).unwrap_or_default();
+        }
+        if parent.body_position != u64::MAX {
+            context.body = load(parent.body_position)?.map(|body| body.text).unwrap_or_default();
+        }
+        Ok(context)
+    }
+}
+
+fn load_layer_1(args: &Layer2Args) -> Result<QuestionHistory> {
+    println!("Loading question index from {}", args.layer1.display());
+    let mut rejects = args.errors.rejects("layer2", &args.layer1)?;
+    let (reader, pb) = open_input(&args.layer1)?;
+    let mut rows = Vec::new();
+    for row in read_layer1(reader) {
+        if let Some(row) = rejects.check(row)? {
+            rows.push(row);
         }
-        xml_buf.clear();
     }
+    let dataset = QuestionHistory::new(rows, args.editors, args.revisions, args.fields.clone());
+    pb.finish();
+    rejects.finish()?;
+    println!("Loaded {} question items from Layer1.", dataset.len());
+    Ok(dataset)
+}
+
+fn layer2_scan(args: &Layer2Args, l1: &mut QuestionHistory, offset: u64, rejects: &mut Rejects, checkpoints: &mut Checkpointer) -> Result<u64> {
+    // a resumed scan goes on through the dump
+    let pb = if let (Some(index), 0) = (&args.index, offset) {
+        let index = PostHistoryIndex::open(index, &args.infile)?;
+        let pb = crate::progress_bar(index.len());
+        println!("Loading question histories from {} through its index", args.infile.display());
+        l1.scan_index(&index, &args.infile, &pb, rejects)?;
+        checkpoints.save_finished(l1)?;
+        pb
+    } else if is_compressed(&args.infile) {
+        println!("Loading question histories from {}", args.infile.display());
+        let (reader, pb) = open_input_from(&args.infile, offset)?;
+        l1.scan(reader, offset, rejects, checkpoints)?;
+        pb
+    } else {
+        let chunks = args.chunks.chunks(&args.infile, offset)?;
+        let pb = chunk_progress_bar(&args.infile, offset)?;
+        println!("Loading question histories from {} in {} chunks", args.infile.display(), chunks.len());
+        l1.scan_chunks(&args.infile, &chunks, &args.chunks, &pb, rejects, checkpoints)?;
+        pb
+    };
 
     pb.finish();
+    let total_items = l1.candidates();
     println!("Loaded {total_items} items in scan-filter!");
 
-    total_items
+    Ok(total_items)
+}
+
+fn layer2_scan_filter(attrs: &BytesStart, l1: &QuestionHistory) -> Result<Option<ScannedRevision>> {
+    layer2_scan_row(attrs, l1.licensed, |post_id, history_type| l1.tracks(post_id, history_type))
+}
+
+/// Reads a PostHistory.xml row of interest to `tracks` (see [`QuestionHistory::tracks`]);
+/// `licensed` tells whether the rows have a ContentLicense.
+fn layer2_scan_row(attrs: &BytesStart, licensed: bool, tracks: impl Fn(PostId, Option<u8>) -> bool) -> Result<Option<ScannedRevision>> {
+    let mut post_id = None;
+    let kind = match layer2_scan_attributes(attrs, licensed, &tracks, &mut post_id) {
+        Ok(None) => return Ok(None),
+        Ok(Some(kind)) => Ok(kind),
+        Err(e) => Err(e),
+    };
+    match post_id {
+        Some(post_id) => Ok(Some(ScannedRevision { post_id, kind })),
+        None => kind.map(|_| None),
+    }
 }
 
-fn layer2_scan_filter(attrs: BytesStart, l1: &BTreeMap<i64, QInfo>) -> Layer2ScanFilterAction {
+fn layer2_scan_attributes(attrs: &BytesStart, licensed: bool, tracks: &impl Fn(PostId, Option<u8>) -> bool, post_id: &mut Option<PostId>) -> Result<Option<RevisionKind>> {
     let mut checks = 0;
-    const REQUIRED_CHECKS : i32 = 5;
+    // the UserId is only required of revisions of Layer1 posts, not of their questions
+    let required_checks = if licensed { 4 } else { 3 };
 
-    let mut post_id = -1;
-    let mut user_id = -1;
-    let mut is_original = false;
+    let mut user_id = None;
+    let mut user_name = None;
+    let mut history_type = None;
+    let mut guid = None;
+    let mut target = None;
+    let mut date = None;
 
     let attrs = attrs.attributes();
 
-    for attr in attrs.map(Result::unwrap) {
+    for attr in attrs {
+        let attr = attr?;
         let attr_key = attr.key.as_ref();
         let attr_val = attr.value.as_ref();
 
         match attr_key {
             b"PostId" => {
-                post_id = i64::from_str(std::str::from_utf8(attr_val).unwrap()).unwrap();
-                if !l1.contains_key(&post_id) {
-                    return Layer2ScanFilterAction::Ignore;
-                }
-                if l1[&post_id].delete {
-                    return Layer2ScanFilterAction::Ignore;
+                let id = parse_attribute("PostId", attr_val)?;
+                if !tracks(id, history_type) {
+                    return Ok(None);
                 }
+                *post_id = Some(id);
                 checks += 1;
             }
             b"PostHistoryTypeId" => {
-                match attr_val {
-                    | b"2" => { // original post
-                        is_original = true;
-                    }
-                    | b"5" => { // edit post
-                        // no-op
-                    }
-                    | b"1"  // original title
-                    | b"4"  // edit title
-                    | _ => { return Layer2ScanFilterAction::Ignore; }
+                history_type = match attr_val {
+                    | b"1" => Some(1), // original title
+                    | b"2" => Some(2), // original body
+                    | b"3" => Some(3), // original tags
+                    | b"4" => Some(4), // edit title
+                    | b"5" => Some(5), // edit body
+                    | b"6" => Some(6), // edit tags
+                    | b"7" => Some(7), // rollback title
+                    | b"8" => Some(8), // rollback body
+                    | b"9" => Some(9), // rollback tags
+                    _ => { return Ok(None); }
+                };
+                if post_id.is_some_and(|id| !tracks(id, history_type)) {
+                    return Ok(None);
                 }
                 checks += 1;
             }
+            // read along with the text, whatever license it is
             b"ContentLicense" => {
-                match attr_val {
-                    | b"CC BY-SA 2.5"
-                    | b"CC BY-SA 3.0"
-                    | b"CC BY-SA 4.0"
-                    => {}
-                    _ => {
-                        panic!("Not CC BY-SA 2.5/3.0/4.0! Found {}",
-                               std::str::from_utf8(attr_val).unwrap());
-                    }
-                }
                 checks += 1;
             }
+            b"RevisionGUID" => {
+                guid = Some(parse_guid_attribute("RevisionGUID", attr_val)?);
+            }
             b"UserId" => {
-                user_id = i32::from_str(std::str::from_utf8(attr_val).unwrap()).unwrap();
-                checks += 1;
+                user_id = Some(parse_attribute("UserId", attr_val)?);
+            }
+            b"UserDisplayName" => {
+                user_name = Some(attr.unescape_value()?.into_owned());
+            }
+            // the original revisions date the post
+            b"CreationDate" if history_type.is_none_or(|history_type| history_type <= 3) => {
+                date = Some(parse_date_attribute("CreationDate", attr_val)?);
+            }
+            // rollbacks name the revision they restore as "Rollback to [guid]"; one that cannot
+            // be read reverts the last edit instead
+            b"Comment" if history_type.is_some_and(|history_type| history_type >= 7) => {
+                target = attr_val.strip_prefix(b"Rollback to [")
+                    .and_then(|rest| rest.strip_suffix(b"]"))
+                    .and_then(|target| parse_guid_attribute("Comment", target).ok());
             }
             b"Text" => {
                 checks += 1;
@@ -177,146 +1004,669 @@ fn layer2_scan_filter(attrs: BytesStart, l1: &BTreeMap<i64, QInfo>) -> Layer2Sca
             _ => (),
         }
 
-        if checks == REQUIRED_CHECKS {
+        let original = history_type.is_some_and(|history_type| history_type <= 3);
+        let rollback = history_type.is_some_and(|history_type| history_type >= 7);
+        if checks == required_checks && user_id.is_some() && guid.is_some()
+            && (!original || date.is_some()) && (!rollback || target.is_some())
+        {
             break;
         }
     }
 
-    if checks == REQUIRED_CHECKS {
-        if is_original {
-            Layer2ScanFilterAction::AddBefore(post_id)
-        } else {
-            if l1[&post_id].author_id == user_id {
-                // wrong author! ergo more than one edit, delete
-                Layer2ScanFilterAction::Delete(post_id)
-            } else if l1[&post_id].after_position != u64::MAX {
-                // too many edits, delete
-                Layer2ScanFilterAction::Delete(post_id)
-            } else {
-                Layer2ScanFilterAction::AddAfter(post_id)
+    let Some(history_type) = history_type.filter(|_| checks == required_checks) else {
+        return Ok(None);
+    };
+    let field = Field::of_history_type(history_type);
+    let editor = match user_id {
+        Some(user_id) => Editor::User(user_id),
+        None => Editor::Deleted(user_name),
+    };
+    Ok(Some(match history_type {
+        1..=3 => RevisionKind::Original { field, has_user: user_id.is_some(), guid, date },
+        4..=6 => RevisionKind::Edit { field, editor, guid },
+        _ => RevisionKind::Rollback { field, editor, guid, target },
+    }))
+}
+
+fn layer2_create_output(args: &Layer2Args) -> Result<TsvWriter<OutputFile>> {
+    let underlying_stream = args.output.create(&args.output.output_path(&args.outfile))?;
+    Ok(TsvWriter::new(underlying_stream, args.flush_interval))
+}
+
+/// Pairs set apart from the Layer2 output, or labeled in it.
+#[derive(Default)]
+struct SetApart {
+    /// Pairs whose edit was rolled back.
+    reverted: u64,
+    /// Pairs whose edit was made outside `--min-edit-delay` and `--max-edit-delay`.
+    outside_window: u64,
+}
+
+/// Writes a revision pair to `writer`, unless it is set apart, counting it in `set_apart`.
+fn layer2_write_pair(args: &Layer2Args, pair: std::result::Result<RevisionPair, RowError>, writer: &mut TsvWriter<OutputFile>, rejects: &mut Rejects, set_apart: &mut SetApart) -> Result<()> {
+    if let Some(pair) = rejects.check(pair)? {
+        if !EditDelay::admits(pair.edit_delay, args.min_edit_delay, args.max_edit_delay) {
+            set_apart.outside_window += 1;
+            return Ok(());
+        }
+        if pair.reverted {
+            set_apart.reverted += 1;
+            if args.rollbacks == Rollbacks::Drop {
+                return Ok(());
             }
         }
-    } else {
-        Layer2ScanFilterAction::Ignore
+        writer.write_row(&pair)?;
     }
+    Ok(())
 }
 
-fn layer2_generate(args: &Layer2Args, l1: &BTreeMap<i64, QInfo>, scan_count: u64) {
-    let mut writer = {
-        let underlying_stream = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&args.outfile)
-            .expect("Failed to open OUTFILE for writing");
-        BufWriter::new(underlying_stream)
-    };
-
-    let mut reader = BufReader::new(
-        OpenOptions::new()
-            .read(true)
-            .open(&args.infile)
-            .expect("Failed to open INFILE for reading")
-    );
-
+/// Writes the revision pairs of `l1` to `writer`, counting those set apart in `set_apart`.
+fn layer2_generate(args: &Layer2Args, l1: &QuestionHistory, scan_count: u64, writer: &mut TsvWriter<OutputFile>, rejects: &mut Rejects, set_apart: &mut SetApart) -> Result<()> {
     println!("Extracting results and writing to {}", args.outfile.display());
 
     let pb = crate::progress_bar(scan_count);
 
-    let mut out_count = l1.len() as u64;
-
-    for (post_id, qinfo) in l1 {
+    let mut write = |pair: std::result::Result<RevisionPair, RowError>| -> Result<()> {
         pb.inc(1);
-        if qinfo.delete || qinfo.before_position == u64::MAX || qinfo.after_position == u64::MAX {
-            out_count -= 1;
-            continue
-        }
-        let before = layer2_load_revision(qinfo.before_position, &mut reader);
-        let after = layer2_load_revision(qinfo.after_position, &mut reader);
+        layer2_write_pair(args, pair, writer, rejects, set_apart)
+    };
 
-        if before.is_none() || after.is_none() {
-            out_count -= 1;
-            continue;
+    if is_compressed(&args.infile) {
+        println!("Reading revisions back from {} in one pass", args.infile.display());
+        let (reader, read_pb) = open_input(&args.infile)?;
+        let pairs = l1.revision_pairs_sequential(reader)?;
+        read_pb.finish_and_clear();
+        for pair in pairs {
+            write(pair)?;
         }
-
-        let before = before.unwrap();
-        let after = after.unwrap();
-
-        let detabulated_before_text = before.text.replace("\t"," ");
-        let detabulated_after_text = after.text.replace("\t", " ");
-
-        writeln!(writer, "{}\t{}\t{}\t{}\t{}",
-            post_id,
-            detabulated_before_text,
-            before.date.format(crate::DATE_FORMAT),
-            detabulated_after_text,
-            after.date.format(crate::DATE_FORMAT)
-        ).unwrap();
-        if writer.buffer().len() >= args.flush_interval {
-            writer.flush().unwrap();
+    } else {
+        let mut reader = BufReader::new(
+            OpenOptions::new()
+                .read(true)
+                .open(&args.infile)?
+        );
+        for pair in l1.revision_pairs(&mut reader) {
+            write(pair)?;
         }
     }
 
-    writer.flush().unwrap();
     pb.finish();
 
+    Ok(())
+}
+
+fn layer2_finish(args: &Layer2Args, writer: TsvWriter<OutputFile>, set_apart: SetApart) -> Result<u64> {
+    let out_count = writer.finish()?;
+    let SetApart { reverted, outside_window } = set_apart;
+
     println!("Finished writing. Found {out_count} candidate revision pairs.");
+    match args.rollbacks {
+        Rollbacks::Drop => println!("Dropped {reverted} pairs whose edit was rolled back."),
+        Rollbacks::Label => println!("Labeled {reverted} pairs whose edit was rolled back."),
+    }
+    if args.min_edit_delay.is_some() || args.max_edit_delay.is_some() {
+        println!("Dropped {outside_window} pairs whose edit was made outside the edit delay window.");
+    }
+
+    Ok(out_count)
 }
 
 struct Revision {
     date: NaiveDateTime,
     text: String,
+    /// The editor's summary of the edit, empty if they gave none.
+    comment: String,
+    /// The ContentLicense of the revision, empty if the dump does not record it.
+    license: String,
 }
 
-fn layer2_load_revision(
+/// Returns `None` for a revision without text.
+fn layer2_load_revision<R: BufRead + Seek>(
     position: u64,
-    reader: &mut BufReader<File>
-) -> Option<Revision> {
-    reader.seek(SeekFrom::Start(position + 1)).unwrap();
+    reader: &mut R
+) -> Result<Option<Revision>> {
+    reader.seek(SeekFrom::Start(position))?;
     let mut str_buf = String::new();
-    reader.read_line(&mut str_buf).unwrap();
-    let mut xml_reader = Reader::from_str(&str_buf);
+    reader.read_line(&mut str_buf)?;
+    layer2_parse_revision(&str_buf)
+}
+
+/// Reads the rows at the `positions` of a PostHistory.xml stream, in increasing order, passing
+/// each line to `f` along with its position and what was asked along with it; a position asked
+/// more than once is read once.
+fn layer2_read_rows_at<R: BufRead, T>(
+    mut reader: R,
+    positions: impl IntoIterator<Item = Result<(u64, T)>>,
+    mut f: impl FnMut(u64, T, &str) -> Result<()>,
+) -> Result<()> {
+    let mut offset = 0;
+    let mut line = String::new();
+    let mut last = None;
+    for position in positions {
+        let (position, asked) = position?;
+        if last != Some(position) {
+            let skipped = std::io::copy(&mut (&mut reader).take(position - offset), &mut std::io::sink())?;
+            if skipped < position - offset {
+                return Err(Error::Io(std::io::Error::new(ErrorKind::UnexpectedEof,
+                    format!("PostHistory.xml ends before byte {position}"))));
+            }
+            line.clear();
+            let n = reader.read_line(&mut line)?;
+            offset = position + n as u64;
+            last = Some(position);
+        }
+        f(position, asked, &line)?;
+    }
+    Ok(())
+}
+
+/// Parses the revision row on `line`; returns `None` for a revision without text.
+fn layer2_parse_revision(line: &str) -> Result<Option<Revision>> {
+    let mut xml_reader = Reader::from_str(line);
     loop {
-        match xml_reader.read_event() {
-            Err(e) => panic!("Hit error {e} while parsing BUFFER {str_buf}"),
-            Ok(Event::Eof) => panic!("Hit end-of-document for BUFFER {str_buf} at position {position}"),
-            Ok(Event::Empty(elm)) => {
-                assert_eq!(elm.name().as_ref(), b"row");
+        match xml_reader.read_event()? {
+            Event::Eof => return Err(Error::MissingAttribute("row")),
+            Event::Empty(elm) => {
+                if elm.name().as_ref() != b"row" {
+                    return Err(Error::MissingAttribute("row"));
+                }
                 let attrs = elm.attributes();
                 let mut text = None;
                 let mut date = None;
-                for attr in attrs.map(Result::unwrap) {
+                let mut comment = String::new();
+                let mut license = String::new();
+                for attr in attrs {
+                    let attr = attr?;
                     match attr.key.as_ref() {
                         b"Text" => {
-                            text = Some(String::from_utf8(attr.value.as_ref().to_vec()).unwrap());
+                            text = Some(parse_attribute("Text", &attr.value)?);
                         }
                         b"CreationDate" => {
-                            let s = std::str::from_utf8(attr.value.as_ref()).unwrap();
-                            date = NaiveDateTime::parse_from_str(s, crate::DATE_FORMAT).ok();
+                            date = Some(parse_date_attribute("CreationDate", &attr.value)?);
+                        }
+                        b"Comment" => {
+                            comment = parse_attribute("Comment", &attr.value)?;
+                        }
+                        b"ContentLicense" => {
+                            license = attr.unescape_value()?.into_owned();
                         }
                         _ => {}
                     }
-                    if text.is_some() && date.is_some() {
-                        break
+                }
+                let Some(text) = text else {
+                    return Ok(None);
+                };
+                return Ok(Some(Revision {
+                    date: date.ok_or(Error::MissingAttribute("CreationDate"))?,
+                    text,
+                    comment,
+                    license,
+                }))
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The tags in the (escaped) text of a tags revision, written like [`Tags`].
+fn unescape_tags(text: &str) -> Result<String> {
+    let tags = quick_xml::escape::unescape(text)
+        .map_err(|e| Error::bad_attribute("Text", text.as_bytes(), e))?;
+    Ok(Tags::from_attribute(&tags).to_string())
+}
+
+/// Returns the number of revision pairs written to OUTFILE.
+pub fn layer2_filter(args: &Layer2Args) -> Result<u64> {
+    let schema = detect_schema(&args.infile, &["PostId", "PostHistoryTypeId", "CreationDate", "Text"], &["ContentLicense", "RevisionGUID", "UserId", "Comment"])?;
+    if args.memory.is_bounded() {
+        if args.checkpoints.resume {
+            return Err(Error::Usage("--resume cannot be combined with --memory-budget".to_string()));
+        }
+        return layer2_filter_sorted(args, schema.has("ContentLicense"));
+    }
+    let mut checkpoints = args.checkpoints.checkpointer(&args.infile, &args.outfile)?;
+    let (offset, mut l1) = match checkpoints.resume()? {
+        Some(resumed) => resumed,
+        None => (0, load_layer_1(args)?),
+    };
+    l1.licensed = schema.has("ContentLicense");
+
+    let mut rejects = args.errors.rejects("layer2", &args.infile)?;
+
+    let scan_count = layer2_scan(args, &mut l1, offset, &mut rejects, &mut checkpoints)?;
+
+    let mut writer = layer2_create_output(args)?;
+    let mut set_apart = SetApart::default();
+    layer2_generate(args, &l1, scan_count, &mut writer, &mut rejects, &mut set_apart)?;
+    let written = layer2_finish(args, writer, set_apart)?;
+
+    rejects.finish()?;
+    checkpoints.clear()?;
+
+    Ok(written)
+}
+
+/// A revision of PostHistory.xml, for [`layer2_filter_sorted`]. Problems with the row found
+/// after its post id are kept as their message, like in [`ScannedRevision`], until it is known
+/// whether the post is of interest.
+#[derive(Serialize, Deserialize)]
+struct SortedRevision {
+    post_id: PostId,
+    position: u64,
+    kind: std::result::Result<RevisionKind, String>,
+}
+
+impl SortedRevision {
+    fn scanned(self) -> (u64, ScannedRevision) {
+        let kind = self.kind.map_err(|reason| Error::Row(RowParseError::new("PostHistory", reason)));
+        (self.position, ScannedRevision { post_id: self.post_id, kind })
+    }
+}
+
+/// A post with an edit to pair and, for an answer, the revisions of its question, for
+/// [`layer2_filter_sorted`].
+#[derive(Serialize, Deserialize)]
+struct RecordedPost {
+    post_id: PostId,
+    qinfo: QInfo,
+    parent: Option<ParentInfo>,
+}
+
+/// The revisions of PostHistory.xml in post id and dump order, for [`layer2_filter_sorted`]:
+/// either those of every post, sorted on disk, or those of the posts asked for, read through an
+/// index of the dump.
+enum PostRevisions<S: Iterator, E: Iterator> {
+    Sorted(Peekable<S>),
+    Index { entries: Peekable<E>, dump: BufReader<File> },
+}
+
+impl<S, E> PostRevisions<S, E>
+where
+    S: Iterator<Item = Result<SortedRevision>>,
+    E: Iterator<Item = Result<IndexEntry>>,
+{
+    /// Applies the revisions of `post_id` to `history`, passing over those of the posts before
+    /// it.
+    fn apply(&mut self, post_id: PostId, history: &mut QuestionHistory, rejects: &mut Rejects) -> Result<()> {
+        match self {
+            PostRevisions::Sorted(revisions) => {
+                while let Some(revision) = revisions.next_if(|revision| revision.as_ref().map_or(true, |revision| revision.post_id <= post_id)) {
+                    let revision = revision?;
+                    if revision.post_id == post_id {
+                        history.apply(Ok(revision.scanned()), rejects)?;
+                    }
+                }
+            }
+            PostRevisions::Index { entries, dump } => {
+                while let Some(entry) = entries.next_if(|entry| entry.as_ref().map_or(true, |entry| entry.post_id <= post_id)) {
+                    let entry = entry?;
+                    if entry.post_id != post_id || !(1..=9).contains(&entry.history_type) || !history.tracks(post_id, Some(entry.history_type)) {
+                        continue;
                     }
+                    let row = entry.read_row(dump)?;
+                    let mut rows = RowReader::resume_at(row.as_slice(), entry.offset);
+                    if let Some(row) = rows.next_map(|element| layer2_scan_filter(element, history)) {
+                        history.apply(row, rejects)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads Layer1 in post id order through an external sort, for [`layer2_filter_sorted`], along
+/// with the questions its answers belong to, in post id order.
+fn layer2_sort_l1(args: &Layer2Args) -> Result<(impl Iterator<Item = Result<PostRow>>, impl Iterator<Item = Result<PostId>>)> {
+    println!("Sorting question index from {}", args.layer1.display());
+    let mut rejects = args.errors.rejects("layer2", &args.layer1)?;
+    let (reader, pb) = open_input(&args.layer1)?;
+    let mut posts = args.memory.sorter("layer2-posts", |row: &PostRow| row.post_id);
+    let mut parents = args.memory.sorter("layer2-parents", |parent_id: &PostId| *parent_id);
+    for row in read_layer1(reader) {
+        if let Some(row) = rejects.check(row)? {
+            if let Some(parent_id) = row.parent_id {
+                parents.push(parent_id)?;
+            }
+            posts.push(row)?;
+        }
+    }
+    pb.finish();
+    rejects.finish()?;
+    println!("Sorted {} question items from Layer1, spilling {} runs to disk.", posts.len(), posts.runs() + parents.runs());
+    // answers to the same question share it
+    let mut last = None;
+    let parents = parents.finish()?
+        .filter(move |parent_id| parent_id.as_ref().map_or(true, |parent_id| last.replace(*parent_id) != Some(*parent_id)));
+    Ok((posts.finish()?, parents))
+}
+
+/// Reads the revisions of the chosen fields of every post in PostHistory.xml, and the original
+/// titles and bodies, in post id and dump order through an external sort, for
+/// [`layer2_filter_sorted`].
+fn layer2_sort_revisions(args: &Layer2Args, licensed: bool, rejects: &mut Rejects) -> Result<impl Iterator<Item = Result<SortedRevision>>> {
+    let tracks = |_: PostId, history_type: Option<u8>| history_type.is_none_or(|history_type| {
+        history_type <= 2 || args.fields.contains(&Field::of_history_type(history_type))
+    });
+    let scan = |element: &BytesStart| layer2_scan_row(element, licensed, tracks);
+    let mut sorter = args.memory.sorter("layer2-revisions", |revision: &SortedRevision| (revision.post_id, revision.position));
+    let mut push = |row: std::result::Result<(u64, ScannedRevision), RowError>| -> Result<()> {
+        if let Some((position, ScannedRevision { post_id, kind })) = rejects.check(row)? {
+            sorter.push(SortedRevision { post_id, position, kind: kind.map_err(|e| e.to_string()) })?;
+        }
+        Ok(())
+    };
+    let pb = if is_compressed(&args.infile) {
+        println!("Sorting the revisions of {}", args.infile.display());
+        let (reader, pb) = open_input(&args.infile)?;
+        let mut rows = RowReader::new(reader);
+        while let Some(row) = rows.next_map(scan) {
+            push(row)?;
+        }
+        pb
+    } else {
+        let chunks = args.chunks.chunks(&args.infile, 0)?;
+        let pb = chunk_progress_bar(&args.infile, 0)?;
+        println!("Sorting the revisions of {} in {} chunks", args.infile.display(), chunks.len());
+        args.chunks.for_each_chunk(&chunks, &mut (), |_, chunk| {
+            let mut rows = open_chunk(&args.infile, chunk, &pb)?;
+            Ok(std::iter::from_fn(|| rows.next_map(scan)).collect::<Vec<_>>())
+        }, |_, _, rows| {
+            for row in rows {
+                push(row)?;
+            }
+            Ok(())
+        })?;
+        pb
+    };
+    pb.finish();
+    println!("Sorted {} revisions, spilling {} runs to disk", sorter.len(), sorter.runs());
+    sorter.finish()
+}
+
+/// Records the revisions of the sorted Layer1 `posts`, and of the questions of its answers
+/// (`parents`), one post at a time in `history`. Returns the posts with an edit to pair in post
+/// id order, answers along with the revisions of their question.
+fn layer2_join_sorted<S, E>(
+    args: &Layer2Args,
+    history: &mut QuestionHistory,
+    posts: impl Iterator<Item = Result<PostRow>>,
+    parents: impl Iterator<Item = Result<PostId>>,
+    mut revisions: PostRevisions<S, E>,
+    rejects: &mut Rejects,
+) -> Result<impl Iterator<Item = Result<RecordedPost>>>
+where
+    S: Iterator<Item = Result<SortedRevision>>,
+    E: Iterator<Item = Result<IndexEntry>>,
+{
+    println!("Joining the Layer1 posts with their revisions");
+    let mut recorded = args.memory.sorter("layer2-recorded", |post: &RecordedPost| post.post_id);
+    let mut answers = args.memory.sorter("layer2-answers", |post: &RecordedPost| post.qinfo.parent_id);
+    let mut parent_infos = args.memory.spill_vec("layer2-questions");
+    let mut posts = posts.peekable();
+    let mut parents = parents.peekable();
+    loop {
+        // the next post of Layer1 or question of one of its answers
+        let next_post = posts.peek().map(|row| row.as_ref().map_or(PostId::MIN, |row| row.post_id));
+        let next_parent = parents.peek().map(|parent_id| *parent_id.as_ref().unwrap_or(&PostId::MIN));
+        let Some(post_id) = next_post.into_iter().chain(next_parent).min() else {
+            break;
+        };
+        if let Some(row) = posts.next_if(|row| row.as_ref().map_or(true, |row| row.post_id == post_id)) {
+            history.insert(row?);
+        }
+        if let Some(parent_id) = parents.next_if(|parent_id| parent_id.as_ref().map_or(true, |parent_id| *parent_id == post_id)) {
+            history.parents.insert(parent_id?, ParentInfo::new());
+        }
+        revisions.apply(post_id, history, rejects)?;
+        if let Some(parent) = history.parents.remove(&post_id) {
+            parent_infos.push((post_id, parent))?;
+        }
+        if let Some(qinfo) = history.questions.remove(&post_id).filter(QInfo::is_recorded) {
+            let post = RecordedPost { post_id, qinfo, parent: None };
+            if post.qinfo.parent_id.is_some() {
+                answers.push(post)?;
+            } else {
+                recorded.push(post)?;
+            }
+        }
+    }
+
+    // join the answers with the revisions of their questions, both in question order
+    let mut parent_infos = parent_infos.into_rows()?.peekable();
+    for answer in answers.finish()? {
+        let mut answer = answer?;
+        answer.parent = loop {
+            match parent_infos.peek() {
+                Some(Ok((parent_id, _))) if Some(*parent_id) < answer.qinfo.parent_id => {
+                    parent_infos.next();
                 }
-                if text.is_none() {
-                    return None
+                Some(Ok((parent_id, parent))) if Some(*parent_id) == answer.qinfo.parent_id => break Some(parent.clone()),
+                Some(Err(_)) => {
+                    parent_infos.next().transpose()?;
                 }
-                return Some(Revision {
-                    date: date.unwrap(),
-                    text: text.unwrap(),
-                })
+                _ => break None,
             }
-            _ => {}
+        };
+        recorded.push(answer)?;
+    }
+    recorded.finish()
+}
+
+/// Writes the revision pairs of the `recorded` posts to `writer`, counting those set apart in
+/// `set_apart`. The revisions the posts need are sorted by position on disk, read from
+/// PostHistory.xml in one pass and sorted back to the posts.
+fn layer2_generate_sorted(
+    args: &Layer2Args,
+    history: &mut QuestionHistory,
+    recorded: impl Iterator<Item = Result<RecordedPost>>,
+    writer: &mut TsvWriter<OutputFile>,
+    rejects: &mut Rejects,
+    set_apart: &mut SetApart,
+) -> Result<()> {
+    let mut posts = args.memory.spill_vec("layer2-pairs");
+    let mut positions = args.memory.sorter("layer2-positions", |&(position, _): &(u64, u64)| position);
+    for post in recorded {
+        let post = post?;
+        for position in post.qinfo.positions(post.parent.as_ref()) {
+            positions.push((position, posts.len()))?;
+        }
+        posts.push(post)?;
+    }
+
+    println!("Reading back {} revisions from {} in one pass", positions.len(), args.infile.display());
+    let mut revisions = args.memory.sorter("layer2-lines", |(post, _, _): &(u64, u64, String)| *post);
+    let (reader, pb) = open_input(&args.infile)?;
+    layer2_read_rows_at(reader, positions.finish()?, |position, post, line| {
+        revisions.push((post, position, line.to_string()))
+    })?;
+    pb.finish_and_clear();
+
+    println!("Extracting results and writing to {}", args.outfile.display());
+    let pb = crate::progress_bar(posts.len());
+    let mut revisions = revisions.finish()?.peekable();
+    for (i, post) in posts.into_rows()?.enumerate() {
+        let RecordedPost { post_id, qinfo, parent } = post?;
+        let mut lines = HashMap::new();
+        while let Some(revision) = revisions.next_if(|revision| revision.as_ref().map_or(true, |(post, _, _)| *post == i as u64)) {
+            let (_, position, line) = revision?;
+            lines.insert(position, line);
+        }
+        if let (Some(parent_id), Some(parent)) = (qinfo.parent_id, parent) {
+            history.parents.insert(parent_id, parent);
         }
+        history.questions.insert(post_id, qinfo);
+        let pairs = history.pairs_with(|position| layer2_parse_revision(&lines[&position]));
+        for pair in pairs {
+            layer2_write_pair(args, pair, writer, rejects, set_apart)?;
+        }
+        history.questions.clear();
+        history.parents.clear();
+        pb.inc(1);
     }
+    pb.finish();
+
+    Ok(())
+}
+
+/// Like [`layer2_filter`], but joins Layer1 and PostHistory.xml sorted by post id on disk,
+/// instead of holding the revisions of every Layer1 post in memory. PostHistory.xml is scanned
+/// once (or, given its index, only the rows of the posts are read), and read once more for the
+/// revisions to pair.
+fn layer2_filter_sorted(args: &Layer2Args, licensed: bool) -> Result<u64> {
+    let (posts, parents) = layer2_sort_l1(args)?;
+    let mut rejects = args.errors.rejects("layer2", &args.infile)?;
+    let mut history = QuestionHistory::new(Vec::new(), args.editors, args.revisions, args.fields.clone());
+    history.licensed = licensed;
+
+    let index = args.index.as_ref()
+        .map(|index| PostHistoryIndex::open(index, &args.infile))
+        .transpose()?;
+    let revisions = match &index {
+        Some(index) => {
+            println!("Reading the revisions of the Layer1 posts from {} through its index", args.infile.display());
+            PostRevisions::Index {
+                entries: index.entries()?.peekable(),
+                dump: BufReader::new(File::open(&args.infile)?),
+            }
+        }
+        None => PostRevisions::Sorted(layer2_sort_revisions(args, licensed, &mut rejects)?.peekable()),
+    };
+    let recorded = layer2_join_sorted(args, &mut history, posts, parents, revisions, &mut rejects)?;
+    println!("Loaded {} items in scan-filter!", history.candidates());
+
+    let mut writer = layer2_create_output(args)?;
+    let mut set_apart = SetApart::default();
+    layer2_generate_sorted(args, &mut history, recorded, &mut writer, &mut rejects, &mut set_apart)?;
+    let written = layer2_finish(args, writer, set_apart)?;
+    rejects.finish()?;
+
+    Ok(written)
 }
 
-pub fn layer2_filter(args: &Layer2Args) {
-    let mut l1 = load_layer_1(args);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{parse_args, TestDir, LAYER1_TSV, LAYER2_TSV, POST_HISTORY_XML};
+
+    #[test]
+    fn revision_pairs_round_trip() {
+        let date = |date| NaiveDateTime::parse_from_str(date, crate::DATE_FORMAT).unwrap();
+        let pair = RevisionPair {
+            post_id: 4,
+            revision: 2,
+            field: Field::Title,
+            before_text: "Title four".to_string(),
+            before_date: date("2011-01-01T00:00:00.000"),
+            before_license: String::new(),
+            after_text: "Title four, edited".to_string(),
+            after_date: date("2011-02-01T12:30:00.250"),
+            after_license: "CC BY-SA 3.0".to_string(),
+            edit_delay: None,
+            question_age: None,
+            comment: String::new(),
+            reverted: true,
+            author_id: DELETED_USER_ID,
+            author_name: "ghost".to_string(),
+            editor_id: 12,
+            tags: Tags::from_attribute("<rust>"),
+            context: Some(QuestionContext::default()),
+            metadata: PostMetadata { score: Some(-1), ..PostMetadata::default() },
+        };
+        assert_eq!(pair.to_string().parse::<RevisionPair>().unwrap(), pair);
+        for line in LAYER2_TSV.lines() {
+            assert_eq!(line.parse::<RevisionPair>().unwrap().to_string(), line);
+        }
+    }
+
+    #[test]
+    fn fields_round_trip() {
+        for field in [Field::Body, Field::Title, Field::Tags] {
+            assert_eq!(field.to_string().parse::<Field>().unwrap(), field);
+        }
+    }
+
+    #[test]
+    fn edit_delays_parse_with_units() {
+        assert_eq!("90".parse::<EditDelay>(), Ok(EditDelay(90)));
+        assert_eq!("30m".parse::<EditDelay>(), Ok(EditDelay(30 * 60)));
+        assert_eq!("2w".parse::<EditDelay>(), Ok(EditDelay(14 * 24 * 60 * 60)));
+        assert!("3x".parse::<EditDelay>().is_err());
+        assert!(format!("{}y", i64::MAX).parse::<EditDelay>().is_err());
+    }
+
+    #[test]
+    fn layer2_pairs_the_edits_of_the_layer1_posts() {
+        let dir = TestDir::new("layer2");
+        let post_history = dir.write("PostHistory.xml", POST_HISTORY_XML);
+        let layer1 = dir.write("layer1.tsv", LAYER1_TSV);
+        let out = dir.file("layer2.tsv");
+        let args = ["--in-file", &post_history, "--in-layer-1", &layer1, "--out-file", &out];
+        assert_eq!(layer2_filter(&parse_args(&args)).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), LAYER2_TSV);
+
+        // joined on disk under a budget, or scanned a row per chunk, the pairs come out the same
+        let args = ["--in-file", &post_history, "--in-layer-1", &layer1, "--out-file", &out, "--memory-budget", "1"];
+        assert_eq!(layer2_filter(&parse_args(&args)).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), LAYER2_TSV);
+        let args = ["--in-file", &post_history, "--in-layer-1", &layer1, "--out-file", &out, "--chunk-size", "1", "--jobs", "3"];
+        assert_eq!(layer2_filter(&parse_args(&args)).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), LAYER2_TSV);
+
+        // the edit of post 3 was rolled back
+        let args = ["--in-file", &post_history, "--in-layer-1", &layer1, "--out-file", &out, "--rollbacks", "label"];
+        assert_eq!(layer2_filter(&parse_args(&args)).unwrap(), 3);
+        let pairs = read_layer2(dir.open("layer2.tsv")).collect::<std::result::Result<Vec<_>, _>>().unwrap();
+        assert_eq!(pairs.iter().map(|pair| (pair.post_id, pair.reverted)).collect::<Vec<_>>(), [(1, false), (2, false), (3, true)]);
+        assert_eq!(pairs[2].after_text, "Body three spam");
+
+        // the edit of post 5 follows one by a deleted user, which it is paired with
+        let args = ["--in-file", &post_history, "--in-layer-1", &layer1, "--out-file", &out, "--revisions", "consecutive"];
+        assert_eq!(layer2_filter(&parse_args(&args)).unwrap(), 3);
+        let pairs = read_layer2(dir.open("layer2.tsv")).collect::<std::result::Result<Vec<_>, _>>().unwrap();
+        assert_eq!((pairs[2].post_id, pairs[2].revision, pairs[2].editor_id), (5, 2, 14));
+        assert_eq!(pairs[2].before_text, "Body five, rewritten");
+    }
+
+    #[test]
+    fn layer2_resumes_from_a_checkpoint() {
+        let dir = TestDir::new("layer2-resume");
+        let post_history = dir.write("PostHistory.xml", POST_HISTORY_XML);
+        let layer1 = dir.write("layer1.tsv", LAYER1_TSV);
+        let out = dir.file("layer2.tsv");
+        let args: Layer2Args = parse_args(&["--in-file", &post_history, "--in-layer-1", &layer1, "--out-file", &out, "--resume"]);
+
+        // a scan interrupted after the revisions of the first two posts
+        let interrupted = POST_HISTORY_XML.find("  <row Id=\"7\"").unwrap();
+        let mut l1 = load_layer_1(&args).unwrap();
+        let mut checkpoints = args.checkpoints.checkpointer(&args.infile, &args.outfile).unwrap();
+        l1.scan(&POST_HISTORY_XML.as_bytes()[..interrupted], 0, &mut Rejects::abort("layer2"), &mut checkpoints).unwrap();
 
-    let scan_count = layer2_scan(args, &mut l1);
+        // resumed with the state of the checkpoint, Layer1 is not read again
+        std::fs::remove_file(&layer1).unwrap();
+        assert_eq!(layer2_filter(&args).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), LAYER2_TSV);
+        assert!(!Path::new(&format!("{out}.ckpt")).exists());
+    }
 
-    layer2_generate(args, &mut l1, scan_count);
+    #[test]
+    fn layer2_rejects_bad_rows_once() {
+        let dir = TestDir::new("layer2-rejects");
+        // a row whose post cannot be told, and a bad row of a post Layer1 did not select
+        let post_history = dir.write("PostHistory.xml", &POST_HISTORY_XML.replace("</posthistory>", "\
+  <row Id=\"12\" PostHistoryTypeId=\"5\" PostId=\"x\" RevisionGUID=\"00000000-0000-0000-0000-00000000000f\" CreationDate=\"2012-01-01T00:00:00.000\" UserId=\"9\" Text=\"?\" ContentLicense=\"CC BY-SA 3.0\" />
+  <row Id=\"13\" PostHistoryTypeId=\"5\" PostId=\"99\" RevisionGUID=\"00000000-0000-0000-0000-000000000010\" CreationDate=\"2012-01-01T00:00:00.000\" UserId=\"y\" Text=\"?\" ContentLicense=\"CC BY-SA 3.0\" />
+</posthistory>"));
+        let layer1 = dir.write("layer1.tsv", LAYER1_TSV);
+        let out = dir.file("layer2.tsv");
+        let rejects = dir.file("rejects.tsv");
+        for budget in [None, Some("1")] {
+            let mut args = vec!["--in-file", &post_history, "--in-layer-1", &layer1, "--out-file", &out, "--on-error", "skip", "--rejects-file", &rejects];
+            args.extend(budget.map(|budget| ["--memory-budget", budget]).into_iter().flatten());
+            assert_eq!(layer2_filter(&parse_args(&args)).unwrap(), 2);
+            assert_eq!(std::fs::read_to_string(&out).unwrap(), LAYER2_TSV);
+            assert_eq!(std::fs::read_to_string(&rejects).unwrap().lines().count(), 1, "budget {budget:?}");
+            std::fs::remove_file(&rejects).unwrap();
+        }
+    }
 }
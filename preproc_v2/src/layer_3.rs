@@ -1,175 +1,555 @@
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
-use std::fs::OpenOptions;
-use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::fmt::{Display, Formatter};
+use std::io::BufRead;
+use std::iter::Peekable;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use chrono::NaiveDateTime;
+use chrono::{NaiveDate, NaiveDateTime};
 use clap::Args;
-use indicatif::ProgressIterator;
-use quick_xml::events::Event;
-use quick_xml::Reader;
+use indicatif::ProgressBar;
+use quick_xml::events::attributes::{Attribute, Attributes};
+use serde::{Deserialize, Serialize};
+use crate::checkpoint::{CheckpointArgs, Checkpointer};
+use crate::chunks::{chunk_progress_bar, open_chunk, ChunkArgs};
+use crate::input::{is_compressed, open_input, open_input_from};
+use crate::output::CompressArgs;
+use crate::error::{Error, ErrorArgs, Rejects, Result, RowError};
+use crate::layer_2::{read_layer2, RevisionPair};
+use crate::PostId;
+use crate::schema::detect_schema;
+use crate::spill::MemoryArgs;
+use crate::xml::{parse_attribute, RowReader};
+use crate::tsv::{parse_column, split_columns, RowParseError, TsvReader, TsvWriter};
 
 #[derive(Args)]
 pub struct Layer3Args {
     #[arg(long = "in-file", required=true)]
-    infile: PathBuf,
+    pub infile: PathBuf,
     #[arg(long = "in-layer-2", required=true)]
-    layer2: PathBuf,
+    pub layer2: PathBuf,
     #[arg(long = "out-file", required=true)]
-    outfile: PathBuf,
-    #[arg(long = "l2-count", default_value_t=2431869)]
-    layer2_size: u64,
-    #[arg(long = "vcount", default_value_t=449071008)]
-    v_count: u64,
+    pub outfile: PathBuf,
     #[arg(long = "flush-interval", default_value_t=1_000_000)]
-    flush_interval: usize,
+    pub flush_interval: usize,
+    #[command(flatten)]
+    pub errors: ErrorArgs,
+    #[command(flatten)]
+    pub output: CompressArgs,
+    #[command(flatten)]
+    pub checkpoints: CheckpointArgs,
+    #[command(flatten)]
+    pub chunks: ChunkArgs,
+    #[command(flatten)]
+    pub memory: MemoryArgs,
 }
 
+/// Up- and down-votes cast on a question before and after an edit; one line of the Layer3
+/// output.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VoteCounts {
+    pub post_id: PostId,
+    /// The revision of the Layer2 pair the edit made.
+    pub revision: u32,
+    pub up_before: u32,
+    pub down_before: u32,
+    pub up_after: u32,
+    pub down_after: u32,
+}
+
+impl Display for VoteCounts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}\t{}\t{}\t{}\t{}\t{}",
+            self.post_id,
+            self.revision,
+            self.up_before,
+            self.down_before,
+            self.up_after,
+            self.down_after
+        )
+    }
+}
+
+impl VoteCounts {
+    /// Net score from the votes cast before the day of the edit.
+    pub fn score_before(&self) -> i64 {
+        i64::from(self.up_before) - i64::from(self.down_before)
+    }
+
+    /// Net score from the votes cast after the day of the edit.
+    pub fn score_after(&self) -> i64 {
+        i64::from(self.up_after) - i64::from(self.down_after)
+    }
+
+    pub fn score_delta(&self) -> i64 {
+        self.score_after() - self.score_before()
+    }
+}
+
+impl FromStr for VoteCounts {
+    type Err = RowParseError;
+
+    fn from_str(line: &str) -> std::result::Result<Self, Self::Err> {
+        const ROW: &str = "layer3";
+        let [post_id, revision, up_before, down_before, up_after, down_after] = split_columns(ROW, line)?;
+        Ok(VoteCounts {
+            post_id: parse_column(ROW, "post id", post_id)?,
+            revision: parse_column(ROW, "revision", revision)?,
+            up_before: parse_column(ROW, "up before", up_before)?,
+            down_before: parse_column(ROW, "down before", down_before)?,
+            up_after: parse_column(ROW, "up after", up_after)?,
+            down_after: parse_column(ROW, "down after", down_after)?,
+        })
+    }
+}
+
+/// Reads back a Layer3 output file.
+pub fn read_layer3<R: BufRead>(reader: R) -> TsvReader<R, VoteCounts> {
+    TsvReader::new(reader)
+}
+
+#[derive(Serialize, Deserialize)]
 struct VCounter {
     edit_time: NaiveDateTime,
-    up_before: u32,
-    down_before: u32,
-    up_after: u32,
-    down_after: u32,
+    counts: VoteCounts,
 }
 
-fn layer3_load_l2_indices(args: &Layer3Args) -> BTreeMap<i64, VCounter> {
-    println!("Loading question index from Layer2 at {}", args.layer2.display());
-    let pb = crate::progress_bar(args.layer2_size);
-    let reader = BufReader::new(OpenOptions::new()
-        .read(true)
-        .open(&args.layer2)
-        .expect("Failed to open IN_LAYER2"));
-    let dataset = reader.lines()
-        .progress_with(pb.clone())
-        .map(|line| {
-            let line = line.unwrap();
-            let post_id = {
-                let first_tab = line.find('\t').expect("No tab in line in TSV");
-                i64::from_str(&line[0..first_tab]).unwrap()
-            };
-            let edit_time = {
-                let last_tab = line.rfind('\t').expect("No (last) tab in line in TSV");
-                NaiveDateTime::parse_from_str(&line[(last_tab+1)..], crate::DATE_FORMAT).unwrap()
-            };
-            (post_id, VCounter {
-                edit_time,
-                up_before: 0,
-                down_before: 0,
-                up_after: 0,
-                down_after: 0,
-            })
-        })
-        .collect::<BTreeMap<i64, VCounter>>();
-    pb.finish();
-    println!("Loaded {} items from Layer2 results", dataset.len());
-    dataset
+/// An up- or down-vote on a tallied question.
+#[derive(Serialize, Deserialize)]
+struct Vote {
+    post_id: PostId,
+    up: bool,
+    vote_day: NaiveDate,
 }
 
-fn layer3_tabulate_vote_counts(args: &Layer3Args, vote_map: &mut BTreeMap<i64, VCounter>) {
-    let mut n_votes = 0;
-    let mut n_proc = 0;
+/// Vote tallies for the edited questions of Layer2.
+#[derive(Serialize, Deserialize)]
+pub struct VoteTally {
+    /// The tallies of the edits of every question, in revision order.
+    votes: BTreeMap<PostId, Vec<VCounter>>,
+    n_counters: usize,
+    n_votes: u64,
+    n_proc: u64,
+}
+
+impl VoteTally {
+    pub fn new(pairs: impl IntoIterator<Item = RevisionPair>) -> Self {
+        let mut votes = BTreeMap::<PostId, Vec<VCounter>>::new();
+        let mut n_counters = 0;
+        for pair in pairs {
+            let edits = votes.entry(pair.post_id).or_default();
+            // the pairs of the fields an edit changed share its tally
+            if edits.last().is_some_and(|vcounter| vcounter.counts.revision == pair.revision) {
+                continue;
+            }
+            edits.push(VCounter::new(&pair));
+            n_counters += 1;
+        }
+        VoteTally { votes, n_counters, n_votes: 0, n_proc: 0 }
+    }
 
-    let pb = crate::progress_bar(args.v_count);
-    let mut reader = Reader::from_file(&args.infile)
-        .expect("Failed to open INFILE for reading");
-    let mut xml_buf = Vec::new();
+    /// Number of edits tallied.
+    pub fn len(&self) -> usize {
+        self.n_counters
+    }
 
-    println!("Tabulating relevant vote counts from {}", args.infile.display());
+    pub fn is_empty(&self) -> bool {
+        self.votes.is_empty()
+    }
 
-    loop {
-        pb.inc(1);
-        n_proc += 1;
-
-        match reader.read_event_into(&mut xml_buf) {
-            Err(e) => panic!("Error at position {}: {e}", reader.buffer_position()),
-            Ok(Event::Eof) => break,
-            Ok(Event::Empty(element)) => {
-                if element.name().as_ref() == b"row" {
-                    let mut attrs = element.attributes();
-                    // ignore row's Id
-                    let _ = attrs.next();
-                    let post_id_attr = attrs.next().unwrap().unwrap();
-                    assert_eq!(post_id_attr.key.as_ref(), b"PostId");
-                    let post_id = i64::from_str(
-                        std::str::from_utf8(post_id_attr.value.as_ref()).unwrap()
-                    ).unwrap();
-                    let mapped = vote_map.get_mut(&post_id);
-                    if let Some(vcounter) = mapped {
-                        let vote_type_attr = attrs.next().unwrap().unwrap();
-                        assert_eq!(vote_type_attr.key.as_ref(), b"VoteTypeId");
-                        let vote_type = vote_type_attr.value.as_ref();
-                        if vote_type != b"2" && vote_type != b"3" {
-                            continue;
-                        }
-                        let date_attr = attrs.next().unwrap().unwrap();
-                        assert_eq!(date_attr.key.as_ref(), b"CreationDate");
-                        let date = NaiveDateTime::parse_from_str(
-                            std::str::from_utf8(date_attr.value.as_ref()).unwrap(),
-                            crate::DATE_FORMAT
-                        ).unwrap();
-                        // 2 is up, 3 is down
-                        if vcounter.edit_time.date() < date.date() {
-                            if vote_type == b"2" {
-                                vcounter.up_before += 1;
-                            } else {
-                                vcounter.down_before += 1;
-                            }
-                        } else if vcounter.edit_time.date() > date.date() {
-                            if vote_type == b"2" {
-                                vcounter.up_after += 1;
-                            } else {
-                                vcounter.down_after += 1;
-                            }
-                        }
-                        n_votes += 1;
-                    }
-                }
+    /// Counts the up- and down-votes in a Votes.xml stream that were cast on a tallied question,
+    /// for each of its edits on a day other than the day of the edit. The stream starts at byte
+    /// `offset` of the dump, where an earlier tabulation left off. Returns `(relevant votes, vote
+    /// rows read)` in total.
+    pub fn tabulate<R: BufRead>(&mut self, reader: R, offset: u64, rejects: &mut Rejects, checkpoints: &mut Checkpointer) -> Result<(u64, u64)> {
+        let mut rows = RowReader::resume_at(reader, offset);
+
+        let mut n_proc = 0;
+        while let Some(row) = rows.next_map(|element| {
+            n_proc += 1;
+            self.classify_vote(element.attributes())
+        }) {
+            self.n_proc += std::mem::take(&mut n_proc);
+            self.apply(row, rejects)?;
+            checkpoints.maybe_save(rows.position(), self)?;
+        }
+        self.n_proc += n_proc;
+
+        checkpoints.save(rows.position(), self)?;
+        Ok((self.n_votes, self.n_proc))
+    }
+
+    /// Like [`VoteTally::tabulate`], but reads `chunks` of the Votes.xml at `path` in parallel.
+    pub fn tabulate_chunks(
+        &mut self,
+        path: &Path,
+        chunks: &[Range<u64>],
+        args: &ChunkArgs,
+        pb: &ProgressBar,
+        rejects: &mut Rejects,
+        checkpoints: &mut Checkpointer,
+    ) -> Result<(u64, u64)> {
+        args.for_each_chunk(chunks, self, |tally, chunk| {
+            let mut rows = open_chunk(path, chunk, pb)?;
+            let mut n_proc = 0;
+            let votes = std::iter::from_fn(|| rows.next_map(|element| {
+                n_proc += 1;
+                tally.classify_vote(element.attributes())
+            })).collect::<Vec<_>>();
+            Ok((votes, n_proc))
+        }, |tally, chunk, (votes, n_proc)| {
+            tally.n_proc += n_proc;
+            for vote in votes {
+                tally.apply(vote, rejects)?;
             }
-            _ => (),
+            checkpoints.maybe_save(chunk.end, tally)
+        })?;
+        checkpoints.save_finished(self)?;
+        Ok((self.n_votes, self.n_proc))
+    }
+
+    fn apply(&mut self, row: std::result::Result<(u64, Vote), RowError>, rejects: &mut Rejects) -> Result<()> {
+        let Some((_, vote)) = rejects.check(row)? else {
+            return Ok(());
+        };
+        self.n_votes += 1;
+        for vcounter in self.votes.get_mut(&vote.post_id).unwrap() {
+            vcounter.count(&vote);
+        }
+        Ok(())
+    }
+
+    /// Returns the up- or down-vote on a tallied question, if the row is one.
+    fn classify_vote(&self, attrs: Attributes) -> Result<Option<Vote>> {
+        classify_vote(attrs, |post_id| self.votes.contains_key(&post_id))
+    }
+
+    /// Vote counts in post id and revision order.
+    pub fn counts(&self) -> impl Iterator<Item = &VoteCounts> {
+        self.votes.values().flatten().map(|vcounter| &vcounter.counts)
+    }
+}
+
+impl VCounter {
+    fn new(pair: &RevisionPair) -> Self {
+        VCounter {
+            edit_time: pair.after_date,
+            counts: VoteCounts {
+                post_id: pair.post_id,
+                revision: pair.revision,
+                ..VoteCounts::default()
+            },
         }
+    }
 
-        xml_buf.clear();
+    /// Counts `vote` before or after the edit, unless it was cast on the day of the edit.
+    fn count(&mut self, vote: &Vote) {
+        let counts = &mut self.counts;
+        match (vote.vote_day.cmp(&self.edit_time.date()), vote.up) {
+            (Ordering::Less, true) => counts.up_before += 1,
+            (Ordering::Less, false) => counts.down_before += 1,
+            (Ordering::Greater, true) => counts.up_after += 1,
+            (Ordering::Greater, false) => counts.down_after += 1,
+            (Ordering::Equal, _) => {}
+        }
     }
+}
+
+/// Returns the up- or down-vote on a question that `tracks`, if the row is one.
+fn classify_vote(mut attrs: Attributes, tracks: impl Fn(PostId) -> bool) -> Result<Option<Vote>> {
+    /// Votes.xml rows always start with `Id`, `PostId`, `VoteTypeId` and `CreationDate`, in
+    /// that order.
+    fn next_attr<'a>(attrs: &mut Attributes<'a>, name: &'static str) -> Result<Attribute<'a>> {
+        let attr = attrs.next().ok_or(Error::MissingAttribute(name))??;
+        if attr.key.as_ref() != name.as_bytes() {
+            return Err(Error::MissingAttribute(name));
+        }
+        Ok(attr)
+    }
+
+    // ignore row's Id
+    next_attr(&mut attrs, "Id")?;
+    let post_id_attr = next_attr(&mut attrs, "PostId")?;
+    let post_id: PostId = parse_attribute("PostId", &post_id_attr.value)?;
+    if !tracks(post_id) {
+        return Ok(None);
+    }
+    let vote_type_attr = next_attr(&mut attrs, "VoteTypeId")?;
+    let vote_type = vote_type_attr.value.as_ref();
+    if vote_type != b"2" && vote_type != b"3" {
+        return Ok(None);
+    }
+    let date_attr = next_attr(&mut attrs, "CreationDate")?;
+    let date = NaiveDateTime::parse_from_str(
+        &parse_attribute::<String>("CreationDate", &date_attr.value)?,
+        crate::DATE_FORMAT
+    ).map_err(|e| Error::bad_attribute("CreationDate", &date_attr.value, e))?;
+    Ok(Some(Vote {
+        post_id,
+        // 2 is up, 3 is down
+        up: vote_type == b"2",
+        vote_day: date.date(),
+    }))
+}
+
+fn layer3_load_l2_indices(args: &Layer3Args) -> Result<VoteTally> {
+    println!("Loading question index from Layer2 at {}", args.layer2.display());
+    let mut rejects = args.errors.rejects("layer3", &args.layer2)?;
+    let (reader, pb) = open_input(&args.layer2)?;
+    let mut pairs = Vec::new();
+    for pair in read_layer2(reader) {
+        if let Some(pair) = rejects.check(pair)? {
+            pairs.push(pair);
+        }
+    }
+    let dataset = VoteTally::new(pairs);
+    pb.finish();
+    rejects.finish()?;
+    println!("Loaded {} items from Layer2 results", dataset.len());
+    Ok(dataset)
+}
+
+fn layer3_tabulate_vote_counts(args: &Layer3Args, vote_map: &mut VoteTally, offset: u64, checkpoints: &mut Checkpointer) -> Result<()> {
+    let mut rejects = args.errors.rejects("layer3", &args.infile)?;
+    let (pb, (n_votes, n_proc)) = if is_compressed(&args.infile) {
+        println!("Tabulating relevant vote counts from {}", args.infile.display());
+        let (reader, pb) = open_input_from(&args.infile, offset)?;
+        let counts = vote_map.tabulate(reader, offset, &mut rejects, checkpoints)?;
+        (pb, counts)
+    } else {
+        let chunks = args.chunks.chunks(&args.infile, offset)?;
+        let pb = chunk_progress_bar(&args.infile, offset)?;
+        println!("Tabulating relevant vote counts from {} in {} chunks", args.infile.display(), chunks.len());
+        let counts = vote_map.tabulate_chunks(&args.infile, &chunks, &args.chunks, &pb, &mut rejects, checkpoints)?;
+        (pb, counts)
+    };
 
     pb.finish();
+    rejects.finish()?;
     println!("Tabulated {n_votes}/{n_proc} votes!");
+    Ok(())
 }
 
-fn layer3_write(args: &Layer3Args, vote_map: &BTreeMap<i64, VCounter>) {
+fn layer3_write(args: &Layer3Args, vote_map: &VoteTally) -> Result<u64> {
     let pb = crate::progress_bar(vote_map.len() as u64);
 
-    println!("Writing vote counts for {} questions.", vote_map.len());
+    println!("Writing vote counts for {} edits.", vote_map.len());
 
-    let mut writer = BufWriter::new(OpenOptions::new()
-        .write(true).create(true).truncate(true).open(&args.outfile)
-        .expect("Couldn't open OUTFILE for writing"));
+    let mut writer = TsvWriter::new(args.output.create(&args.output.output_path(&args.outfile))?, args.flush_interval);
 
-    for (post_id, vcounts) in vote_map {
+    for vcounts in vote_map.counts() {
         pb.inc(1);
+        writer.write_row(vcounts)?;
+    }
 
-        writeln!(writer, "{}\t{}\t{}\t{}\t{}",
-            post_id,
-            vcounts.up_before,
-            vcounts.down_before,
-            vcounts.up_after,
-            vcounts.down_after
-        ).unwrap();
-        if writer.buffer().len() >= args.flush_interval {
-            writer.flush().unwrap();
+    let written = writer.finish()?;
+    pb.finish();
+
+    println!("Finished writing.");
+
+    Ok(written)
+}
+
+/// Reads the edits of Layer2 in post id and revision order through an external sort, for
+/// [`layer3_filter_sorted`].
+fn layer3_sort_l2_edits(args: &Layer3Args) -> Result<impl Iterator<Item = Result<VCounter>>> {
+    println!("Sorting question index from Layer2 at {}", args.layer2.display());
+    let mut rejects = args.errors.rejects("layer3", &args.layer2)?;
+    let (reader, pb) = open_input(&args.layer2)?;
+    let mut sorter = args.memory.sorter("layer3-edits", |vcounter: &VCounter| (vcounter.counts.post_id, vcounter.counts.revision));
+    for pair in read_layer2(reader) {
+        if let Some(pair) = rejects.check(pair)? {
+            sorter.push(VCounter::new(&pair))?;
         }
     }
+    pb.finish();
+    rejects.finish()?;
+    println!("Sorted {} items from Layer2 results, spilling {} runs to disk", sorter.len(), sorter.runs());
+    sorter.finish()
+}
 
-    writer.flush().unwrap();
+/// Reads every up- and down-vote of Votes.xml in post id order through an external sort, for
+/// [`layer3_filter_sorted`]. Returns them along with the number of vote rows read.
+fn layer3_sort_votes(args: &Layer3Args) -> Result<(impl Iterator<Item = Result<Vote>>, u64)> {
+    let mut rejects = args.errors.rejects("layer3", &args.infile)?;
+    let mut sorter = args.memory.sorter("layer3-votes", |vote: &Vote| vote.post_id);
+    let mut n_proc = 0;
+    let pb = if is_compressed(&args.infile) {
+        println!("Sorting up- and down-votes from {}", args.infile.display());
+        let (reader, pb) = open_input(&args.infile)?;
+        let mut rows = RowReader::new(reader);
+        while let Some(row) = rows.next_map(|element| {
+            n_proc += 1;
+            classify_vote(element.attributes(), |_| true)
+        }) {
+            if let Some((_, vote)) = rejects.check(row)? {
+                sorter.push(vote)?;
+            }
+        }
+        pb
+    } else {
+        let chunks = args.chunks.chunks(&args.infile, 0)?;
+        let pb = chunk_progress_bar(&args.infile, 0)?;
+        println!("Sorting up- and down-votes from {} in {} chunks", args.infile.display(), chunks.len());
+        args.chunks.for_each_chunk(&chunks, &mut (), |_, chunk| {
+            let mut rows = open_chunk(&args.infile, chunk, &pb)?;
+            let mut n_proc = 0;
+            let votes = std::iter::from_fn(|| rows.next_map(|element| {
+                n_proc += 1;
+                classify_vote(element.attributes(), |_| true)
+            })).collect::<Vec<_>>();
+            Ok((votes, n_proc))
+        }, |_, _, (votes, chunk_proc)| {
+            n_proc += chunk_proc;
+            for vote in votes {
+                if let Some((_, vote)) = rejects.check(vote)? {
+                    sorter.push(vote)?;
+                }
+            }
+            Ok(())
+        })?;
+        pb
+    };
     pb.finish();
+    rejects.finish()?;
+    println!("Sorted {} votes, spilling {} runs to disk", sorter.len(), sorter.runs());
+    Ok((sorter.finish()?, n_proc))
+}
 
-    println!("Finished writing.");
+/// Counts the `votes` on the edits of one post, passing over the votes on the posts before it.
+/// Returns the number of votes counted.
+fn layer3_count_post_votes(edits: &mut [VCounter], votes: &mut Peekable<impl Iterator<Item = Result<Vote>>>) -> Result<u64> {
+    let post_id = edits[0].counts.post_id;
+    let mut n_votes = 0;
+    while let Some(vote) = votes.next_if(|vote| vote.as_ref().map_or(true, |vote| vote.post_id <= post_id)) {
+        let vote = vote?;
+        if vote.post_id == post_id {
+            n_votes += 1;
+            for vcounter in edits.iter_mut() {
+                vcounter.count(&vote);
+            }
+        }
+    }
+    Ok(n_votes)
 }
 
-pub fn layer3_filter(args: &Layer3Args) {
-    let mut vote_map = layer3_load_l2_indices(args);
+/// Like [`layer3_filter`], but joins the edits of Layer2 and the votes, both sorted by post id
+/// on disk, instead of tallying the votes in memory.
+fn layer3_filter_sorted(args: &Layer3Args) -> Result<u64> {
+    let edits = layer3_sort_l2_edits(args)?;
+    let (votes, n_proc) = layer3_sort_votes(args)?;
+    let mut votes = votes.peekable();
 
-    layer3_tabulate_vote_counts(args, &mut vote_map);
+    println!("Tabulating the sorted votes of every edit into {}", args.outfile.display());
 
-    layer3_write(args, &vote_map)
-}
\ No newline at end of file
+    let mut writer = TsvWriter::new(args.output.create(&args.output.output_path(&args.outfile))?, args.flush_interval);
+
+    let mut n_votes = 0;
+    let mut post = Vec::<VCounter>::new();
+    for vcounter in edits {
+        let vcounter = vcounter?;
+        if let Some(last) = post.last() {
+            // one tally per edit, as in [`VoteTally::new`]
+            if (last.counts.post_id, last.counts.revision) == (vcounter.counts.post_id, vcounter.counts.revision) {
+                continue;
+            }
+            if last.counts.post_id != vcounter.counts.post_id {
+                n_votes += layer3_count_post_votes(&mut post, &mut votes)?;
+                for vcounter in post.drain(..) {
+                    writer.write_row(&vcounter.counts)?;
+                }
+            }
+        }
+        post.push(vcounter);
+    }
+    if !post.is_empty() {
+        n_votes += layer3_count_post_votes(&mut post, &mut votes)?;
+        for vcounter in post {
+            writer.write_row(&vcounter.counts)?;
+        }
+    }
+
+    let written = writer.finish()?;
+    println!("Tabulated {n_votes}/{n_proc} votes!");
+    println!("Finished writing vote counts for {written} edits.");
+
+    Ok(written)
+}
+
+/// Returns the number of vote count rows written to OUTFILE.
+pub fn layer3_filter(args: &Layer3Args) -> Result<u64> {
+    detect_schema(&args.infile, &["Id", "PostId", "VoteTypeId", "CreationDate"], &[])?;
+    if args.memory.is_bounded() {
+        if args.checkpoints.resume {
+            return Err(Error::Usage("--resume cannot be combined with --memory-budget".to_string()));
+        }
+        return layer3_filter_sorted(args);
+    }
+    let mut checkpoints = args.checkpoints.checkpointer(&args.infile, &args.outfile)?;
+    let (offset, mut vote_map) = match checkpoints.resume()? {
+        Some(resumed) => resumed,
+        None => (0, layer3_load_l2_indices(args)?),
+    };
+
+    layer3_tabulate_vote_counts(args, &mut vote_map, offset, &mut checkpoints)?;
+
+    let written = layer3_write(args, &vote_map)?;
+    checkpoints.clear()?;
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{parse_args, TestDir, LAYER2_TSV, LAYER3_TSV, VOTES_XML};
+
+    #[test]
+    fn vote_counts_round_trip() {
+        let counts = VoteCounts { post_id: 1, revision: 3, up_before: 4, down_before: 1, up_after: 7, down_after: 0 };
+        assert_eq!(counts.to_string().parse::<VoteCounts>().unwrap(), counts);
+        assert_eq!(counts.score_delta(), 4);
+    }
+
+    #[test]
+    fn layer3_counts_the_votes_around_each_edit() {
+        let dir = TestDir::new("layer3");
+        let votes = dir.write("Votes.xml", VOTES_XML);
+        let layer2 = dir.write("layer2.tsv", LAYER2_TSV);
+        let out = dir.file("layer3.tsv");
+        assert_eq!(layer3_filter(&parse_args(&["--in-file", &votes, "--in-layer-2", &layer2, "--out-file", &out])).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), LAYER3_TSV);
+
+        // sorted and joined on disk, the counts come out the same
+        let args = ["--in-file", &votes, "--in-layer-2", &layer2, "--out-file", &out, "--memory-budget", "1"];
+        assert_eq!(layer3_filter(&parse_args(&args)).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), LAYER3_TSV);
+
+        // and so they do tabulated a row per chunk
+        let args = ["--in-file", &votes, "--in-layer-2", &layer2, "--out-file", &out, "--chunk-size", "1", "--jobs", "3"];
+        assert_eq!(layer3_filter(&parse_args(&args)).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), LAYER3_TSV);
+    }
+
+    #[test]
+    fn layer3_resumes_from_a_checkpoint() {
+        let dir = TestDir::new("layer3-resume");
+        let votes = dir.write("Votes.xml", VOTES_XML);
+        let layer2 = dir.write("layer2.tsv", LAYER2_TSV);
+        let out = dir.file("layer3.tsv");
+        let args: Layer3Args = parse_args(&["--in-file", &votes, "--in-layer-2", &layer2, "--out-file", &out, "--resume"]);
+
+        // a tabulation interrupted after the first two votes
+        let interrupted = VOTES_XML.find("  <row Id=\"3\"").unwrap();
+        let mut vote_map = layer3_load_l2_indices(&args).unwrap();
+        let mut checkpoints = args.checkpoints.checkpointer(&args.infile, &args.outfile).unwrap();
+        vote_map.tabulate(&VOTES_XML.as_bytes()[..interrupted], 0, &mut Rejects::abort("layer3"), &mut checkpoints).unwrap();
+
+        // resumed with the state of the checkpoint, Layer2 is not read again
+        std::fs::remove_file(&layer2).unwrap();
+        assert_eq!(layer3_filter(&args).unwrap(), 2);
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), LAYER3_TSV);
+        assert!(!Path::new(&format!("{out}.ckpt")).exists());
+    }
+}
@@ -1,209 +1,689 @@
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::BTreeMap;
 use std::ffi::{OsStr, OsString};
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, Write};
+use std::iter::Peekable;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use chrono::NaiveDateTime;
-use clap::Args;
-use indicatif::ProgressIterator;
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use crate::error::{Error, ErrorArgs, Rejects, Result, RowError};
+use crate::input::open_input;
+use crate::output::CompressArgs;
+use crate::layer_1::PostMetadata;
+use crate::layer_2::{read_layer2, Field, QuestionContext, RevisionPair};
+use crate::layer_3::{read_layer3, VoteCounts};
+use crate::PostId;
+use crate::spill::{MemoryArgs, SpillVec};
+use crate::split::{assign_splits, split_manifest_path, SplitArgs, SplitKey, SplitManifest};
+use crate::tsv::{parse_column, RowParseError, TsvReader, TsvWriter};
 
 #[derive(Args)]
 pub struct Layer4Args {
     #[clap(long="in-layer-2", required=true)]
-    layer2: PathBuf,
+    pub layer2: PathBuf,
     #[clap(long="in-layer-3", required=true)]
-    layer3: PathBuf,
+    pub layer3: PathBuf,
     #[clap(long="out-base", required=true)]
-    out_base: PathBuf,
-    #[clap(long="l2-count", default_value_t=2431869)]
-    l2_count: u64,
-    #[clap(long="split")]
-    split: String,
+    pub out_base: PathBuf,
+    #[command(flatten)]
+    pub split: SplitArgs,
     #[clap(long="flush-interval", default_value_t=1_000_000)]
-    flush_interval: usize,
+    pub flush_interval: usize,
+    #[command(flatten)]
+    pub errors: ErrorArgs,
+    #[command(flatten)]
+    pub output: CompressArgs,
+    #[command(flatten)]
+    pub votes: VoteArgs,
+    /// Append the title and body of the question to every answer edit (empty for questions)
+    #[clap(long="context-columns")]
+    pub context_columns: bool,
+    /// Append the Posts.xml attributes carried from Layer1, as a JSON object
+    #[clap(long="metadata-column")]
+    pub metadata_column: bool,
+    /// Fields whose edits to write a dataset of (comma-separated)
+    #[clap(long="fields", value_enum, value_delimiter=',', default_value="body")]
+    pub fields: Vec<Field>,
+    #[clap(long="task", value_enum, default_value_t=Task::Edit)]
+    pub task: Task,
+    /// Keep only edits published under one of these licenses (comma-separated), such as
+    /// "CC BY-SA 4.0"; every license is kept if none is given
+    #[clap(long="allow-license", value_delimiter=',')]
+    pub allow_licenses: Vec<String>,
+    #[command(flatten)]
+    pub memory: MemoryArgs,
 }
 
-struct XInfo {
-    created_date: NaiveDateTime,
-    edit_date: NaiveDateTime,
-    pre_edit_up_votes: u32,
-    post_edit_up_votes: u32,
-    pre_edit_down_votes: u32,
-    post_edit_down_votes: u32,
+/// What the examples of the splits ask for.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Task {
+    /// The revision after the edit, from the one before
+    #[default]
+    Edit,
+    /// The revision after the edit, from the one before and the editor's comment as instruction
+    Instruct,
+    /// The editor's comment, from the revisions before and after the edit
+    Summarize,
 }
 
-fn layer4_simple_filters(args: &Layer4Args) -> BTreeMap<i64, XInfo> {
-    let reader = BufReader::new(OpenOptions::new()
-        .read(true).open(&args.layer2)
-        .expect("Failed to open IN_LAYER_3 for reading"));
-    let pb = crate::progress_bar(args.l2_count);
+// How the Layer3 vote counts select and annotate examples.
+#[derive(Args, Clone, Default)]
+pub struct VoteArgs {
+    /// Keep only edits whose net score after the edit exceeds the net score before it by at least
+    /// this much
+    #[arg(long = "min-vote-delta")]
+    pub min_vote_delta: Option<i64>,
+    /// Keep only edits after which the question scored positively, and better than before
+    #[arg(long = "only-improved")]
+    pub only_improved: bool,
+    /// Append the Layer3 vote counts to every example
+    #[arg(long = "vote-columns")]
+    pub vote_columns: bool,
+}
+
+pub const SPLIT_HEADER: &str = "input\toutput";
+pub const INSTRUCT_HEADER: &str = "instruction\tinput\toutput";
+pub const SUMMARIZE_HEADER: &str = "input\tedited\toutput";
+pub const LICENSE_COLUMN_HEADER: &str = "license";
+pub const VOTE_COLUMNS_HEADER: &str = "up_before\tdown_before\tup_after\tdown_after";
+pub const CONTEXT_COLUMNS_HEADER: &str = "question_title\tquestion_body";
+pub const METADATA_COLUMN_HEADER: &str = "metadata";
+
+/// A training example, i.e. the body before and after the edit and the license the edit was
+/// published under, optionally with the votes on the post around the edit, the question of an
+/// edited answer and the carried attributes of the post; one line of a Layer4 split. The
+/// [`Task`] decides what goes in and out: for `instruct` the editor's comment is the
+/// instruction, for `summarize` the comment is the output and the body after the edit is
+/// `edited`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitExample {
+    pub instruction: Option<String>,
+    pub input: String,
+    pub edited: Option<String>,
+    pub output: String,
+    /// The ContentLicense of the revision after the edit, which as an adaptation of the one
+    /// before may be shared under the same or a later CC BY-SA version.
+    pub license: String,
+    pub votes: Option<VoteCounts>,
+    pub context: Option<QuestionContext>,
+    pub metadata: Option<PostMetadata>,
+}
+
+impl From<RevisionPair> for SplitExample {
+    fn from(pair: RevisionPair) -> Self {
+        SplitExample {
+            instruction: None,
+            input: pair.before_text,
+            edited: None,
+            output: pair.after_text,
+            license: pair.after_license,
+            votes: None,
+            context: None,
+            metadata: None,
+        }
+    }
+}
 
-    fn scan_for_code(s: &str) -> bool {
-        if s.contains("&#xD;&#xA;    ") {
-            return true;
+impl SplitExample {
+    /// The example of `task` for `pair`, if the pair has what the task needs: the instruct and
+    /// summarize tasks need a comment.
+    pub fn for_task(mut pair: RevisionPair, task: Task) -> Option<Self> {
+        if task != Task::Edit && pair.comment.is_empty() {
+            return None;
+        }
+        let comment = std::mem::take(&mut pair.comment);
+        let example = SplitExample::from(pair);
+        Some(match task {
+            Task::Edit => example,
+            Task::Instruct => SplitExample {
+                instruction: Some(comment),
+                ..example
+            },
+            Task::Summarize => SplitExample {
+                edited: Some(example.output),
+                output: comment,
+                ..example
+            },
+        })
+    }
+}
+
+impl Display for SplitExample {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(instruction) = &self.instruction {
+            write!(f, "{instruction}\t")?;
+        }
+        write!(f, "{}", self.input)?;
+        if let Some(edited) = &self.edited {
+            write!(f, "\t{edited}")?;
+        }
+        write!(f, "\t{}\t{}", self.output, self.license)?;
+        if let Some(votes) = &self.votes {
+            write!(f, "\t{}\t{}\t{}\t{}", votes.up_before, votes.down_before, votes.up_after, votes.down_after)?;
         }
-        let backtick_count = s.matches('`').count();
-        let escaped_backtick_count = s.matches("\\`").count();
-        if backtick_count > escaped_backtick_count {
-            return true;
+        if let Some(context) = &self.context {
+            write!(f, "\t{}\t{}", context.title, context.body)?;
         }
-        false
+        if let Some(metadata) = &self.metadata {
+            write!(f, "\t{metadata}")?;
+        }
+        Ok(())
     }
+}
 
-    fn estimate_token_count(s: &str) -> usize {
-        let ws_delimited : Vec<_> = s.split_whitespace().collect();
-        let mut n_tokens = 1;
+/// Where the columns of a split lie, as named by its header.
+struct SplitLayout {
+    columns: usize,
+    instruction: Option<usize>,
+    input: usize,
+    edited: Option<usize>,
+    output: usize,
+    license: usize,
+    votes: Option<[usize; 4]>,
+    context: Option<[usize; 2]>,
+    metadata: Option<usize>,
+}
 
-        for x in ws_delimited {
-            let mut prev = '\n';
-            for c in x.chars() {
-                if c.is_ascii_punctuation() {
-                    n_tokens += 1;
-                }
-                if c.is_alphabetic() && (prev != '\n' && !prev.is_alphabetic()) {
-                    n_tokens += 1;
-                }
-                if c.is_numeric() && (prev != '\n' && !prev.is_numeric()) {
-                    n_tokens += 1;
-                }
-                prev = c;
+impl SplitLayout {
+    fn of_header(header: &str) -> std::result::Result<Self, RowParseError> {
+        const ROW: &str = "layer4 header";
+        let names = header.split('\t').collect::<Vec<_>>();
+        let find = |name: &str| names.iter().position(|column| *column == name);
+        let require = |name: &str| find(name)
+            .ok_or_else(|| RowParseError::new(ROW, format!("no {name} column")));
+        // a group of columns is there whole or not at all
+        fn group<const N: usize>(names: &str, find: impl Fn(&str) -> Option<usize>) -> std::result::Result<Option<[usize; N]>, RowParseError> {
+            let found = names.split('\t').filter_map(&find).collect::<Vec<_>>();
+            match found.len() {
+                0 => Ok(None),
+                _ => found.try_into()
+                    .map(Some)
+                    .map_err(|_| RowParseError::new(ROW, format!("expected all or none of the columns {names:?}"))),
             }
         }
+        Ok(SplitLayout {
+            columns: names.len(),
+            instruction: find("instruction"),
+            input: require("input")?,
+            edited: find("edited"),
+            output: require("output")?,
+            license: require(LICENSE_COLUMN_HEADER)?,
+            votes: group(VOTE_COLUMNS_HEADER, find)?,
+            context: group(CONTEXT_COLUMNS_HEADER, find)?,
+            metadata: find(METADATA_COLUMN_HEADER),
+        })
+    }
+}
+
+impl SplitExample {
+    /// Parses a line of a split laid out as `layout`.
+    fn parse(line: &str, layout: &SplitLayout) -> std::result::Result<Self, RowParseError> {
+        const ROW: &str = "layer4";
+        let columns = line.split('\t').collect::<Vec<_>>();
+        if columns.len() != layout.columns {
+            return Err(RowParseError::new(ROW, format!("expected {} columns, found {}", layout.columns, columns.len())));
+        }
+        let column = |i: usize| columns[i].to_string();
+        let votes = match layout.votes {
+            Some([up_before, down_before, up_after, down_after]) => Some(VoteCounts {
+                post_id: 0,
+                revision: 0,
+                up_before: parse_column(ROW, "up before", columns[up_before])?,
+                down_before: parse_column(ROW, "down before", columns[down_before])?,
+                up_after: parse_column(ROW, "up after", columns[up_after])?,
+                down_after: parse_column(ROW, "down after", columns[down_after])?,
+            }),
+            None => None,
+        };
+        let metadata = match layout.metadata {
+            Some(i) => Some(parse_column(ROW, "metadata", columns[i])?),
+            None => None,
+        };
+        Ok(SplitExample {
+            instruction: layout.instruction.map(column),
+            input: column(layout.input),
+            edited: layout.edited.map(column),
+            output: column(layout.output),
+            license: column(layout.license),
+            votes,
+            context: layout.context.map(|[title, body]| QuestionContext {
+                post_id: 0,
+                title: column(title),
+                body: column(body),
+            }),
+            metadata,
+        })
+    }
+}
+
+/// A line of a split, parsed once the header has told its layout.
+struct SplitLine(String);
 
-        n_tokens
+impl FromStr for SplitLine {
+    type Err = RowParseError;
+
+    fn from_str(line: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(SplitLine(line.to_string()))
     }
+}
 
-    // let mut deny_length = 0;
-    // let mut deny_code = 0;
+/// Reads back a Layer4 split, laid out as its header says.
+pub fn read_split<R: BufRead>(reader: R) -> impl Iterator<Item = std::result::Result<SplitExample, RowError>> {
+    let mut lines = TsvReader::<R, SplitLine>::new(reader);
+    let mut layout = None;
+    let mut failed = false;
+    std::iter::from_fn(move || loop {
+        if failed {
+            return None;
+        }
+        let offset = lines.offset();
+        let line = match lines.next()? {
+            Ok(SplitLine(line)) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        match &layout {
+            None => match SplitLayout::of_header(&line) {
+                Ok(header) => layout = Some(header),
+                Err(e) => {
+                    // without the layout, no line can be read
+                    failed = true;
+                    return Some(Err(RowError::new(offset, e)));
+                }
+            },
+            Some(layout) => return Some(SplitExample::parse(&line, layout)
+                .map_err(|e| RowError::new(offset, e))),
+        }
+    })
+}
 
-    println!("Running deny filters in {} Layer2 inputs...", args.l2_count);
+fn scan_for_code(s: &str) -> bool {
+    if s.contains("&#xD;&#xA;    ") {
+        return true;
+    }
+    let backtick_count = s.matches('`').count();
+    let escaped_backtick_count = s.matches("\\`").count();
+    if backtick_count > escaped_backtick_count {
+        return true;
+    }
+    false
+}
+
+fn estimate_token_count(s: &str) -> usize {
+    let ws_delimited : Vec<_> = s.split_whitespace().collect();
+    let mut n_tokens = 1;
 
-    let dataset = reader.lines()
-        .progress_with(pb.clone())
-        .filter_map(|line| {
-            let line = line.unwrap();
-            if estimate_token_count(&line) > 200 {
-                // deny_length += 1;
-                return None
+    for x in ws_delimited {
+        let mut prev = '\n';
+        for c in x.chars() {
+            if c.is_ascii_punctuation() {
+                n_tokens += 1;
             }
-            if scan_for_code(&line) {
-                // deny_code += 1;
-                return None
+            if c.is_alphabetic() && (prev != '\n' && !prev.is_alphabetic()) {
+                n_tokens += 1;
             }
-            let items = line.split('\t').collect::<Vec<_>>();
-            assert_eq!(items.len(), 5);
-            let created_date = NaiveDateTime::parse_from_str(items[2], crate::DATE_FORMAT)
-                .map_err(|e| {
-                    println!("{items:?}");
-                    e
-                })
-                .unwrap();
-            let edit_date = NaiveDateTime::parse_from_str(items[4], crate::DATE_FORMAT)
-                .map_err(|e| {
-                    println!("{items:?}");
-                    e
-                })
-                .unwrap();
-            Some((
-                i64::from_str(items[0]).unwrap(),
-                XInfo {
-                    created_date,
-                    edit_date,
-                    pre_edit_up_votes: 0,
-                    post_edit_up_votes: 0,
-                    pre_edit_down_votes: 0,
-                    post_edit_down_votes: 0,
+            if c.is_numeric() && (prev != '\n' && !prev.is_numeric()) {
+                n_tokens += 1;
+            }
+            prev = c;
+        }
+    }
+
+    n_tokens
+}
+
+/// Whether a revision pair is short enough and free of code. The checks run over the revision
+/// columns of the Layer2 line (the question's author and tags aside), dates included.
+pub fn passes_deny_filters(pair: &RevisionPair) -> bool {
+    let line = format!("{}\t{}\t{}\t{}\t{}",
+        pair.post_id,
+        pair.before_text,
+        pair.before_date.format(crate::DATE_FORMAT),
+        pair.after_text,
+        pair.after_date.format(crate::DATE_FORMAT),
+    );
+    estimate_token_count(&line) <= 200 && !scan_for_code(&line)
+}
+
+/// Whether the edit was published under one of the `--allow-license` licenses, if any are given.
+pub fn passes_license_filter(allow_licenses: &[String], pair: &RevisionPair) -> bool {
+    allow_licenses.is_empty() || allow_licenses.contains(&pair.after_license)
+}
+
+/// Whether the votes around an edit pass `--min-vote-delta` and `--only-improved`.
+pub fn passes_vote_filters(args: &VoteArgs, votes: &VoteCounts) -> bool {
+    if args.min_vote_delta.is_some_and(|min| votes.score_delta() < min) {
+        return false;
+    }
+    !args.only_improved || (votes.score_after() > 0 && votes.score_delta() > 0)
+}
+
+/// The Layer3 vote counts of the edits, loaded by post id and revision, or under
+/// `--memory-budget` read alongside Layer2, as both are in post id and revision order.
+enum Layer4Votes {
+    Loaded(BTreeMap<(PostId, u32), VoteCounts>),
+    Joined(Box<VoteJoin>),
+}
+
+/// Layer3 read in step with the Layer2 pairs looked up in it.
+struct VoteJoin {
+    counts: Peekable<TsvReader<Box<dyn BufRead + Send>, VoteCounts>>,
+    rejects: Rejects,
+    /// The edit looked up last.
+    last_pair: (PostId, u32),
+    /// The edit of the Layer3 row read last.
+    last_counts: (PostId, u32),
+}
+
+impl Layer4Votes {
+    fn open(args: &Layer4Args) -> Result<Self> {
+        if !args.memory.is_bounded() {
+            return layer4_load_votes(args).map(Layer4Votes::Loaded);
+        }
+        let rejects = args.errors.rejects("layer4", &args.layer3)?;
+        let (reader, pb) = open_input(&args.layer3)?;
+        pb.finish_and_clear();
+        println!("Joining vote counts from {} in post id order", args.layer3.display());
+        Ok(Layer4Votes::Joined(Box::new(VoteJoin {
+            counts: read_layer3(reader).peekable(),
+            rejects,
+            last_pair: (PostId::MIN, 0),
+            last_counts: (PostId::MIN, 0),
+        })))
+    }
+
+    /// The vote counts of the edit that made `revision` of `post_id`, if Layer3 has them.
+    fn get(&mut self, post_id: PostId, revision: u32) -> Result<Option<VoteCounts>> {
+        match self {
+            Layer4Votes::Loaded(votes) => Ok(votes.get(&(post_id, revision)).cloned()),
+            Layer4Votes::Joined(join) => join.get((post_id, revision)),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        if let Layer4Votes::Joined(join) = self {
+            join.rejects.finish()?;
+        }
+        Ok(())
+    }
+}
+
+impl VoteJoin {
+    fn get(&mut self, edit: (PostId, u32)) -> Result<Option<VoteCounts>> {
+        if edit < self.last_pair {
+            return Err(out_of_order("layer2", edit));
+        }
+        self.last_pair = edit;
+        while let Some(row) = self.counts.next_if(|row| row.as_ref().map_or(true, |row| (row.post_id, row.revision) < edit)) {
+            if let Some(row) = self.rejects.check(row)? {
+                let row_edit = (row.post_id, row.revision);
+                if row_edit < self.last_counts {
+                    return Err(out_of_order("layer3", row_edit));
                 }
-            ))
+                self.last_counts = row_edit;
+            }
+        }
+        Ok(match self.counts.peek() {
+            Some(Ok(row)) if (row.post_id, row.revision) == edit => Some(row.clone()),
+            _ => None,
         })
-        .collect::<BTreeMap<i64, XInfo>>();
+    }
+}
+
+fn out_of_order(row: &'static str, (post_id, revision): (PostId, u32)) -> Error {
+    Error::Row(RowParseError::new(row, format!(
+        "post {post_id} revision {revision} is out of post id and revision order, which --memory-budget needs")))
+}
+
+/// Loads the Layer3 vote counts by post id and revision.
+fn layer4_load_votes(args: &Layer4Args) -> Result<BTreeMap<(PostId, u32), VoteCounts>> {
+    let mut rejects = args.errors.rejects("layer4", &args.layer3)?;
+    let (reader, pb) = open_input(&args.layer3)?;
+
+    println!("Loading vote counts from {}", args.layer3.display());
+
+    let mut votes = BTreeMap::new();
+    for counts in read_layer3(reader) {
+        if let Some(counts) = rejects.check(counts)? {
+            votes.insert((counts.post_id, counts.revision), counts);
+        }
+    }
 
     pb.finish();
-    println!("Deny filters yield {} examples", dataset.len());
+    rejects.finish()?;
+    println!("Loaded vote counts for {} edits", votes.len());
 
-    dataset
+    Ok(votes)
 }
 
-fn layer4_generate(args: &Layer4Args, posts: &BTreeSet<i64>) {
-    let split = args.split.split(':').map(|s| i64::from_str(s).unwrap()).collect::<Vec<_>>();
-    let train_count = split[0];
-    let eval_count = split[1];
-    let test_count = split[2];
-    let total = train_count + eval_count + test_count;
+/// The examples of every chosen field, along with the field.
+type FieldExamples = SpillVec<(Field, SplitExample)>;
 
-    let train_prop = (train_count as f32) / (total as f32);
-    let eval_prop = (eval_count as f32) / (total as f32);
-    let test_prop = (test_count as f32) / (total as f32);
+/// Reads Layer2 once, keeping the examples of the chosen fields that pass the deny, license and
+/// vote filters and have what the task needs, in post id order. The split keys are kept in
+/// memory, and the examples in a [`SpillVec`].
+fn layer4_simple_filters(args: &Layer4Args, votes: &mut Layer4Votes) -> Result<(Vec<SplitKey>, FieldExamples)> {
+    let mut rejects = args.errors.rejects("layer4", &args.layer2)?;
+    let (reader, pb) = open_input(&args.layer2)?;
 
-    let actual_total = posts.len() as f32;
-    // let actual_train_count = (actual_total * train_prop).round() as usize;
-    let actual_eval_count = (actual_total * eval_prop).round() as usize;
-    let actual_test_count = (actual_total * test_prop).round() as usize;
-    // assert_eq!(actual_train_count + actual_eval_count + actual_test_count, posts.len());
-    let actual_train_count = posts.len() - actual_eval_count - actual_test_count;
-    println!("actual_total={actual_total}, actual_train_count={actual_train_count}, actual_eval_count={actual_eval_count}, actual_test_count={actual_test_count}");
+    println!("Running deny filters over Layer2 inputs in {}...", args.layer2.display());
+
+    let mut keys = Vec::new();
+    let mut examples = args.memory.spill_vec("layer4-examples");
+    for pair in read_layer2(reader) {
+        if let Some(pair) = rejects.check(pair)?.filter(|pair| args.fields.contains(&pair.field)) {
+            // edits missing from Layer3 count as having had no votes
+            let counts = votes.get(pair.post_id, pair.revision)?.unwrap_or_default();
+            if passes_deny_filters(&pair) && passes_license_filter(&args.allow_licenses, &pair) && passes_vote_filters(&args.votes, &counts) {
+                let key = SplitKey::of(&pair);
+                let field = pair.field;
+                let context = args.context_columns.then(|| pair.context.clone().unwrap_or_default());
+                let metadata = args.metadata_column.then(|| pair.metadata.clone());
+                let Some(example) = SplitExample::for_task(pair, args.task) else {
+                    continue;
+                };
+                let example = SplitExample {
+                    votes: args.votes.vote_columns.then_some(counts),
+                    context,
+                    metadata,
+                    ..example
+                };
+                keys.push(key);
+                examples.push((field, example))?;
+            }
+        }
+    }
 
-    let fname_base = args.out_base.file_stem().unwrap();
+    pb.finish();
+    rejects.finish()?;
+    println!("Deny filters yield {} examples", examples.len());
+    if examples.is_spilled() {
+        println!("Spilled examples past the memory budget to disk");
+    }
+
+    Ok((keys, examples))
+}
+
+/// Paths of the train, eval and test splits of the edits of `field` written for OUT_BASE. Body
+/// edits go to `OUT_BASE-train.tsv` and so on, title and tag edits to `OUT_BASE-title-train.tsv`
+/// and `OUT_BASE-tags-train.tsv`.
+pub fn layer4_output_paths(out_base: &Path, field: Field, output: &CompressArgs) -> [PathBuf; 3] {
+    let mut fname_base = out_base.file_stem().unwrap().to_os_string();
+    if field != Field::Body {
+        fname_base.push(format!("-{field}"));
+    }
+    let fname_base = fname_base.as_os_str();
     fn append(a: &OsStr, b: &OsStr) -> OsString {
         let mut x = a.to_os_string();
         x.push(b);
         x
     }
-    let train_path = args.out_base.with_file_name(append(fname_base, OsStr::new("-train.tsv")));
-    let eval_path = args.out_base.with_file_name(append(fname_base, OsStr::new("-eval.tsv")));
-    let test_path = args.out_base.with_file_name(append(fname_base, OsStr::new("-test.tsv")));
-
-    let mut reader = BufReader::new(OpenOptions::new()
-        .read(true).open(&args.layer2)
-        .expect("Failed to open IN_LAYER_2 for reading"));
+    [
+        out_base.with_file_name(append(fname_base, OsStr::new("-train.tsv"))),
+        out_base.with_file_name(append(fname_base, OsStr::new("-eval.tsv"))),
+        out_base.with_file_name(append(fname_base, OsStr::new("-test.tsv"))),
+    ].map(|path| output.output_path(&path))
+}
 
-    layer4_write(&train_path, actual_train_count, &mut reader, posts, args.flush_interval);
-    layer4_write(&eval_path, actual_eval_count, &mut reader, posts, args.flush_interval);
-    layer4_write(&test_path, actual_test_count, &mut reader, posts, args.flush_interval);
+/// Writes the header of a split.
+pub fn write_split_header<W: Write>(writer: &mut TsvWriter<W>, task: Task, vote_columns: bool, context_columns: bool, metadata_column: bool) -> std::io::Result<()> {
+    let mut header = format!("{}\t{LICENSE_COLUMN_HEADER}", match task {
+        Task::Edit => SPLIT_HEADER,
+        Task::Instruct => INSTRUCT_HEADER,
+        Task::Summarize => SUMMARIZE_HEADER,
+    });
+    if vote_columns {
+        header = format!("{header}\t{VOTE_COLUMNS_HEADER}");
+    }
+    if context_columns {
+        header = format!("{header}\t{CONTEXT_COLUMNS_HEADER}");
+    }
+    if metadata_column {
+        header = format!("{header}\t{METADATA_COLUMN_HEADER}");
+    }
+    writer.write_header(&header)
 }
 
-fn layer4_write(file: &Path, count: usize, reader: &mut BufReader<File>, posts: &BTreeSet<i64>, flush_interval: usize) {
-    let mut written = 0;
-    let mut buf = String::new();
+/// Writes `examples` as a split, preceded by its header.
+pub fn write_split<W: Write>(writer: &mut TsvWriter<W>, task: Task, vote_columns: bool, context_columns: bool, metadata_column: bool, examples: impl IntoIterator<Item = SplitExample>) -> std::io::Result<()> {
+    write_split_header(writer, task, vote_columns, context_columns, metadata_column)?;
+    for example in examples {
+        writer.write_row(&example)?;
+    }
+    Ok(())
+}
 
-    let mut writer = BufWriter::new(OpenOptions::new()
-        .write(true).truncate(true).create(true).open(file)
-        .expect(format!("Failed to open OUT_TRAIN_PATH ({}) for writing", file.display()).as_str()));
+/// The examples written by Layer4.
+pub struct Layer4Counts {
+    /// Examples in the train, eval and test splits of every field.
+    pub splits: Vec<(Field, [usize; 3])>,
+    /// Examples published under every license, over all fields and splits.
+    pub licenses: BTreeMap<String, usize>,
+}
 
-    let pb = crate::progress_bar(count as u64);
+/// Splits `dataset` and writes the dataset of every chosen field. The examples of all fields are
+/// split together, so that every dataset puts a post in the same split.
+fn layer4_generate(args: &Layer4Args, keys: Vec<SplitKey>, examples: FieldExamples) -> Result<Layer4Counts> {
+    let splits = assign_splits(&args.split, &keys)?;
 
-    println!("Writing {count} examples to {}", file.display());
+    let manifest = SplitManifest::new(&args.split, &keys, &splits);
+    println!("Split {} examples {:?}: train={}, eval={}, test={}", keys.len(), args.split.mode, manifest.train.len(), manifest.eval.len(), manifest.test.len());
+    let manifest_path = split_manifest_path(&args.out_base);
+    manifest.write(&manifest_path)?;
+    println!("Wrote the post ids of every split to {}", manifest_path.display());
 
-    writeln!(writer, "input\toutput").unwrap();
+    let mut datasets = BTreeMap::new();
+    for &field in &args.fields {
+        let paths = layer4_output_paths(&args.out_base, field, &args.output);
+        let mut writers = Vec::with_capacity(paths.len());
+        for path in &paths {
+            let mut writer = TsvWriter::new(args.output.create(path)?, args.flush_interval);
+            write_split_header(&mut writer, args.task, args.votes.vote_columns, args.context_columns, args.metadata_column)?;
+            writers.push(writer);
+        }
+        println!("Writing {field} edits to {}", paths.map(|path| path.display().to_string()).join(", "));
+        datasets.insert(field, (writers, [0; 3]));
+    }
 
-    while written < count {
-        reader.read_line(&mut buf).unwrap();
+    let pb = crate::progress_bar(examples.len());
 
-        let line_split = buf.split('\t').collect::<Vec<_>>();
-        let post_id = i64::from_str(line_split[0]).unwrap();
+    let mut licenses = BTreeMap::<String, usize>::new();
+    for (example, split) in examples.into_rows()?.zip(splits) {
+        let (field, example) = example?;
+        let (writers, counts) = datasets.get_mut(&field).unwrap();
+        writers[split as usize].write_row(&example)?;
+        counts[split as usize] += 1;
+        *licenses.entry(example.license.clone()).or_default() += 1;
+        pb.inc(1);
+    }
 
-        if posts.contains(&post_id) {
-            pb.inc(1);
-            written += 1;
-            writeln!(writer, "{}\t{}", line_split[1], line_split[3]).unwrap();
-            if writer.buffer().len() > flush_interval {
-                writer.flush().unwrap();
-            }
+    let mut splits = Vec::with_capacity(datasets.len());
+    for (field, (writers, counts)) in datasets {
+        for writer in writers {
+            writer.finish()?;
         }
-
-        buf.clear();
+        splits.push((field, counts));
     }
-
-    writer.flush().unwrap();
     pb.finish();
+    for (license, count) in &licenses {
+        let license = if license.is_empty() { "(unrecorded)" } else { license };
+        println!("{count} examples under {license}");
+    }
     println!("Finished!");
+
+    Ok(Layer4Counts { splits, licenses })
 }
 
-pub fn layer4_filter(args: &Layer4Args) {
-    let simple_filtered = layer4_simple_filters(args);
+/// Returns the number of examples written to the train, eval and test splits of every field, and
+/// under every license.
+pub fn layer4_filter(args: &Layer4Args) -> Result<Layer4Counts> {
+    let mut votes = Layer4Votes::open(args)?;
+    let (keys, examples) = layer4_simple_filters(args, &mut votes)?;
+    votes.finish()?;
 
-    let posts = simple_filtered.keys().map(|x| *x).collect::<BTreeSet<i64>>();
+    layer4_generate(args, keys, examples)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use super::*;
+    use crate::testing::{parse_args, TestDir, LAYER2_TSV, LAYER3_TSV};
+
+    #[test]
+    fn split_examples_round_trip_through_their_header() {
+        let dir = TestDir::new("layer4-splits");
+        let pair = LAYER2_TSV.lines().nth(1).unwrap().parse::<RevisionPair>().unwrap();
+        for task in [Task::Edit, Task::Instruct, Task::Summarize] {
+            for (vote_columns, context_columns, metadata_column) in [(false, false, false), (true, false, true), (false, true, false), (true, true, true)] {
+                let mut example = SplitExample::for_task(pair.clone(), task).unwrap();
+                // the splits leave out the post ids
+                if vote_columns {
+                    example.votes = Some(VoteCounts { up_before: 1, down_after: 2, ..VoteCounts::default() });
+                }
+                if context_columns {
+                    example.context = Some(QuestionContext { post_id: 0, ..pair.context.clone().unwrap() });
+                }
+                if metadata_column {
+                    example.metadata = Some(PostMetadata { score: Some(1), ..PostMetadata::default() });
+                }
+                let mut writer = TsvWriter::new(File::create(dir.file("split.tsv")).unwrap(), 0);
+                write_split(&mut writer, task, vote_columns, context_columns, metadata_column, [example.clone()]).unwrap();
+                writer.finish().unwrap();
+                let examples = read_split(dir.open("split.tsv")).collect::<std::result::Result<Vec<_>, _>>().unwrap();
+                assert_eq!(examples, [example]);
+            }
+        }
+    }
+
+    #[test]
+    fn split_without_a_license_column_is_rejected() {
+        let mut examples = read_split("input\toutput\na\tb\n".as_bytes());
+        assert!(examples.next().unwrap().is_err());
+        assert!(examples.next().is_none());
+    }
 
-    layer4_generate(&args, &posts);
-}
\ No newline at end of file
+    #[test]
+    fn layer4_splits_the_pairs_with_their_votes() {
+        let dir = TestDir::new("layer4");
+        let layer2 = dir.write("layer2.tsv", LAYER2_TSV);
+        let layer3 = dir.write("layer3.tsv", LAYER3_TSV);
+        let out_base = dir.file("dataset");
+        let args = [
+            "--in-layer-2", &layer2, "--in-layer-3", &layer3, "--out-base", &out_base, "--split", "1:0:0",
+            "--vote-columns", "--context-columns",
+        ];
+        let counts = layer4_filter(&parse_args(&args)).unwrap();
+        assert_eq!(counts.splits, [(Field::Body, [2, 0, 0])]);
+        assert_eq!(counts.licenses.into_iter().collect::<Vec<_>>(), [("CC BY-SA 2.5".to_string(), 1), ("CC BY-SA 3.0".to_string(), 1)]);
+
+        let [train, ..] = layer4_output_paths(Path::new(&out_base), Field::Body, &parse_args(&[]));
+        let examples = read_split(std::io::BufReader::new(File::open(&train).unwrap()))
+            .collect::<std::result::Result<Vec<_>, _>>().unwrap();
+        let mut examples = examples.iter()
+            .map(|example| (example.input.as_str(), example.output.as_str(), example.votes.as_ref().unwrap().up_after))
+            .collect::<Vec<_>>();
+        examples.sort();
+        assert_eq!(examples, [("Answer body", "Answer body edited", 0), ("Original body one", "Edited body one", 2)]);
+    }
+}
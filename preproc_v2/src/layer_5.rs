@@ -0,0 +1,476 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::str::FromStr;
+use chrono::NaiveDateTime;
+use clap::Args;
+use quick_xml::events::BytesStart;
+use crate::error::{Error, ErrorArgs, Rejects, Result};
+use crate::input::open_input;
+use crate::output::CompressArgs;
+use crate::PostId;
+use crate::schema::detect_schema;
+use crate::xml::{parse_attribute, parse_date_attribute, parse_guid_attribute, RowReader};
+use crate::tsv::{parse_column, split_columns, RowParseError, TsvReader, TsvWriter};
+
+#[derive(Args)]
+pub struct Layer5Args {
+    /// SuggestedEdits.xml
+    #[arg(long = "in-file", required=true)]
+    pub infile: PathBuf,
+    /// SuggestedEditVotes.xml
+    #[arg(long = "in-votes", required=true)]
+    pub votes: PathBuf,
+    /// PostHistory.xml, for the body each suggestion was made on and the revision it became
+    #[arg(long = "in-post-history", required=true)]
+    pub post_history: PathBuf,
+    #[arg(long = "out-file", required=true)]
+    pub outfile: PathBuf,
+    #[arg(long = "flush-interval", default_value_t=1_000_000)]
+    pub flush_interval: usize,
+    #[command(flatten)]
+    pub errors: ErrorArgs,
+    #[command(flatten)]
+    pub output: CompressArgs,
+}
+
+/// A reviewed suggested edit of the body of a post, labeled with the outcome of the review; one
+/// line of the Layer5 output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SuggestedEditRow {
+    pub id: i64,
+    pub post_id: PostId,
+    pub approved: bool,
+    /// Reviewer votes to approve and to reject the suggestion.
+    pub approve_votes: u32,
+    pub reject_votes: u32,
+    pub creation_date: NaiveDateTime,
+    /// When the suggestion was approved or rejected.
+    pub decision_date: NaiveDateTime,
+    /// The Id of the PostHistory.xml revision an approved suggestion became, if the dump has it.
+    pub revision_id: Option<i64>,
+    /// The suggester's comment (escaped, like the texts).
+    pub comment: String,
+    /// The body of the post when the suggestion was made.
+    pub before_text: String,
+    /// The suggested body.
+    pub after_text: String,
+}
+
+impl Display for SuggestedEditRow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t",
+            self.id,
+            self.post_id,
+            self.approved,
+            self.approve_votes,
+            self.reject_votes,
+            self.creation_date.format(crate::DATE_FORMAT),
+            self.decision_date.format(crate::DATE_FORMAT),
+        )?;
+        if let Some(revision_id) = self.revision_id {
+            write!(f, "{revision_id}")?;
+        }
+        write!(f, "\t{}\t{}\t{}",
+            self.comment.replace('\t', " "),
+            self.before_text.replace('\t', " "),
+            self.after_text.replace('\t', " "),
+        )
+    }
+}
+
+impl FromStr for SuggestedEditRow {
+    type Err = RowParseError;
+
+    fn from_str(line: &str) -> std::result::Result<Self, Self::Err> {
+        const ROW: &str = "layer5";
+        let [id, post_id, approved, approve_votes, reject_votes, creation_date, decision_date, revision_id, comment, before_text, after_text] = split_columns(ROW, line)?;
+        let parse_date = |name, value| NaiveDateTime::parse_from_str(value, crate::DATE_FORMAT)
+            .map_err(|e| RowParseError::new(ROW, format!("bad {name} {value:?}: {e}")));
+        Ok(SuggestedEditRow {
+            id: parse_column(ROW, "id", id)?,
+            post_id: parse_column(ROW, "post id", post_id)?,
+            approved: parse_column(ROW, "approved", approved)?,
+            approve_votes: parse_column(ROW, "approve votes", approve_votes)?,
+            reject_votes: parse_column(ROW, "reject votes", reject_votes)?,
+            creation_date: parse_date("creation date", creation_date)?,
+            decision_date: parse_date("decision date", decision_date)?,
+            revision_id: match revision_id {
+                "" => None,
+                revision_id => Some(parse_column(ROW, "revision id", revision_id)?),
+            },
+            comment: comment.to_string(),
+            before_text: before_text.to_string(),
+            after_text: after_text.to_string(),
+        })
+    }
+}
+
+/// Reads back a Layer5 output file.
+pub fn read_layer5<R: BufRead>(reader: R) -> TsvReader<R, SuggestedEditRow> {
+    TsvReader::new(reader)
+}
+
+/// A reviewed suggestion as read from SuggestedEdits.xml.
+struct Suggestion {
+    post_id: PostId,
+    creation_date: NaiveDateTime,
+    approved: bool,
+    decision_date: NaiveDateTime,
+    guid: Option<u128>,
+    comment: String,
+    text: String,
+}
+
+/// The body revision of a post in PostHistory.xml.
+struct BodyRevision {
+    id: i64,
+    post_id: PostId,
+    date: NaiveDateTime,
+    guid: Option<u128>,
+    text: String,
+}
+
+/// A vote of a reviewer on a suggestion.
+struct ReviewVote {
+    suggestion_id: i64,
+    approve: bool,
+}
+
+/// The reviewed suggestions of SuggestedEdits.xml, gathering their votes and the revisions they
+/// were made on and became.
+pub struct SuggestionReviews {
+    suggestions: BTreeMap<i64, Suggestion>,
+    votes: HashMap<i64, (u32, u32)>,
+    /// The suggestions made on every post.
+    posts: HashMap<PostId, Vec<i64>>,
+    /// The approved suggestions, by the RevisionGUID of the edit they became.
+    guids: HashMap<u128, i64>,
+    /// The latest body revision before each suggestion, as its date and text.
+    before: HashMap<i64, (NaiveDateTime, String)>,
+    revision_ids: HashMap<i64, i64>,
+}
+
+impl SuggestionReviews {
+    /// Reads the suggestions that were approved or rejected from a SuggestedEdits.xml stream;
+    /// pending ones, and suggestions that leave the body alone, are skipped.
+    pub fn load<R: BufRead>(reader: R, rejects: &mut Rejects) -> Result<Self> {
+        let mut reviews = SuggestionReviews {
+            suggestions: BTreeMap::new(),
+            votes: HashMap::new(),
+            posts: HashMap::new(),
+            guids: HashMap::new(),
+            before: HashMap::new(),
+            revision_ids: HashMap::new(),
+        };
+        let mut rows = RowReader::new(reader);
+        while let Some(row) = rows.next_map(layer5_parse_suggestion) {
+            let Some((_, (id, suggestion))) = rejects.check(row)? else {
+                continue;
+            };
+            reviews.posts.entry(suggestion.post_id).or_default().push(id);
+            if let (true, Some(guid)) = (suggestion.approved, suggestion.guid) {
+                reviews.guids.insert(guid, id);
+            }
+            reviews.suggestions.insert(id, suggestion);
+        }
+        Ok(reviews)
+    }
+
+    pub fn len(&self) -> usize {
+        self.suggestions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.suggestions.is_empty()
+    }
+
+    /// Counts the approve and reject votes on the loaded suggestions in a SuggestedEditVotes.xml
+    /// stream. Returns the number of votes counted.
+    pub fn tally<R: BufRead>(&mut self, reader: R, rejects: &mut Rejects) -> Result<u64> {
+        let mut n_votes = 0;
+        let mut rows = RowReader::new(reader);
+        while let Some(row) = rows.next_map(|element| self.classify_vote(element)) {
+            let Some((_, vote)) = rejects.check(row)? else {
+                continue;
+            };
+            let (approve, reject) = self.votes.entry(vote.suggestion_id).or_default();
+            if vote.approve {
+                *approve += 1;
+            } else {
+                *reject += 1;
+            }
+            n_votes += 1;
+        }
+        Ok(n_votes)
+    }
+
+    fn classify_vote(&self, element: &BytesStart) -> Result<Option<ReviewVote>> {
+        let mut suggestion_id = None;
+        let mut approve = None;
+        for attr in element.attributes() {
+            let attr = attr?;
+            match attr.key.as_ref() {
+                b"SuggestedEditId" => {
+                    let id = parse_attribute("SuggestedEditId", &attr.value)?;
+                    if !self.suggestions.contains_key(&id) {
+                        return Ok(None);
+                    }
+                    suggestion_id = Some(id);
+                }
+                b"VoteTypeId" => {
+                    approve = match attr.value.as_ref() {
+                        b"2" => Some(true),
+                        b"3" => Some(false),
+                        _ => return Ok(None),
+                    };
+                }
+                _ => (),
+            }
+            if suggestion_id.is_some() && approve.is_some() {
+                break;
+            }
+        }
+        Ok(Some(ReviewVote {
+            suggestion_id: suggestion_id.ok_or(Error::MissingAttribute("SuggestedEditId"))?,
+            approve: approve.ok_or(Error::MissingAttribute("VoteTypeId"))?,
+        }))
+    }
+
+    /// Finds, in a PostHistory.xml stream, the body of every suggestion's post just before the
+    /// suggestion was made, and the revision each approved suggestion became. Returns the number
+    /// of body revisions read.
+    pub fn link<R: BufRead>(&mut self, reader: R, rejects: &mut Rejects) -> Result<u64> {
+        let mut n_revisions = 0;
+        let mut rows = RowReader::new(reader);
+        while let Some(row) = rows.next_map(|element| layer5_parse_body_revision(element, &self.posts)) {
+            let Some((_, revision)) = rejects.check(row)? else {
+                continue;
+            };
+            n_revisions += 1;
+            if let Some(&id) = revision.guid.and_then(|guid| self.guids.get(&guid)) {
+                self.revision_ids.insert(id, revision.id);
+            }
+            for id in &self.posts[&revision.post_id] {
+                if revision.date >= self.suggestions[id].creation_date {
+                    continue;
+                }
+                match self.before.get(id) {
+                    Some((date, _)) if *date > revision.date => {}
+                    _ => {
+                        self.before.insert(*id, (revision.date, revision.text.clone()));
+                    }
+                }
+            }
+        }
+        Ok(n_revisions)
+    }
+
+    /// The suggestions whose earlier body was found, in id order.
+    pub fn rows(&self) -> impl Iterator<Item = SuggestedEditRow> + '_ {
+        self.suggestions.iter().filter_map(|(id, suggestion)| {
+            let (_, before_text) = self.before.get(id)?;
+            let (approve_votes, reject_votes) = self.votes.get(id).copied().unwrap_or_default();
+            Some(SuggestedEditRow {
+                id: *id,
+                post_id: suggestion.post_id,
+                approved: suggestion.approved,
+                approve_votes,
+                reject_votes,
+                creation_date: suggestion.creation_date,
+                decision_date: suggestion.decision_date,
+                revision_id: self.revision_ids.get(id).copied(),
+                comment: suggestion.comment.clone(),
+                before_text: before_text.clone(),
+                after_text: suggestion.text.clone(),
+            })
+        })
+    }
+}
+
+/// Returns the id and the suggestion, if the row is a reviewed suggested edit of a body.
+fn layer5_parse_suggestion(element: &BytesStart) -> Result<Option<(i64, Suggestion)>> {
+    let mut id = None;
+    let mut post_id = None;
+    let mut creation_date = None;
+    let mut approval_date = None;
+    let mut rejection_date = None;
+    let mut guid = None;
+    let mut comment = String::new();
+    let mut text = None;
+    for attr in element.attributes() {
+        let attr = attr?;
+        let value = attr.value.as_ref();
+        match attr.key.as_ref() {
+            b"Id" => id = Some(parse_attribute("Id", value)?),
+            b"PostId" => post_id = Some(parse_attribute("PostId", value)?),
+            b"CreationDate" => creation_date = Some(parse_date_attribute("CreationDate", value)?),
+            b"ApprovalDate" => approval_date = Some(parse_date_attribute("ApprovalDate", value)?),
+            b"RejectionDate" => rejection_date = Some(parse_date_attribute("RejectionDate", value)?),
+            b"RevisionGUID" => guid = Some(parse_guid_attribute("RevisionGUID", value)?),
+            b"Comment" => comment = parse_attribute("Comment", value)?,
+            b"Text" => text = Some(parse_attribute("Text", value)?),
+            _ => (),
+        }
+    }
+    let (approved, decision_date) = match (approval_date, rejection_date) {
+        (Some(date), _) => (true, date),
+        (None, Some(date)) => (false, date),
+        (None, None) => return Ok(None),
+    };
+    let Some(text) = text else {
+        return Ok(None);
+    };
+    Ok(Some((id.ok_or(Error::MissingAttribute("Id"))?, Suggestion {
+        post_id: post_id.ok_or(Error::MissingAttribute("PostId"))?,
+        creation_date: creation_date.ok_or(Error::MissingAttribute("CreationDate"))?,
+        approved,
+        decision_date,
+        guid,
+        comment,
+        text,
+    })))
+}
+
+/// Returns the body revision, if the row is one of a post in `posts`.
+fn layer5_parse_body_revision<T>(element: &BytesStart, posts: &HashMap<PostId, T>) -> Result<Option<BodyRevision>> {
+    let mut id = None;
+    let mut post_id = None;
+    let mut date = None;
+    let mut guid = None;
+    let mut text = None;
+    for attr in element.attributes() {
+        let attr = attr?;
+        let value = attr.value.as_ref();
+        match attr.key.as_ref() {
+            b"Id" => id = Some(parse_attribute("Id", value)?),
+            // only original, edited and rolled back bodies
+            b"PostHistoryTypeId" if !matches!(value, b"2" | b"5" | b"8") => return Ok(None),
+            b"PostId" => {
+                let value = parse_attribute("PostId", value)?;
+                if !posts.contains_key(&value) {
+                    return Ok(None);
+                }
+                post_id = Some(value);
+            }
+            b"RevisionGUID" => guid = Some(parse_guid_attribute("RevisionGUID", value)?),
+            b"CreationDate" => date = Some(parse_date_attribute("CreationDate", value)?),
+            b"Text" => text = Some(parse_attribute("Text", value)?),
+            _ => (),
+        }
+    }
+    let (Some(post_id), Some(text)) = (post_id, text) else {
+        return Ok(None);
+    };
+    Ok(Some(BodyRevision {
+        id: id.ok_or(Error::MissingAttribute("Id"))?,
+        post_id,
+        date: date.ok_or(Error::MissingAttribute("CreationDate"))?,
+        guid,
+        text,
+    }))
+}
+
+/// Returns the number of suggested edits written to OUTFILE.
+pub fn layer5_filter(args: &Layer5Args) -> Result<u64> {
+    detect_schema(&args.infile, &["Id", "PostId", "CreationDate"], &["ApprovalDate", "RejectionDate", "RevisionGUID", "Comment", "Text"])?;
+    detect_schema(&args.votes, &["SuggestedEditId", "VoteTypeId"], &[])?;
+    detect_schema(&args.post_history, &["Id", "PostId", "PostHistoryTypeId", "CreationDate"], &["RevisionGUID"])?;
+
+    println!("Loading reviewed suggested edits from {}", args.infile.display());
+    let mut rejects = args.errors.rejects("layer5", &args.infile)?;
+    let (reader, pb) = open_input(&args.infile)?;
+    let mut reviews = SuggestionReviews::load(reader, &mut rejects)?;
+    pb.finish();
+    rejects.finish()?;
+    println!("Loaded {} reviewed suggestions", reviews.len());
+
+    println!("Counting reviewer votes from {}", args.votes.display());
+    let mut rejects = args.errors.rejects("layer5", &args.votes)?;
+    let (reader, pb) = open_input(&args.votes)?;
+    let n_votes = reviews.tally(reader, &mut rejects)?;
+    pb.finish();
+    rejects.finish()?;
+    println!("Counted {n_votes} votes");
+
+    println!("Linking suggestions to revisions in {}", args.post_history.display());
+    let mut rejects = args.errors.rejects("layer5", &args.post_history)?;
+    let (reader, pb) = open_input(&args.post_history)?;
+    let n_revisions = reviews.link(reader, &mut rejects)?;
+    pb.finish();
+    rejects.finish()?;
+    println!("Read {n_revisions} body revisions of suggested posts; linked {} approved suggestions", reviews.revision_ids.len());
+
+    let mut writer = TsvWriter::new(args.output.create(&args.output.output_path(&args.outfile))?, args.flush_interval);
+    for row in reviews.rows() {
+        writer.write_row(&row)?;
+    }
+    let written = writer.finish()?;
+
+    println!("Finished writing {written} suggested edits to {}.", args.outfile.display());
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{parse_args, TestDir, POST_HISTORY_XML};
+
+    const SUGGESTED_EDITS_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<suggestededits>
+  <row Id="100" PostId="3" CreationDate="2011-01-15T00:00:00.000" ApprovalDate="2011-01-16T00:00:00.000" Comment="spam" Text="Body three spam" RevisionGUID="00000000-0000-0000-0000-000000000006" OwnerUserId="12" />
+  <row Id="101" PostId="1" CreationDate="2010-01-15T00:00:00.000" RejectionDate="2010-01-16T00:00:00.000" Comment="reword" Text="Body one rewritten" OwnerUserId="12" />
+  <row Id="102" PostId="1" CreationDate="2010-01-20T00:00:00.000" Text="Pending" OwnerUserId="12" />
+</suggestededits>
+"#;
+
+    const SUGGESTED_EDIT_VOTES_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<suggestededitvotes>
+  <row Id="1" SuggestedEditId="100" UserId="9" VoteTypeId="2" CreationDate="2011-01-15T00:00:00.000" />
+  <row Id="2" SuggestedEditId="100" UserId="10" VoteTypeId="2" CreationDate="2011-01-15T00:00:00.000" />
+  <row Id="3" SuggestedEditId="100" UserId="11" VoteTypeId="3" CreationDate="2011-01-16T00:00:00.000" />
+  <row Id="4" SuggestedEditId="101" UserId="9" VoteTypeId="3" CreationDate="2010-01-16T00:00:00.000" />
+  <row Id="5" SuggestedEditId="101" UserId="10" VoteTypeId="4" CreationDate="2010-01-16T00:00:00.000" />
+</suggestededitvotes>
+"#;
+
+    #[test]
+    fn suggested_edit_rows_round_trip() {
+        let date = |date| NaiveDateTime::parse_from_str(date, crate::DATE_FORMAT).unwrap();
+        let approved = SuggestedEditRow {
+            id: 100,
+            post_id: 3,
+            approved: true,
+            approve_votes: 2,
+            reject_votes: 1,
+            creation_date: date("2011-01-15T00:00:00.000"),
+            decision_date: date("2011-01-16T00:00:00.500"),
+            revision_id: Some(8),
+            comment: "spam".to_string(),
+            before_text: "Body three".to_string(),
+            after_text: "Body three spam".to_string(),
+        };
+        let rejected = SuggestedEditRow { approved: false, revision_id: None, comment: String::new(), ..approved.clone() };
+        for row in [approved, rejected] {
+            assert_eq!(row.to_string().parse::<SuggestedEditRow>().unwrap(), row);
+        }
+    }
+
+    #[test]
+    fn layer5_labels_reviewed_suggestions() {
+        let dir = TestDir::new("layer5");
+        let suggested_edits = dir.write("SuggestedEdits.xml", SUGGESTED_EDITS_XML);
+        let votes = dir.write("SuggestedEditVotes.xml", SUGGESTED_EDIT_VOTES_XML);
+        let post_history = dir.write("PostHistory.xml", POST_HISTORY_XML);
+        let out = dir.file("layer5.tsv");
+        let args = ["--in-file", &suggested_edits, "--in-votes", &votes, "--in-post-history", &post_history, "--out-file", &out];
+        assert_eq!(layer5_filter(&parse_args(&args)).unwrap(), 2);
+        let rows = read_layer5(dir.open("layer5.tsv")).collect::<std::result::Result<Vec<_>, _>>().unwrap();
+        let labels = rows.iter()
+            .map(|row| (row.id, row.approved, row.approve_votes, row.reject_votes, row.revision_id, row.before_text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(labels, [(100, true, 2, 1, Some(8), "Body three"), (101, false, 0, 1, None, "Original body one")]);
+    }
+}
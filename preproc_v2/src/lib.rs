@@ -0,0 +1,85 @@
+//! Stack Exchange dump preprocessing.
+//!
+//! Each layer narrows the output of the previous one:
+//!
+//! 1. [`layer_1`] selects questions and/or answers from `Posts.xml` into
+//!    [`layer_1::PostRow`]s.
+//! 2. [`layer_2`] pairs revisions of the body (and optionally the title and tags) of each post
+//!    in `PostHistory.xml`, with the original title and body of the question each answer belongs
+//!    to, and produces [`layer_2::RevisionPair`]s. Edits that a rollback later reverted are
+//!    dropped or labeled. Every pair records how long after the post (and its question) the edit
+//!    was made, and edits outside an edit delay window can be dropped.
+//! 3. [`layer_3`] tabulates `Votes.xml` around each edit into [`layer_3::VoteCounts`].
+//! 4. [`layer_4`] filters the revision pairs and splits them into [`layer_4::SplitExample`]s, one
+//!    dataset per edited field.
+//! 5. [`layer_5`] labels the suggested edits of `SuggestedEdits.xml` with their review, the
+//!    votes of `SuggestedEditVotes.xml` and the revisions of `PostHistory.xml` they were made
+//!    on and became, into [`layer_5::SuggestedEditRow`]s. It stands apart from the other layers.
+//!
+//! Layers exchange tab-separated files (see [`tsv`]); every row type implements `FromStr` and
+//! `Display` for its TSV line. Malformed rows are handled according to [`error::ErrorPolicy`].
+//! Before reading a dump file, a layer detects which attributes it has (see [`schema`]), so that
+//! dumps from before an attribute existed are read too.
+//!
+//! [`index`] builds a PostId index of `PostHistory.xml` once, through which [`layer_2`] reads
+//! only the rows of the posts it pairs.
+//!
+//! Under `--memory-budget`, Layers 2 to 4 hold about that many bytes of rows in memory and sort
+//! and join the rest on disk (see [`spill`]): Layer2 joins the posts of Layer1 and their
+//! revisions sorted by post id, Layer3 the edits and votes, and Layer4 joins Layer2 and Layer3 in
+//! order.
+
+pub mod checkpoint;
+pub mod chunks;
+pub mod error;
+pub mod index;
+pub mod input;
+pub mod layer_1;
+pub mod layer_2;
+pub mod layer_3;
+pub mod layer_4;
+pub mod layer_5;
+pub mod output;
+pub mod pipeline;
+pub mod schema;
+pub mod spill;
+pub mod split;
+pub mod tsv;
+pub mod xml;
+#[cfg(test)]
+mod testing;
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use indicatif::{ProgressBar, ProgressBarIter, ProgressState, ProgressStyle};
+
+pub type PostId = i64;
+pub const DATE_FORMAT : &str = "%Y-%m-%dT%H:%M:%S%.3f";
+
+pub fn progress_bar(count: u64) -> ProgressBar {
+    let pb = ProgressBar::new(count);
+    pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta})")
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
+        .progress_chars("#>-"));
+    pb
+}
+
+/// Progress bar over an input of `len` bytes.
+pub fn progress_bar_bytes(len: u64) -> ProgressBar {
+    let pb = ProgressBar::new(len);
+    pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} {binary_bytes_per_sec} ({eta})")
+        .unwrap()
+        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
+        .progress_chars("#>-"));
+    pb
+}
+
+/// Opens `path` for buffered reading, along with a progress bar sized to the file and advanced by
+/// the bytes read from it.
+pub fn open_with_progress(path: &Path) -> std::io::Result<(BufReader<ProgressBarIter<File>>, ProgressBar)> {
+    let file = File::open(path)?;
+    let pb = progress_bar_bytes(file.metadata()?.len());
+    Ok((BufReader::new(pb.wrap_read(file)), pb))
+}
@@ -1,26 +1,6 @@
-#![feature(slice_pattern)]
-
-extern crate core;
-
-mod layer_1;
-mod layer_2;
-mod layer_3;
-mod layer_4;
-
+use std::process::ExitCode;
 use clap::{Parser, Subcommand};
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
-
-pub type PostId = i64;
-pub const DATE_FORMAT : &'static str = "%Y-%m-%dT%H:%M:%S%.3f";
-
-fn progress_bar(count: u64) -> ProgressBar {
-    let pb = ProgressBar::new(count);
-    pb.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} ({eta})")
-        .unwrap()
-        .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
-        .progress_chars("#>-"));
-    pb
-}
+use preproc_v2::{index, layer_1, layer_2, layer_3, layer_4, layer_5, pipeline};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -40,23 +20,32 @@ enum Commands {
     Layer3(layer_3::Layer3Args),
     #[clap(name="layer4")]
     Layer4(layer_4::Layer4Args),
+    #[clap(name="layer5")]
+    Layer5(layer_5::Layer5Args),
+    #[clap(name="index")]
+    Index(index::IndexArgs),
+    #[clap(name="pipeline")]
+    Pipeline(pipeline::PipelineArgs),
 }
 
-fn main() {
+fn main() -> ExitCode {
     let cli = Cli::parse();
 
-    match &cli.command {
-        Commands::Layer1(args) => {
-            layer_1::layer1_filter(args);
-        }
-        Commands::Layer2(args) => {
-            layer_2::layer2_filter(args);
-        }
-        Commands::Layer3(args) => {
-            layer_3::layer3_filter(args);
-        }
-        Commands::Layer4(args) => {
-            layer_4::layer4_filter(args);
+    let result = match &cli.command {
+        Commands::Layer1(args) => layer_1::layer1_filter(args).map(drop),
+        Commands::Layer2(args) => layer_2::layer2_filter(args).map(drop),
+        Commands::Layer3(args) => layer_3::layer3_filter(args).map(drop),
+        Commands::Layer4(args) => layer_4::layer4_filter(args).map(drop),
+        Commands::Layer5(args) => layer_5::layer5_filter(args).map(drop),
+        Commands::Index(args) => index::index_run(args).map(drop),
+        Commands::Pipeline(args) => pipeline::pipeline_run(args),
+    };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
         }
     }
 }
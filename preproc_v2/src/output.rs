@@ -0,0 +1,258 @@
+//! Optionally compressed layer outputs.
+//!
+//! With `--compress`, a layer writes its output through a gzip or zstd encoder and appends the
+//! matching extension to the output path, so that the next layer (see [`crate::input`]) reads
+//! it back transparently. Both encoders compress on several threads: zstd natively, gzip by
+//! compressing blocks of the output in parallel as separate gzip members.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use clap::{Args, ValueEnum};
+use flate2::write::GzEncoder;
+use rayon::prelude::*;
+use crate::tsv::Finish;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputCompression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl OutputCompression {
+    fn extension(self) -> Option<&'static str> {
+        match self {
+            OutputCompression::None => None,
+            OutputCompression::Gzip => Some("gz"),
+            OutputCompression::Zstd => Some("zst"),
+        }
+    }
+}
+
+#[derive(Args, Clone)]
+pub struct CompressArgs {
+    /// Compress the output, appending `.gz` or `.zst` to its path
+    #[arg(long = "compress", value_enum, default_value_t = OutputCompression::None)]
+    pub compress: OutputCompression,
+    /// Compression threads, 0 for one per core
+    #[arg(long = "compress-threads", default_value_t = 0)]
+    pub compress_threads: usize,
+}
+
+impl CompressArgs {
+    pub fn none() -> Self {
+        CompressArgs {
+            compress: OutputCompression::None,
+            compress_threads: 0,
+        }
+    }
+
+    /// `path` with the extension of the chosen compression, unless it already has it.
+    pub fn output_path(&self, path: &Path) -> PathBuf {
+        match self.compress.extension() {
+            Some(extension) if path.extension() != Some(extension.as_ref()) => {
+                let mut path = OsString::from(path);
+                path.push(".");
+                path.push(extension);
+                PathBuf::from(path)
+            }
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Creates (or truncates) `path`, which should come from [`CompressArgs::output_path`].
+    pub fn create(&self, path: &Path) -> io::Result<OutputFile> {
+        let file = File::create(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        let threads = match self.compress_threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
+        };
+        Ok(match self.compress {
+            OutputCompression::None => OutputFile::Plain(file),
+            OutputCompression::Gzip => OutputFile::Gzip(ParallelGzEncoder::new(file, threads)?),
+            OutputCompression::Zstd => {
+                let mut encoder = zstd::Encoder::new(file, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+                encoder.multithread(threads as u32)?;
+                OutputFile::Zstd(encoder)
+            }
+        })
+    }
+}
+
+pub enum OutputFile {
+    Plain(File),
+    Gzip(ParallelGzEncoder<File>),
+    Zstd(zstd::Encoder<'static, File>),
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputFile::Plain(file) => file.write(buf),
+            OutputFile::Gzip(encoder) => encoder.write(buf),
+            OutputFile::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(file) => file.flush(),
+            OutputFile::Gzip(encoder) => encoder.flush(),
+            OutputFile::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl Finish for OutputFile {
+    fn finish(self) -> io::Result<()> {
+        match self {
+            OutputFile::Plain(file) => file.finish(),
+            OutputFile::Gzip(encoder) => encoder.finish(),
+            OutputFile::Zstd(encoder) => encoder.finish()?.finish(),
+        }
+    }
+}
+
+/// Size of the blocks compressed as one gzip member each.
+const GZIP_BLOCK_SIZE: usize = 4 << 20;
+
+/// Gzip encoder that compresses `threads` blocks at a time in parallel. The output is a sequence
+/// of gzip members, which every gzip reader decompresses as one stream.
+pub struct ParallelGzEncoder<W: Write> {
+    writer: W,
+    pool: rayon::ThreadPool,
+    blocks: Vec<Vec<u8>>,
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    pub fn new(writer: W, threads: usize) -> io::Result<Self> {
+        Ok(ParallelGzEncoder {
+            writer,
+            pool: rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .map_err(io::Error::other)?,
+            blocks: vec![Vec::with_capacity(GZIP_BLOCK_SIZE)],
+        })
+    }
+
+    /// Compresses and writes every buffered block.
+    fn compress_blocks(&mut self) -> io::Result<()> {
+        let blocks = std::mem::take(&mut self.blocks);
+        let members = self.pool.install(|| blocks.par_iter()
+            .filter(|block| !block.is_empty())
+            .map(|block| {
+                let mut encoder = GzEncoder::new(Vec::with_capacity(block.len() / 2), flate2::Compression::default());
+                encoder.write_all(block)?;
+                encoder.finish()
+            })
+            .collect::<io::Result<Vec<_>>>())?;
+        for member in members {
+            self.writer.write_all(&member)?;
+        }
+        self.blocks = blocks;
+        self.blocks.truncate(1);
+        self.blocks[0].clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut block = self.blocks.last_mut().unwrap();
+        if block.len() == GZIP_BLOCK_SIZE {
+            if self.blocks.len() == self.pool.current_num_threads() {
+                self.compress_blocks()?;
+            } else {
+                self.blocks.push(Vec::with_capacity(GZIP_BLOCK_SIZE));
+            }
+            block = self.blocks.last_mut().unwrap();
+        }
+        let n = buf.len().min(GZIP_BLOCK_SIZE - block.len());
+        block.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    /// Only flushes what has been compressed already; partial blocks are kept so that flushing
+    /// often does not degrade the compression.
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Finish> Finish for ParallelGzEncoder<W> {
+    fn finish(mut self) -> io::Result<()> {
+        self.compress_blocks()?;
+        self.writer.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use super::*;
+    use crate::input::open_input;
+    use crate::layer_2::layer2_filter;
+    use crate::layer_3::layer3_filter;
+    use crate::testing::{parse_args, TestDir, LAYER1_TSV, LAYER3_TSV, POST_HISTORY_XML, VOTES_XML};
+
+    fn compress_args(compress: OutputCompression, compress_threads: usize) -> CompressArgs {
+        CompressArgs { compress, compress_threads }
+    }
+
+    #[test]
+    fn output_paths_get_the_extension_once() {
+        let gzip = compress_args(OutputCompression::Gzip, 0);
+        assert_eq!(gzip.output_path(Path::new("out.tsv")), Path::new("out.tsv.gz"));
+        assert_eq!(gzip.output_path(Path::new("out.tsv.gz")), Path::new("out.tsv.gz"));
+        assert_eq!(compress_args(OutputCompression::Zstd, 0).output_path(Path::new("out.tsv")), Path::new("out.tsv.zst"));
+        assert_eq!(CompressArgs::none().output_path(Path::new("out.tsv")), Path::new("out.tsv"));
+    }
+
+    #[test]
+    fn compressed_outputs_read_back() {
+        let dir = TestDir::new("output");
+        // enough for a few gzip members compressed in parallel, and a partial one
+        let contents = (0..1_200_000).map(|i| format!("{i}\t{}\n", i % 7)).collect::<String>();
+        assert!(contents.len() > 2 * GZIP_BLOCK_SIZE);
+        for compress in [OutputCompression::None, OutputCompression::Gzip, OutputCompression::Zstd] {
+            let args = compress_args(compress, 2);
+            let path = args.output_path(&dir.path().join("out.tsv"));
+            let mut file = args.create(&path).unwrap();
+            file.write_all(contents.as_bytes()).unwrap();
+            file.finish().unwrap();
+
+            let (mut reader, _) = open_input(&path).unwrap();
+            let mut read = String::new();
+            reader.read_to_string(&mut read).unwrap();
+            assert!(read == contents, "{}", path.display());
+        }
+
+        // the gzip output is several members, not one
+        let mut first_member = Vec::new();
+        flate2::read::GzDecoder::new(File::open(dir.path().join("out.tsv.gz")).unwrap()).read_to_end(&mut first_member).unwrap();
+        assert_eq!(first_member.len(), GZIP_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn layers_read_the_compressed_output_of_the_previous_layer() {
+        let dir = TestDir::new("output-layers");
+        let post_history = dir.write("PostHistory.xml", POST_HISTORY_XML);
+        let votes = dir.write("Votes.xml", VOTES_XML);
+        let layer1 = dir.write("layer1.tsv", LAYER1_TSV);
+        let (layer2, layer3) = (dir.file("layer2.tsv"), dir.file("layer3.tsv"));
+        for compress in ["gzip", "zstd"] {
+            let args = ["--in-file", &post_history, "--in-layer-1", &layer1, "--out-file", &layer2, "--compress", compress];
+            assert_eq!(layer2_filter(&parse_args(&args)).unwrap(), 2);
+            let extension = if compress == "gzip" { "gz" } else { "zst" };
+            let layer2 = format!("{layer2}.{extension}");
+            let args = ["--in-file", &votes, "--in-layer-2", &layer2, "--out-file", &layer3];
+            assert_eq!(layer3_filter(&parse_args(&args)).unwrap(), 2);
+            assert_eq!(std::fs::read_to_string(&layer3).unwrap(), LAYER3_TSV);
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use crate::checkpoint::CheckpointArgs;
+use crate::chunks::ChunkArgs;
+use crate::output::CompressArgs;
+use crate::error::{ErrorArgs, Result};
+use crate::index::{default_index_path, PostHistoryIndex};
+use crate::layer_1::{self, Layer1Args, PostAttribute, SelectArgs};
+use crate::layer_2::{self, EditDelay, Editors, Field, Layer2Args, Revisions, Rollbacks};
+use crate::layer_3::{self, Layer3Args};
+use crate::layer_4::{self, Layer4Args, Task, VoteArgs};
+use crate::layer_5::{self, Layer5Args};
+use crate::spill::MemoryArgs;
+use crate::split::{self, SplitArgs};
+
+pub const POSTS_FILE: &str = "Posts.xml";
+pub const POST_HISTORY_FILE: &str = "PostHistory.xml";
+pub const VOTES_FILE: &str = "Votes.xml";
+pub const SUGGESTED_EDITS_FILE: &str = "SuggestedEdits.xml";
+pub const SUGGESTED_EDIT_VOTES_FILE: &str = "SuggestedEditVotes.xml";
+
+pub const LAYER1_FILE: &str = "layer1.tsv";
+pub const LAYER2_FILE: &str = "layer2.tsv";
+pub const LAYER3_FILE: &str = "layer3.tsv";
+pub const LAYER5_FILE: &str = "suggested-edits.tsv";
+pub const DATASET_BASE: &str = "dataset";
+pub const MANIFEST_FILE: &str = "manifest.json";
+pub const REJECTS_FILE: &str = "rejects.tsv";
+
+#[derive(Args)]
+pub struct PipelineArgs {
+    /// Directory containing Posts.xml, PostHistory.xml and Votes.xml, extracted or compressed;
+    /// layer5 also runs if it contains SuggestedEdits.xml and SuggestedEditVotes.xml, and layer2
+    /// reads PostHistory.xml through its index if it contains PostHistory.xml.idx, which must have
+    /// been built from that PostHistory.xml
+    #[arg(long = "dump-dir", required=true)]
+    pub dump_dir: PathBuf,
+    /// Directory receiving every intermediate, the final splits and the run manifest
+    #[arg(long = "out-dir", required=true)]
+    pub out_dir: PathBuf,
+    #[command(flatten)]
+    pub split: SplitArgs,
+    #[arg(long = "flush-interval", default_value_t=1_000_000)]
+    pub flush_interval: usize,
+    #[command(flatten)]
+    pub select: SelectArgs,
+    /// Posts.xml attributes to carry through to the final splits (comma-separated)
+    #[arg(long = "carry", value_enum, value_delimiter = ',')]
+    pub carry: Vec<PostAttribute>,
+    /// Whose edits to pair with the original post
+    #[arg(long = "editor", value_enum, default_value_t = Editors::Other)]
+    pub editors: Editors,
+    /// Which revisions of a post to pair
+    #[arg(long = "revisions", value_enum, default_value_t = Revisions::Single)]
+    pub revisions: Revisions,
+    /// Fields whose edits to pair and write a dataset of (comma-separated)
+    #[arg(long = "fields", value_enum, value_delimiter = ',', default_value = "body")]
+    pub fields: Vec<Field>,
+    /// What to do with edits that were later rolled back
+    #[arg(long = "rollbacks", value_enum, default_value_t = Rollbacks::Drop)]
+    pub rollbacks: Rollbacks,
+    /// Keep only edits made at least this long after the post, e.g. `90s`, `30m`, `12h`, `7d`,
+    /// `4w` or `2y`
+    #[arg(long = "min-edit-delay")]
+    pub min_edit_delay: Option<EditDelay>,
+    /// Keep only edits made at most this long after the post
+    #[arg(long = "max-edit-delay")]
+    pub max_edit_delay: Option<EditDelay>,
+    // rejected rows of every layer go to OUT_DIR/rejects.tsv unless --rejects-file is given
+    #[command(flatten)]
+    pub errors: ErrorArgs,
+    #[command(flatten)]
+    pub output: CompressArgs,
+    #[command(flatten)]
+    pub chunks: ChunkArgs,
+    #[command(flatten)]
+    pub memory: MemoryArgs,
+    #[command(flatten)]
+    pub votes: VoteArgs,
+    /// Append the title and body of the question to every answer edit (empty for questions)
+    #[arg(long = "context-columns")]
+    pub context_columns: bool,
+    /// Append the Posts.xml attributes carried from Layer1, as a JSON object
+    #[arg(long = "metadata-column")]
+    pub metadata_column: bool,
+    #[arg(long = "task", value_enum, default_value_t = Task::Edit)]
+    pub task: Task,
+    /// Keep only edits published under one of these licenses (comma-separated), such as
+    /// "CC BY-SA 4.0"; every license is kept if none is given
+    #[arg(long = "allow-license", value_delimiter = ',')]
+    pub allow_licenses: Vec<String>,
+    /// Bytes of input scanned between checkpoints of layer2 and layer3, 0 to disable
+    #[arg(long = "checkpoint-interval", default_value_t = 4 << 30, conflicts_with = "memory_budget")]
+    pub checkpoint_interval: u64,
+    /// Skip the stages an interrupted run finished and resume the next from its checkpoint
+    #[arg(long = "resume", conflicts_with = "memory_budget")]
+    pub resume: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OutputRecord {
+    path: PathBuf,
+    rows: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StageRecord {
+    stage: String,
+    inputs: Vec<PathBuf>,
+    outputs: Vec<OutputRecord>,
+    elapsed_secs: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RunManifest {
+    dump_dir: PathBuf,
+    out_dir: PathBuf,
+    started_at: String,
+    finished_at: Option<String>,
+    stages: Vec<StageRecord>,
+    /// Examples in the final splits published under every license.
+    #[serde(default)]
+    licenses: BTreeMap<String, u64>,
+}
+
+impl RunManifest {
+    /// Rewritten after every stage so that an interrupted run still leaves a record of what
+    /// finished.
+    fn write(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(OpenOptions::new()
+            .write(true).create(true).truncate(true).open(path)?);
+        serde_json::to_writer_pretty(&mut writer, self).map_err(std::io::Error::from)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn run_stage<F>(
+    manifest: &mut RunManifest,
+    manifest_path: &Path,
+    stage: &'static str,
+    inputs: Vec<PathBuf>,
+    run: F,
+) -> Result<()> where F: FnOnce() -> Result<Vec<OutputRecord>> {
+    if manifest.stages.iter().any(|record| record.stage == stage) {
+        println!("=== Skipping {stage}, finished by an earlier run ===");
+        return Ok(());
+    }
+    println!("=== Running {stage} ===");
+    let start = Instant::now();
+    let outputs = run()?;
+    let elapsed_secs = start.elapsed().as_secs_f64();
+    println!("=== Finished {stage} in {elapsed_secs:.1}s ===");
+    manifest.stages.push(StageRecord {
+        stage: stage.to_string(),
+        inputs,
+        outputs,
+        elapsed_secs,
+    });
+    manifest.write(manifest_path)
+}
+
+/// Finds dump file `name` in `dump_dir`, either extracted or compressed: `Posts.xml`,
+/// `Posts.xml.zst`, `Posts.xml.gz`, `Posts.xml.bz2`, `Posts.7z` or `<site>-Posts.7z`.
+pub fn find_dump_file(dump_dir: &Path, name: &str) -> Result<PathBuf> {
+    let stem = name.trim_end_matches(".xml");
+    let mut candidates = vec![
+        dump_dir.join(name),
+        dump_dir.join(format!("{name}.zst")),
+        dump_dir.join(format!("{name}.gz")),
+        dump_dir.join(format!("{name}.bz2")),
+        dump_dir.join(format!("{stem}.7z")),
+    ];
+    let suffix = format!("-{stem}.7z");
+    for entry in std::fs::read_dir(dump_dir)? {
+        let path = entry?.path();
+        if path.file_name().and_then(OsStr::to_str).is_some_and(|file| file.ends_with(&suffix)) {
+            candidates.push(path);
+        }
+    }
+    candidates.into_iter()
+        .find(|path| path.is_file())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound,
+            format!("Missing dump file {name} in {}", dump_dir.display())).into())
+}
+
+pub fn pipeline_run(args: &PipelineArgs) -> Result<()> {
+    std::fs::create_dir_all(&args.out_dir)?;
+
+    let posts = find_dump_file(&args.dump_dir, POSTS_FILE)?;
+    let post_history = find_dump_file(&args.dump_dir, POST_HISTORY_FILE)?;
+    let votes = find_dump_file(&args.dump_dir, VOTES_FILE)?;
+    // an index built beforehand by the `index` subcommand spares layer2 scanning the whole dump;
+    // one built from another dump is rejected before any layer runs
+    let post_history_index = Some(default_index_path(&post_history)).filter(|index| index.is_file());
+    if let Some(index) = &post_history_index {
+        PostHistoryIndex::open(index, &post_history)?;
+    }
+
+    let layer1_path = args.output.output_path(&args.out_dir.join(LAYER1_FILE));
+    let layer2_path = args.output.output_path(&args.out_dir.join(LAYER2_FILE));
+    let layer3_path = args.output.output_path(&args.out_dir.join(LAYER3_FILE));
+    let out_base = args.out_dir.join(DATASET_BASE);
+    let manifest_path = args.out_dir.join(MANIFEST_FILE);
+
+    let errors = ErrorArgs {
+        on_error: args.errors.on_error,
+        rejects_file: Some(args.errors.rejects_file.clone()
+            .unwrap_or_else(|| args.out_dir.join(REJECTS_FILE))),
+    };
+    let checkpoints = CheckpointArgs {
+        checkpoint: None,
+        checkpoint_interval: args.checkpoint_interval,
+        resume: args.resume,
+    };
+
+    let resumed = if args.resume && manifest_path.is_file() {
+        let reader = File::open(&manifest_path)?;
+        let manifest: RunManifest = serde_json::from_reader(reader)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("{}: {e}", manifest_path.display())))?;
+        Some(manifest)
+    } else {
+        None
+    };
+
+    if let (Some(rejects_file), None) = (&errors.rejects_file, &resumed) {
+        // layers append to the rejects file, so start this run with an empty one
+        File::create(rejects_file)?;
+    }
+
+    let mut manifest = resumed.unwrap_or_else(|| RunManifest {
+        dump_dir: args.dump_dir.clone(),
+        out_dir: args.out_dir.clone(),
+        started_at: chrono::Local::now().to_rfc3339(),
+        finished_at: None,
+        stages: Vec::new(),
+        licenses: BTreeMap::new(),
+    });
+
+    run_stage(&mut manifest, &manifest_path, "layer1", vec![posts.clone()], || {
+        let rows = layer_1::layer1_filter(&Layer1Args {
+            infile: posts.clone(),
+            outfile: layer1_path.clone(),
+            flush_interval: args.flush_interval,
+            select: args.select.clone(),
+            carry: args.carry.clone(),
+            errors: errors.clone(),
+            output: args.output.clone(),
+            chunks: args.chunks.clone(),
+        })?;
+        Ok(vec![OutputRecord { path: layer1_path.clone(), rows }])
+    })?;
+
+    run_stage(&mut manifest, &manifest_path, "layer2", vec![post_history.clone(), layer1_path.clone()], || {
+        let rows = layer_2::layer2_filter(&Layer2Args {
+            infile: post_history.clone(),
+            layer1: layer1_path.clone(),
+            outfile: layer2_path.clone(),
+            flush_interval: args.flush_interval,
+            editors: args.editors,
+            revisions: args.revisions,
+            fields: args.fields.clone(),
+            rollbacks: args.rollbacks,
+            min_edit_delay: args.min_edit_delay,
+            max_edit_delay: args.max_edit_delay,
+            index: post_history_index.clone(),
+            errors: errors.clone(),
+            output: args.output.clone(),
+            checkpoints: checkpoints.clone(),
+            chunks: args.chunks.clone(),
+            memory: args.memory.clone(),
+        })?;
+        Ok(vec![OutputRecord { path: layer2_path.clone(), rows }])
+    })?;
+
+    run_stage(&mut manifest, &manifest_path, "layer3", vec![votes.clone(), layer2_path.clone()], || {
+        let rows = layer_3::layer3_filter(&Layer3Args {
+            infile: votes.clone(),
+            layer2: layer2_path.clone(),
+            outfile: layer3_path.clone(),
+            flush_interval: args.flush_interval,
+            errors: errors.clone(),
+            output: args.output.clone(),
+            checkpoints: checkpoints.clone(),
+            chunks: args.chunks.clone(),
+            memory: args.memory.clone(),
+        })?;
+        Ok(vec![OutputRecord { path: layer3_path.clone(), rows }])
+    })?;
+
+    let mut licenses = None;
+    run_stage(&mut manifest, &manifest_path, "layer4", vec![layer2_path.clone(), layer3_path.clone()], || {
+        let counts = layer_4::layer4_filter(&Layer4Args {
+            layer2: layer2_path.clone(),
+            layer3: layer3_path.clone(),
+            out_base: out_base.clone(),
+            split: args.split.clone(),
+            flush_interval: args.flush_interval,
+            errors: errors.clone(),
+            output: args.output.clone(),
+            votes: args.votes.clone(),
+            context_columns: args.context_columns,
+            metadata_column: args.metadata_column,
+            fields: args.fields.clone(),
+            task: args.task,
+            allow_licenses: args.allow_licenses.clone(),
+            memory: args.memory.clone(),
+        })?;
+        licenses = Some(counts.licenses.iter()
+            .map(|(license, &count)| (license.clone(), count as u64))
+            .collect());
+        let mut outputs = counts.splits.iter()
+            .flat_map(|&(field, counts)| layer_4::layer4_output_paths(&out_base, field, &args.output).into_iter()
+                .zip(counts)
+                .map(|(path, rows)| OutputRecord { path, rows: rows as u64 }))
+            .collect::<Vec<_>>();
+        outputs.push(OutputRecord {
+            path: split::split_manifest_path(&out_base),
+            rows: counts.splits.iter().flat_map(|(_, counts)| counts).sum::<usize>() as u64,
+        });
+        Ok(outputs)
+    })?;
+    if let Some(licenses) = licenses {
+        manifest.licenses = licenses;
+        manifest.write(&manifest_path)?;
+    }
+
+    match (find_dump_file(&args.dump_dir, SUGGESTED_EDITS_FILE), find_dump_file(&args.dump_dir, SUGGESTED_EDIT_VOTES_FILE)) {
+        (Ok(suggested_edits), Ok(suggested_edit_votes)) => {
+            let layer5_path = args.output.output_path(&args.out_dir.join(LAYER5_FILE));
+            let inputs = vec![suggested_edits.clone(), suggested_edit_votes.clone(), post_history.clone()];
+            run_stage(&mut manifest, &manifest_path, "layer5", inputs, || {
+                let rows = layer_5::layer5_filter(&Layer5Args {
+                    infile: suggested_edits.clone(),
+                    votes: suggested_edit_votes.clone(),
+                    post_history: post_history.clone(),
+                    outfile: layer5_path.clone(),
+                    flush_interval: args.flush_interval,
+                    errors: errors.clone(),
+                    output: args.output.clone(),
+                })?;
+                Ok(vec![OutputRecord { path: layer5_path.clone(), rows }])
+            })?;
+        }
+        _ => println!("=== Skipping layer5, the dump has no suggested edits ==="),
+    }
+
+    manifest.finished_at = Some(chrono::Local::now().to_rfc3339());
+    manifest.write(&manifest_path)?;
+
+    println!("Pipeline finished; manifest written to {}", manifest_path.display());
+
+    Ok(())
+}
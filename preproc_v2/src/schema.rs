@@ -0,0 +1,170 @@
+//! Detection of the schema of a dump file from its first rows.
+//!
+//! The attributes of the dumps changed over the years: `ContentLicense` only appears in dumps
+//! published since 2018, and older dumps may write dates without milliseconds. Each layer samples
+//! the first rows of the dump files it reads, reports what it found, fails with a clear error if
+//! an attribute it cannot do without is missing, and requires the optional ones only of dumps
+//! that have them.
+
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use crate::error::{Error, Result};
+use crate::input::open_input;
+use crate::xml::RowReader;
+
+/// Rows sampled from the start of a dump file.
+pub const SCHEMA_SAMPLE_ROWS: usize = 1000;
+
+/// How precisely the dates of a dump file are written. Both are parsed alike.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DatePrecision {
+    /// No dates in the sampled rows.
+    #[default]
+    Unknown,
+    Seconds,
+    Milliseconds,
+    /// Some dates with milliseconds and some without.
+    Mixed,
+}
+
+impl DatePrecision {
+    fn with(self, milliseconds: bool) -> Self {
+        match (self, milliseconds) {
+            (DatePrecision::Unknown | DatePrecision::Seconds, false) => DatePrecision::Seconds,
+            (DatePrecision::Unknown | DatePrecision::Milliseconds, true) => DatePrecision::Milliseconds,
+            _ => DatePrecision::Mixed,
+        }
+    }
+}
+
+impl Display for DatePrecision {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            DatePrecision::Unknown => "none seen",
+            DatePrecision::Seconds => "without milliseconds",
+            DatePrecision::Milliseconds => "with milliseconds",
+            DatePrecision::Mixed => "with and without milliseconds",
+        })
+    }
+}
+
+/// The attributes and date precision seen in the first rows of a dump file.
+#[derive(Clone, Debug, Default)]
+pub struct DumpSchema {
+    pub attributes: BTreeSet<String>,
+    pub dates: DatePrecision,
+    /// Rows sampled, fewer than [`SCHEMA_SAMPLE_ROWS`] for a short dump.
+    pub rows: usize,
+}
+
+impl DumpSchema {
+    /// Samples the first rows of the dump file at `path`. Malformed rows are left for the layer
+    /// to report.
+    pub fn detect(path: &Path) -> Result<Self> {
+        let (reader, pb) = open_input(path)?;
+        pb.finish_and_clear();
+        let mut schema = DumpSchema::default();
+        let mut rows = RowReader::new(reader);
+        while schema.rows < SCHEMA_SAMPLE_ROWS {
+            let row = rows.next_map(|element| {
+                for attr in element.attributes() {
+                    let attr = attr?;
+                    let key = String::from_utf8_lossy(attr.key.as_ref());
+                    // dates are written as 2008-07-31T21:42:52.667
+                    if key.ends_with("Date") && attr.value.get(10) == Some(&b'T') {
+                        schema.dates = schema.dates.with(attr.value.contains(&b'.'));
+                    }
+                    if !schema.attributes.contains(key.as_ref()) {
+                        schema.attributes.insert(key.into_owned());
+                    }
+                }
+                Ok(Some(()))
+            });
+            match row {
+                Some(Ok(_)) => schema.rows += 1,
+                Some(Err(e)) if matches!(e.error, Error::Io(_)) => return Err(e.error),
+                Some(Err(_)) => {}
+                None => break,
+            }
+        }
+        Ok(schema)
+    }
+
+    pub fn has(&self, attribute: &str) -> bool {
+        self.attributes.contains(attribute)
+    }
+
+    /// Fails if the dump file at `path` lacks any of the `required` attributes.
+    pub fn require(&self, path: &Path, required: &[&'static str]) -> Result<()> {
+        match required.iter().find(|attribute| !self.has(attribute)) {
+            Some(attribute) => Err(Error::Schema { input: path.to_path_buf(), attribute }),
+            None => Ok(()),
+        }
+    }
+
+    /// Prints the schema of the dump file at `path`, along with the `optional` attributes it
+    /// lacks.
+    pub fn report(&self, path: &Path, optional: &[&str]) {
+        println!("Detected schema of {} from its first {} rows:", path.display(), self.rows);
+        println!("  attributes: {}", self.attributes.iter().map(String::as_str).collect::<Vec<_>>().join(", "));
+        println!("  dates: {}", self.dates);
+        let missing = optional.iter().filter(|attribute| !self.has(attribute)).copied().collect::<Vec<_>>();
+        if !missing.is_empty() {
+            println!("  missing optional attributes: {}", missing.join(", "));
+        }
+    }
+}
+
+/// Detects and reports the schema of the dump file at `path`, checking that it has the
+/// `required` attributes.
+pub fn detect_schema(path: &Path, required: &[&'static str], optional: &[&str]) -> Result<DumpSchema> {
+    let schema = DumpSchema::detect(path)?;
+    schema.report(path, optional);
+    schema.require(path, required)?;
+    Ok(schema)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer_2::layer2_filter;
+    use crate::testing::{parse_args, TestDir, LAYER1_TSV, LAYER2_TSV, POST_HISTORY_XML};
+
+    /// `POST_HISTORY_XML` as dumps published before 2018 write it: without licenses, and with
+    /// dates without milliseconds.
+    fn old_post_history() -> String {
+        let xml = POST_HISTORY_XML.replace(".000\"", "\"");
+        ["2.5", "3.0", "4.0"].iter().fold(xml, |xml, version| xml.replace(&format!(" ContentLicense=\"CC BY-SA {version}\""), ""))
+    }
+
+    #[test]
+    fn schemas_tell_old_dumps_apart() {
+        let dir = TestDir::new("schema");
+        let schema = DumpSchema::detect(Path::new(&dir.write("PostHistory.xml", POST_HISTORY_XML))).unwrap();
+        assert_eq!((schema.rows, schema.dates, schema.has("ContentLicense")), (14, DatePrecision::Milliseconds, true));
+
+        let old = dir.write("Old.xml", &old_post_history());
+        let schema = DumpSchema::detect(Path::new(&old)).unwrap();
+        assert_eq!((schema.rows, schema.dates, schema.has("ContentLicense")), (14, DatePrecision::Seconds, false));
+        assert!(schema.require(Path::new(&old), &["PostId", "CreationDate", "Text"]).is_ok());
+        assert!(matches!(schema.require(Path::new(&old), &["PostId", "ContentLicense"]),
+            Err(Error::Schema { attribute: "ContentLicense", .. })));
+
+        let mixed = dir.write("Mixed.xml", &POST_HISTORY_XML.replacen(".000\"", "\"", 1));
+        assert_eq!(DumpSchema::detect(Path::new(&mixed)).unwrap().dates, DatePrecision::Mixed);
+    }
+
+    #[test]
+    fn layers_read_old_dumps() {
+        let dir = TestDir::new("schema-layer2");
+        let post_history = dir.write("PostHistory.xml", &old_post_history());
+        let layer1 = dir.write("layer1.tsv", LAYER1_TSV);
+        let out = dir.file("layer2.tsv");
+        let args = ["--in-file", &post_history, "--in-layer-1", &layer1, "--out-file", &out];
+        assert_eq!(layer2_filter(&parse_args(&args)).unwrap(), 2);
+        // the same pairs, without their licenses
+        let layer2 = LAYER2_TSV.replace("CC BY-SA 2.5", "").replace("CC BY-SA 3.0", "");
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), layer2);
+    }
+}
@@ -0,0 +1,401 @@
+//! Bounded-memory sorting and buffering of rows, spilling to disk past `--memory-budget`.
+//!
+//! Without a budget, layers keep their tables in memory. With one, they hold rows in memory only
+//! up to about the budget: an [`ExternalSort`] sorts each budget's worth of rows into a run on
+//! disk and merges the runs back, and a [`SpillVec`] writes its rows out in order once they
+//! outgrow the budget. Layers then join sorted streams of rows by merging them, instead of
+//! looking rows up in maps. Spilled rows are written with bincode into temporary files, which
+//! are removed once read back. At most [`MERGE_WIDTH`] runs are merged at once, so that a small
+//! budget does not run into the limit on open files; more runs are merged in several passes.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use clap::Args;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::error::{Error, Result};
+
+#[derive(Args, Clone, Default)]
+pub struct MemoryArgs {
+    /// Approximate bytes of rows a layer holds in memory, past which it sorts and joins them on
+    /// disk; unbounded if not given. Cannot be combined with checkpoints
+    #[arg(long = "memory-budget")]
+    pub memory_budget: Option<u64>,
+    /// Directory of the spilled rows; defaults to the system's temporary directory
+    #[arg(long = "spill-dir")]
+    pub spill_dir: Option<PathBuf>,
+}
+
+impl MemoryArgs {
+    pub fn unbounded() -> Self {
+        MemoryArgs::default()
+    }
+
+    pub fn is_bounded(&self) -> bool {
+        self.memory_budget.is_some()
+    }
+
+    fn dir(&self) -> PathBuf {
+        self.spill_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+
+    /// A sorter of rows by `key`, spilling runs named after `name`.
+    pub fn sorter<T, K: Ord, F: Fn(&T) -> K>(&self, name: &'static str, key: F) -> ExternalSort<T, F> {
+        ExternalSort {
+            name,
+            budget: self.memory_budget,
+            dir: self.dir(),
+            key,
+            buffer: Vec::new(),
+            buffered: 0,
+            runs: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// An ordered buffer of rows, spilling to a file named after `name`.
+    pub fn spill_vec<T>(&self, name: &'static str) -> SpillVec<T> {
+        SpillVec {
+            name,
+            budget: self.memory_budget,
+            dir: self.dir(),
+            buffer: Vec::new(),
+            buffered: 0,
+            spilled: None,
+            len: 0,
+        }
+    }
+}
+
+/// Most runs an [`ExternalSort`] reads at once; more are first merged into longer runs.
+pub const MERGE_WIDTH: usize = 64;
+
+/// Approximate bytes `row` takes up in memory: its own size and what it owns, as measured by its
+/// serialized size.
+pub fn memory_size<T: Serialize>(row: &T) -> u64 {
+    std::mem::size_of::<T>() as u64 + bincode::serialized_size(row).unwrap_or(0)
+}
+
+fn spill_error(path: &Path, e: bincode::Error) -> Error {
+    Error::Spill(format!("{}: {e}", path.display()))
+}
+
+/// A temporary file of spilled rows, removed when dropped.
+struct SpillFile {
+    path: PathBuf,
+    rows: u64,
+}
+
+impl SpillFile {
+    fn create(dir: &Path, name: &str) -> Result<(Self, BufWriter<File>)> {
+        static SPILLS: AtomicUsize = AtomicUsize::new(0);
+        let n = SPILLS.fetch_add(1, AtomicOrdering::Relaxed);
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{name}-{}-{n}.spill", std::process::id()));
+        let writer = BufWriter::new(File::create(&path)?);
+        Ok((SpillFile { path, rows: 0 }, writer))
+    }
+
+    fn write<T: Serialize>(&mut self, writer: &mut BufWriter<File>, row: &T) -> Result<()> {
+        bincode::serialize_into(writer, row).map_err(|e| spill_error(&self.path, e))?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn read<T: DeserializeOwned>(self) -> Result<SpillReader<T>> {
+        let reader = BufReader::new(File::open(&self.path)?);
+        Ok(SpillReader { remaining: self.rows, file: self, reader, row: PhantomData })
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The rows of a spill file, in the order they were written.
+struct SpillReader<T> {
+    file: SpillFile,
+    reader: BufReader<File>,
+    remaining: u64,
+    row: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> Iterator for SpillReader<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(bincode::deserialize_from(&mut self.reader).map_err(|e| spill_error(&self.file.path, e)))
+    }
+}
+
+/// Sorts rows by a key, in memory while they fit in the budget and otherwise in runs on disk.
+/// Rows of equal keys keep the order they were pushed in.
+pub struct ExternalSort<T, F> {
+    name: &'static str,
+    budget: Option<u64>,
+    dir: PathBuf,
+    key: F,
+    buffer: Vec<T>,
+    buffered: u64,
+    runs: Vec<SpillFile>,
+    len: u64,
+}
+
+impl<T: Serialize + DeserializeOwned, K: Ord, F: Fn(&T) -> K> ExternalSort<T, F> {
+    pub fn push(&mut self, row: T) -> Result<()> {
+        if let Some(budget) = self.budget {
+            self.buffered += memory_size(&row);
+            if self.buffered > budget {
+                self.spill_run()?;
+                self.buffered = memory_size(&row);
+            }
+        }
+        self.buffer.push(row);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Number of rows pushed.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of runs spilled to disk so far.
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    fn spill_run(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.buffer.sort_by_key(&self.key);
+        let (mut run, mut writer) = SpillFile::create(&self.dir, self.name)?;
+        for row in self.buffer.drain(..) {
+            run.write(&mut writer, &row)?;
+        }
+        writer.flush()?;
+        self.runs.push(run);
+        Ok(())
+    }
+
+    /// The rows pushed, in key order.
+    pub fn finish(mut self) -> Result<SortedRows<T, K, F>> {
+        if self.runs.is_empty() {
+            self.buffer.sort_by_key(&self.key);
+            return Ok(SortedRows(Sorted::Memory(std::mem::take(&mut self.buffer).into_iter())));
+        }
+        self.spill_run()?;
+        let mut runs = std::mem::take(&mut self.runs);
+        while runs.len() > MERGE_WIDTH {
+            // runs merged in order keep rows of equal keys in the order they were pushed
+            let mut merged = Vec::with_capacity(runs.len().div_ceil(MERGE_WIDTH));
+            let mut groups = runs.into_iter();
+            loop {
+                let group = groups.by_ref().take(MERGE_WIDTH).collect::<Vec<_>>();
+                if group.len() <= 1 {
+                    merged.extend(group);
+                    break;
+                }
+                let (mut run, mut writer) = SpillFile::create(&self.dir, self.name)?;
+                for row in SortedRows(merge_runs(group, &self.key)?) {
+                    run.write(&mut writer, &row?)?;
+                }
+                writer.flush()?;
+                merged.push(run);
+            }
+            runs = merged;
+        }
+        Ok(SortedRows(merge_runs(runs, self.key)?))
+    }
+}
+
+/// The rows of sorted `runs` in key order.
+fn merge_runs<T: DeserializeOwned, K: Ord, F: Fn(&T) -> K>(runs: Vec<SpillFile>, key: F) -> Result<Sorted<T, K, F>> {
+    let mut readers = runs.into_iter()
+        .map(SpillFile::read)
+        .collect::<Result<Vec<_>>>()?;
+    let mut heads = BinaryHeap::new();
+    for (run, reader) in readers.iter_mut().enumerate() {
+        if let Some(row) = reader.next() {
+            let row = row?;
+            heads.push(Head { key: key(&row), run, row });
+        }
+    }
+    Ok(Sorted::Merge { readers, heads, key })
+}
+
+/// The next row of a run in a merge; the heap yields the smallest key, and the earliest run
+/// among equal keys.
+struct Head<K, T> {
+    key: K,
+    run: usize,
+    row: T,
+}
+
+impl<K: Ord, T> Ord for Head<K, T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.key.cmp(&self.key).then(other.run.cmp(&self.run))
+    }
+}
+
+impl<K: Ord, T> PartialOrd for Head<K, T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord, T> PartialEq for Head<K, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: Ord, T> Eq for Head<K, T> {}
+
+/// The rows of an [`ExternalSort`] in key order.
+pub struct SortedRows<T, K, F>(Sorted<T, K, F>);
+
+enum Sorted<T, K, F> {
+    Memory(std::vec::IntoIter<T>),
+    Merge {
+        readers: Vec<SpillReader<T>>,
+        heads: BinaryHeap<Head<K, T>>,
+        key: F,
+    },
+}
+
+impl<T: DeserializeOwned, K: Ord, F: Fn(&T) -> K> Iterator for SortedRows<T, K, F> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            Sorted::Memory(rows) => rows.next().map(Ok),
+            Sorted::Merge { readers, heads, key } => {
+                let Head { run, row, .. } = heads.pop()?;
+                match readers[run].next() {
+                    Some(Ok(next)) => heads.push(Head { key: key(&next), run, row: next }),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => {}
+                }
+                Some(Ok(row))
+            }
+        }
+    }
+}
+
+/// Rows kept in the order they were pushed, in memory while they fit in the budget and
+/// otherwise in a file on disk.
+pub struct SpillVec<T> {
+    name: &'static str,
+    budget: Option<u64>,
+    dir: PathBuf,
+    buffer: Vec<T>,
+    buffered: u64,
+    spilled: Option<(SpillFile, BufWriter<File>)>,
+    len: u64,
+}
+
+impl<T: Serialize + DeserializeOwned> SpillVec<T> {
+    pub fn push(&mut self, row: T) -> Result<()> {
+        if let Some(budget) = self.budget {
+            self.buffered += memory_size(&row);
+            if self.buffered > budget {
+                self.spill()?;
+                self.buffered = memory_size(&row);
+            }
+        }
+        self.buffer.push(row);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether some of the rows were written to disk.
+    pub fn is_spilled(&self) -> bool {
+        self.spilled.is_some()
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        if self.spilled.is_none() {
+            self.spilled = Some(SpillFile::create(&self.dir, self.name)?);
+        }
+        let (file, writer) = self.spilled.as_mut().unwrap();
+        for row in self.buffer.drain(..) {
+            file.write(writer, &row)?;
+        }
+        Ok(())
+    }
+
+    /// The rows pushed, in order.
+    pub fn into_rows(self) -> Result<impl Iterator<Item = Result<T>>> {
+        let spilled = match self.spilled {
+            Some((file, mut writer)) => {
+                writer.flush()?;
+                drop(writer);
+                Some(file.read()?)
+            }
+            None => None,
+        };
+        Ok(spilled.into_iter().flatten().chain(self.buffer.into_iter().map(Ok)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestDir;
+
+    #[test]
+    fn external_sort_merges_more_runs_than_it_opens_at_once() {
+        let dir = TestDir::new("spill");
+        let memory = MemoryArgs { memory_budget: Some(1), spill_dir: Some(dir.path().to_path_buf()) };
+        // one run per row, merged in two passes
+        let rows = (0..MERGE_WIDTH as u64 * 5).map(|i| (i * 7 % 10, i)).collect::<Vec<_>>();
+        let mut sorter = memory.sorter("test", |&(key, _): &(u64, u64)| key);
+        for &row in &rows {
+            sorter.push(row).unwrap();
+        }
+        assert_eq!(sorter.runs(), rows.len() - 1);
+        let sorted = sorter.finish().unwrap().collect::<Result<Vec<_>>>().unwrap();
+        let mut expected = rows;
+        expected.sort_by_key(|&(key, _)| key);
+        assert_eq!(sorted, expected);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn spill_vec_keeps_its_rows_in_order() {
+        let dir = TestDir::new("spill-vec");
+        let memory = MemoryArgs { memory_budget: Some(64), spill_dir: Some(dir.path().to_path_buf()) };
+        let mut rows = memory.spill_vec("test");
+        for i in 0..100u64 {
+            rows.push(i.to_string()).unwrap();
+        }
+        assert!(rows.is_spilled());
+        let rows = rows.into_rows().unwrap().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(rows, (0..100u64).map(|i| i.to_string()).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,457 @@
+//! Assignment of Layer4 examples to the train, eval and test splits.
+//!
+//! Every mode assigns the examples that passed the Layer4 filters as a whole, so the proportions
+//! given by `--split` hold exactly, up to whole posts (whose revision pairs stay together) or, in
+//! grouped mode, whole authors; in time mode the cutoff dates decide instead, by the first edit
+//! of each post. The shuffles are seeded with `--seed`, so the same inputs and arguments always
+//! give the same splits; the post ids of each split are recorded in a split manifest next to the
+//! splits.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use chrono::{NaiveDate, NaiveDateTime};
+use clap::{Args, ValueEnum};
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use serde::Serialize;
+use crate::error::Error;
+use crate::layer_2::RevisionPair;
+use crate::PostId;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SplitMode {
+    /// In post id order: the oldest questions train, the newest test
+    Sequential,
+    /// Shuffled
+    #[default]
+    Random,
+    /// Shuffled within each stratum (see --stratify-by), keeping every stratum in proportion
+    Stratified,
+    /// Shuffled by author, so that each author's questions all land in one split
+    Grouped,
+    /// By the date of the first edit of each post, split at --eval-from and --test-from
+    Time,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StratifyBy {
+    /// The first tag of the question
+    #[default]
+    Tag,
+    /// The change in body length, in powers of two
+    EditSize,
+}
+
+/// Proportions of the train, eval and test splits, written as `train:eval:test` such as `8:1:1`.
+/// Each part is a whole number, and at least one is not zero.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SplitProportions(pub [u64; 3]);
+
+impl FromStr for SplitProportions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s.split(':')
+            .map(|part| part.parse::<u64>().map_err(|e| format!("bad part {part:?} of {s:?}: {e}")))
+            .collect::<Result<Vec<_>, _>>()?;
+        let parts: [u64; 3] = parts.try_into()
+            .map_err(|parts: Vec<_>| format!("expected train:eval:test, found {} parts in {s:?}", parts.len()))?;
+        if parts.iter().all(|&part| part == 0) {
+            return Err(format!("{s:?} puts no examples in any split"));
+        }
+        Ok(SplitProportions(parts))
+    }
+}
+
+impl Display for SplitProportions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let [train, eval, test] = self.0;
+        write!(f, "{train}:{eval}:{test}")
+    }
+}
+
+#[derive(Args, Clone)]
+pub struct SplitArgs {
+    /// Proportions of the train, eval and test splits, as `train:eval:test`
+    #[arg(long = "split", default_value = "8:1:1")]
+    pub split: SplitProportions,
+    #[arg(long = "split-mode", value_enum, default_value_t = SplitMode::Random)]
+    pub mode: SplitMode,
+    /// Seed of the random, stratified and grouped shuffles
+    #[arg(long = "seed", default_value_t = 0)]
+    pub seed: u64,
+    #[arg(long = "stratify-by", value_enum, default_value_t = StratifyBy::Tag)]
+    pub stratify_by: StratifyBy,
+    /// In time mode, posts first edited on or after this date (YYYY-MM-DD) go to eval
+    #[arg(long = "eval-from", required_if_eq("mode", "time"))]
+    pub eval_from: Option<NaiveDate>,
+    /// In time mode, posts first edited on or after this date (YYYY-MM-DD) go to test; not
+    /// before --eval-from
+    #[arg(long = "test-from", required_if_eq("mode", "time"))]
+    pub test_from: Option<NaiveDate>,
+}
+
+impl SplitArgs {
+    /// Random `train:eval:test` splits.
+    pub fn random(split: SplitProportions, seed: u64) -> Self {
+        SplitArgs {
+            split,
+            mode: SplitMode::Random,
+            seed,
+            stratify_by: StratifyBy::Tag,
+            eval_from: None,
+            test_from: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Split {
+    Train,
+    Eval,
+    Test,
+}
+
+pub const SPLITS: [Split; 3] = [Split::Train, Split::Eval, Split::Test];
+
+/// What the split modes need to know about an example.
+pub struct SplitKey {
+    pub post_id: PostId,
+    pub author_id: i32,
+    /// Tells apart the authors of posts whose owner was deleted.
+    pub author_name: String,
+    pub primary_tag: Option<String>,
+    /// Difference in length between the bodies before and after the edit.
+    pub edit_size: usize,
+    pub edit_date: NaiveDateTime,
+}
+
+impl SplitKey {
+    pub fn of(pair: &RevisionPair) -> Self {
+        SplitKey {
+            post_id: pair.post_id,
+            author_id: pair.author_id,
+            author_name: pair.author_name.clone(),
+            primary_tag: pair.tags.primary().map(str::to_string),
+            edit_size: pair.after_text.len().abs_diff(pair.before_text.len()),
+            edit_date: pair.after_date,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum Stratum<'a> {
+    Tag(Option<&'a str>),
+    EditSize(u32),
+}
+
+impl<'a> Stratum<'a> {
+    fn of(key: &'a SplitKey, by: StratifyBy) -> Self {
+        match by {
+            StratifyBy::Tag => Stratum::Tag(key.primary_tag.as_deref()),
+            StratifyBy::EditSize => Stratum::EditSize(usize::BITS - key.edit_size.leading_zeros()),
+        }
+    }
+}
+
+/// Splits `total` examples into train/eval/test counts proportional to `split`.
+pub fn split_counts(split: &SplitProportions, total: usize) -> [usize; 3] {
+    let [train_count, eval_count, test_count] = split.0;
+    let total_parts = train_count + eval_count + test_count;
+
+    let eval_prop = (eval_count as f32) / (total_parts as f32);
+    let test_prop = (test_count as f32) / (total_parts as f32);
+
+    let actual_total = total as f32;
+    let actual_eval_count = (actual_total * eval_prop).round() as usize;
+    let actual_test_count = (actual_total * test_prop).round() as usize;
+    let actual_train_count = total - actual_eval_count - actual_test_count;
+
+    [actual_train_count, actual_eval_count, actual_test_count]
+}
+
+/// Assigns each of `keys`, which are in post id order, to a split.
+pub fn assign_splits(args: &SplitArgs, keys: &[SplitKey]) -> crate::error::Result<Vec<Split>> {
+    let mut rng = ChaCha8Rng::seed_from_u64(args.seed);
+    let mut splits = vec![Split::Train; keys.len()];
+    let counts = split_counts(&args.split, keys.len());
+    match args.mode {
+        SplitMode::Sequential => {
+            fill(&mut splits, counts, posts(keys));
+        }
+        SplitMode::Random => {
+            let mut posts = posts(keys);
+            posts.shuffle(&mut rng);
+            fill(&mut splits, counts, posts);
+        }
+        SplitMode::Stratified => {
+            let mut strata = BTreeMap::<_, Vec<Vec<usize>>>::new();
+            for post in posts(keys) {
+                strata.entry(Stratum::of(&keys[post[0]], args.stratify_by)).or_default().push(post);
+            }
+            for mut posts in strata.into_values() {
+                posts.shuffle(&mut rng);
+                let total = posts.iter().map(Vec::len).sum();
+                fill(&mut splits, split_counts(&args.split, total), posts);
+            }
+        }
+        SplitMode::Grouped => {
+            let mut authors = BTreeMap::<_, Vec<usize>>::new();
+            for (i, key) in keys.iter().enumerate() {
+                authors.entry((key.author_id, key.author_name.as_str())).or_default().push(i);
+            }
+            let mut groups = authors.into_values().collect::<Vec<_>>();
+            groups.shuffle(&mut rng);
+            fill(&mut splits, counts, groups);
+        }
+        SplitMode::Time => {
+            let (Some(eval_from), Some(test_from)) = (args.eval_from, args.test_from) else {
+                return Err(Error::Usage("--split-mode time needs --eval-from and --test-from".to_string()));
+            };
+            if eval_from > test_from {
+                return Err(Error::Usage(format!("--eval-from {eval_from} is after --test-from {test_from}, which leaves eval empty")));
+            }
+            // the revision pairs of a post stay together, dated by its first edit
+            for post in posts(keys) {
+                let date = post.iter().map(|&i| keys[i].edit_date).min().unwrap().date();
+                let split = if date >= test_from {
+                    Split::Test
+                } else if date >= eval_from {
+                    Split::Eval
+                } else {
+                    Split::Train
+                };
+                for i in post {
+                    splits[i] = split;
+                }
+            }
+        }
+    }
+    Ok(splits)
+}
+
+/// The examples of each post, in post id order.
+fn posts(keys: &[SplitKey]) -> Vec<Vec<usize>> {
+    let mut posts = Vec::<Vec<usize>>::new();
+    for (i, key) in keys.iter().enumerate() {
+        match posts.last_mut() {
+            Some(post) if keys[post[0]].post_id == key.post_id => post.push(i),
+            _ => posts.push(vec![i]),
+        }
+    }
+    posts
+}
+
+/// Assigns whole `groups` of examples, in order, to train until it holds `counts[0]` examples,
+/// then to eval until it holds `counts[1]` and the rest to test. The proportions are only as
+/// exact as the groups are small.
+fn fill(splits: &mut [Split], counts: [usize; 3], groups: impl IntoIterator<Item = Vec<usize>>) {
+    let mut assigned = [0; 3];
+    let mut split = 0;
+    for group in groups {
+        while split < 2 && assigned[split] >= counts[split] {
+            split += 1;
+        }
+        assigned[split] += group.len();
+        for i in group {
+            splits[i] = SPLITS[split];
+        }
+    }
+}
+
+/// The post ids of every split, along with the arguments that produced them.
+#[derive(Serialize)]
+pub struct SplitManifest {
+    pub mode: SplitMode,
+    pub split: String,
+    pub seed: u64,
+    pub stratify_by: Option<StratifyBy>,
+    pub eval_from: Option<NaiveDate>,
+    pub test_from: Option<NaiveDate>,
+    pub train: Vec<PostId>,
+    pub eval: Vec<PostId>,
+    pub test: Vec<PostId>,
+}
+
+impl SplitManifest {
+    pub fn new(args: &SplitArgs, keys: &[SplitKey], splits: &[Split]) -> Self {
+        let mut manifest = SplitManifest {
+            mode: args.mode,
+            split: args.split.to_string(),
+            seed: args.seed,
+            stratify_by: (args.mode == SplitMode::Stratified).then_some(args.stratify_by),
+            eval_from: args.eval_from,
+            test_from: args.test_from,
+            train: Vec::new(),
+            eval: Vec::new(),
+            test: Vec::new(),
+        };
+        for (key, split) in keys.iter().zip(splits) {
+            match split {
+                Split::Train => manifest.train.push(key.post_id),
+                Split::Eval => manifest.eval.push(key.post_id),
+                Split::Test => manifest.test.push(key.post_id),
+            }
+        }
+        // the revision pairs of a post all go to its split
+        for post_ids in [&mut manifest.train, &mut manifest.eval, &mut manifest.test] {
+            post_ids.sort_unstable();
+            post_ids.dedup();
+        }
+        manifest
+    }
+
+    pub fn write(&self, path: &Path) -> crate::error::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut writer, self).map_err(std::io::Error::from)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Path of the split manifest written for OUT_BASE.
+pub fn split_manifest_path(out_base: &Path) -> PathBuf {
+    let mut fname = out_base.file_stem().unwrap().to_os_string();
+    fname.push("-splits.json");
+    out_base.with_file_name(fname)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(post_id: PostId, author_id: i32, primary_tag: &str, edit_date: &str) -> SplitKey {
+        SplitKey {
+            post_id,
+            author_id,
+            author_name: String::new(),
+            primary_tag: Some(primary_tag.to_string()),
+            edit_size: 0,
+            edit_date: NaiveDateTime::parse_from_str(edit_date, crate::DATE_FORMAT).unwrap(),
+        }
+    }
+
+    /// A post per key of 100 posts by 20 authors, half of them tagged `python` and half `rust`.
+    fn keys() -> Vec<SplitKey> {
+        (0..100).map(|i| key(i + 1, (i % 20) as i32, if i % 2 == 0 { "python" } else { "rust" }, "2020-01-01T00:00:00.000")).collect()
+    }
+
+    fn count(splits: &[Split], split: Split) -> usize {
+        splits.iter().filter(|&&s| s == split).count()
+    }
+
+    #[test]
+    fn split_proportions_round_trip() {
+        let split = "8:1:1".parse::<SplitProportions>().unwrap();
+        assert_eq!(split, SplitProportions([8, 1, 1]));
+        assert_eq!(split.to_string().parse::<SplitProportions>().unwrap(), split);
+        assert_eq!(split_counts(&split, 20), [16, 2, 2]);
+        for bad in ["8:1", "8:1:1:1", "-1:1:1", "a:1:1", "0:0:0"] {
+            assert!(bad.parse::<SplitProportions>().is_err(), "{bad} was accepted");
+        }
+    }
+
+    #[test]
+    fn random_mode_keeps_the_proportions_and_the_pairs_of_a_post_together() {
+        let mut keys = keys();
+        keys.insert(1, key(1, 0, "python", "2020-02-01T00:00:00.000"));
+        let args = SplitArgs::random(SplitProportions([8, 1, 1]), 7);
+        let splits = assign_splits(&args, &keys).unwrap();
+        assert_eq!(splits[0], splits[1]);
+        // the counts are only off by the post with two pairs
+        let counts = SPLITS.map(|split| count(&splits, split));
+        assert_eq!(counts.iter().sum::<usize>(), 101);
+        assert!(counts[0].abs_diff(81) <= 1 && counts[1].abs_diff(10) <= 1 && counts[2].abs_diff(10) <= 1, "{counts:?}");
+
+        assert_eq!(assign_splits(&args, &keys).unwrap(), splits);
+        assert_ne!(assign_splits(&SplitArgs::random(args.split, 8), &keys).unwrap(), splits);
+    }
+
+    #[test]
+    fn stratified_mode_keeps_every_stratum_in_proportion() {
+        let keys = keys();
+        let args = SplitArgs { mode: SplitMode::Stratified, ..SplitArgs::random(SplitProportions([8, 1, 1]), 0) };
+        let splits = assign_splits(&args, &keys).unwrap();
+        for tag in ["python", "rust"] {
+            let stratum = keys.iter().zip(&splits)
+                .filter(|(key, _)| key.primary_tag.as_deref() == Some(tag))
+                .map(|(_, &split)| split)
+                .collect::<Vec<_>>();
+            assert_eq!(SPLITS.map(|split| count(&stratum, split)), [40, 5, 5], "{tag}");
+        }
+    }
+
+    #[test]
+    fn grouped_mode_puts_each_author_in_one_split() {
+        let keys = keys();
+        let args = SplitArgs { mode: SplitMode::Grouped, ..SplitArgs::random(SplitProportions([8, 1, 1]), 0) };
+        let splits = assign_splits(&args, &keys).unwrap();
+        let mut authors = BTreeMap::new();
+        for (key, split) in keys.iter().zip(&splits) {
+            assert_eq!(authors.entry(key.author_id).or_insert(split), &split, "author {}", key.author_id);
+        }
+        // every author has 5 posts, so the proportions hold exactly
+        assert_eq!(SPLITS.map(|split| count(&splits, split)), [80, 10, 10]);
+    }
+
+    #[test]
+    fn split_manifests_list_the_posts_of_each_split() {
+        let keys = [
+            key(1, 1, "python", "2020-01-01T00:00:00.000"),
+            key(1, 1, "python", "2020-02-01T00:00:00.000"),
+            key(2, 1, "python", "2020-01-01T00:00:00.000"),
+            key(3, 1, "python", "2020-01-01T00:00:00.000"),
+        ];
+        let args = SplitArgs { mode: SplitMode::Sequential, ..SplitArgs::random(SplitProportions([2, 1, 1]), 0) };
+        let splits = assign_splits(&args, &keys).unwrap();
+        assert_eq!(splits, [Split::Train, Split::Train, Split::Eval, Split::Test]);
+        let manifest = SplitManifest::new(&args, &keys, &splits);
+        assert_eq!((manifest.train.as_slice(), manifest.eval.as_slice(), manifest.test.as_slice()), (&[1][..], &[2][..], &[3][..]));
+
+        let dir = crate::testing::TestDir::new("split-manifest");
+        let path = split_manifest_path(&dir.path().join("out.tsv"));
+        assert_eq!(path, dir.path().join("out-splits.json"));
+        manifest.write(&path).unwrap();
+        let json = serde_json::from_str::<serde_json::Value>(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(json, serde_json::json!({
+            "mode": "sequential",
+            "split": "2:1:1",
+            "seed": 0,
+            "stratify_by": null,
+            "eval_from": null,
+            "test_from": null,
+            "train": [1],
+            "eval": [2],
+            "test": [3],
+        }));
+    }
+
+    #[test]
+    fn time_mode_splits_posts_by_their_first_edit() {
+        let keys = [
+            key(1, 1, "python", "2019-06-01T00:00:00.000"),
+            key(2, 1, "python", "2020-06-01T00:00:00.000"),
+            key(2, 1, "python", "2021-06-01T00:00:00.000"),
+            key(3, 1, "python", "2021-06-01T00:00:00.000"),
+        ];
+        let mut args = SplitArgs {
+            mode: SplitMode::Time,
+            eval_from: NaiveDate::from_ymd_opt(2020, 1, 1),
+            test_from: NaiveDate::from_ymd_opt(2021, 1, 1),
+            ..SplitArgs::random(SplitProportions([8, 1, 1]), 0)
+        };
+        assert_eq!(assign_splits(&args, &keys).unwrap(), [Split::Train, Split::Eval, Split::Eval, Split::Test]);
+
+        (args.eval_from, args.test_from) = (args.test_from, args.eval_from);
+        assert!(matches!(assign_splits(&args, &keys), Err(Error::Usage(_))));
+    }
+}
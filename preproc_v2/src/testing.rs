@@ -0,0 +1,116 @@
+//! A small dump and helpers shared by the unit tests of the layers.
+//!
+//! The dump has a question (1) edited by another user, an answer (2) to it edited the same way,
+//! a question (3) whose edit was rolled back, a question (4) whose owner was deleted and a
+//! question (5) edited by a deleted user and then by another user.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use clap::{Args, Command, FromArgMatches};
+
+pub const POSTS_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<posts>
+  <row Id="1" PostTypeId="1" CreationDate="2010-01-01T00:00:00.000" Score="5" Body="b" OwnerUserId="8" LastEditorUserId="9" LastEditDate="2010-02-01T00:00:00.000" Title="T1" Tags="&lt;python&gt;&lt;list&gt;" AnswerCount="1" CommentCount="0" ContentLicense="CC BY-SA 2.5" />
+  <row Id="2" PostTypeId="2" ParentId="1" CreationDate="2010-01-02T00:00:00.000" Score="1" Body="a" OwnerUserId="10" LastEditorUserId="9" LastEditDate="2010-03-01T00:00:00.000" CommentCount="0" ContentLicense="CC BY-SA 2.5" />
+  <row Id="3" PostTypeId="1" CreationDate="2011-01-01T00:00:00.000" Score="2" Body="b" OwnerUserId="11" LastEditorUserId="12" LastEditDate="2011-02-01T00:00:00.000" Title="T3" Tags="&lt;rust&gt;" AnswerCount="0" CommentCount="0" ContentLicense="CC BY-SA 3.0" />
+  <row Id="4" PostTypeId="1" CreationDate="2011-01-01T00:00:00.000" Score="2" Body="b" OwnerDisplayName="ghost" LastEditorUserId="12" LastEditDate="2011-02-01T00:00:00.000" Title="T4" Tags="&lt;rust&gt;" AnswerCount="0" CommentCount="0" ContentLicense="CC BY-SA 3.0" />
+  <row Id="5" PostTypeId="1" CreationDate="2012-01-01T00:00:00.000" Score="3" Body="b" OwnerUserId="13" LastEditorUserId="14" LastEditDate="2012-03-01T00:00:00.000" Title="T5" Tags="&lt;go&gt;" AnswerCount="0" CommentCount="0" ContentLicense="CC BY-SA 4.0" />
+</posts>
+"#;
+
+pub const POST_HISTORY_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<posthistory>
+  <row Id="1" PostHistoryTypeId="1" PostId="1" RevisionGUID="00000000-0000-0000-0000-000000000001" CreationDate="2010-01-01T00:00:00.000" UserId="8" Text="Title one" ContentLicense="CC BY-SA 2.5" />
+  <row Id="2" PostHistoryTypeId="2" PostId="1" RevisionGUID="00000000-0000-0000-0000-000000000001" CreationDate="2010-01-01T00:00:00.000" UserId="8" Text="Original body one" ContentLicense="CC BY-SA 2.5" />
+  <row Id="3" PostHistoryTypeId="3" PostId="1" RevisionGUID="00000000-0000-0000-0000-000000000001" CreationDate="2010-01-01T00:00:00.000" UserId="8" Text="&lt;python&gt;&lt;list&gt;" ContentLicense="CC BY-SA 2.5" />
+  <row Id="4" PostHistoryTypeId="2" PostId="2" RevisionGUID="00000000-0000-0000-0000-000000000002" CreationDate="2010-01-02T00:00:00.000" UserId="10" Text="Answer body" ContentLicense="CC BY-SA 2.5" />
+  <row Id="5" PostHistoryTypeId="5" PostId="1" RevisionGUID="00000000-0000-0000-0000-000000000003" CreationDate="2010-02-01T00:00:00.000" UserId="9" Comment="fixed grammar" Text="Edited body one" ContentLicense="CC BY-SA 2.5" />
+  <row Id="6" PostHistoryTypeId="5" PostId="2" RevisionGUID="00000000-0000-0000-0000-000000000004" CreationDate="2010-03-01T00:00:00.000" UserId="9" Comment="clarify" Text="Answer body edited" ContentLicense="CC BY-SA 3.0" />
+  <row Id="7" PostHistoryTypeId="2" PostId="3" RevisionGUID="00000000-0000-0000-0000-000000000005" CreationDate="2011-01-01T00:00:00.000" UserId="11" Text="Body three" ContentLicense="CC BY-SA 3.0" />
+  <row Id="8" PostHistoryTypeId="5" PostId="3" RevisionGUID="00000000-0000-0000-0000-000000000006" CreationDate="2011-02-01T00:00:00.000" UserId="12" Comment="spam" Text="Body three spam" ContentLicense="CC BY-SA 3.0" />
+  <row Id="9" PostHistoryTypeId="8" PostId="3" RevisionGUID="00000000-0000-0000-0000-000000000007" CreationDate="2011-02-02T00:00:00.000" UserId="11" Comment="Rollback to [00000000-0000-0000-0000-000000000005]" Text="Body three" ContentLicense="CC BY-SA 3.0" />
+  <row Id="10" PostHistoryTypeId="2" PostId="4" RevisionGUID="00000000-0000-0000-0000-000000000008" CreationDate="2011-01-01T00:00:00.000" UserDisplayName="ghost" Text="Body four" ContentLicense="CC BY-SA 3.0" />
+  <row Id="11" PostHistoryTypeId="5" PostId="4" RevisionGUID="00000000-0000-0000-0000-000000000009" CreationDate="2011-02-01T00:00:00.000" UserId="12" Comment="edit" Text="Body four edited" ContentLicense="CC BY-SA 3.0" />
+  <row Id="12" PostHistoryTypeId="2" PostId="5" RevisionGUID="00000000-0000-0000-0000-00000000000a" CreationDate="2012-01-01T00:00:00.000" UserId="13" Text="Body five" ContentLicense="CC BY-SA 4.0" />
+  <row Id="13" PostHistoryTypeId="5" PostId="5" RevisionGUID="00000000-0000-0000-0000-00000000000b" CreationDate="2012-02-01T00:00:00.000" UserDisplayName="gone" Comment="rewrite" Text="Body five, rewritten" ContentLicense="CC BY-SA 4.0" />
+  <row Id="14" PostHistoryTypeId="5" PostId="5" RevisionGUID="00000000-0000-0000-0000-00000000000c" CreationDate="2012-03-01T00:00:00.000" UserId="14" Comment="typo" Text="Body five, rewritten and fixed" ContentLicense="CC BY-SA 4.0" />
+</posthistory>
+"#;
+
+pub const VOTES_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<votes>
+  <row Id="1" PostId="1" VoteTypeId="2" CreationDate="2010-01-05T00:00:00.000" />
+  <row Id="2" PostId="1" VoteTypeId="2" CreationDate="2010-03-05T00:00:00.000" />
+  <row Id="3" PostId="1" VoteTypeId="2" CreationDate="2010-03-06T00:00:00.000" />
+  <row Id="4" PostId="3" VoteTypeId="3" CreationDate="2011-03-06T00:00:00.000" />
+</votes>
+"#;
+
+/// The Layer1 output of the dump for `--post-types both`.
+pub const LAYER1_TSV: &str = "\
+1\t8\t\tpython|list\t\t
+2\t10\t\t\t1\t
+3\t11\t\trust\t\t
+5\t13\t\tgo\t\t
+";
+
+/// The Layer2 output of the dump for the Layer1 output above; post 5 was edited twice.
+pub const LAYER2_TSV: &str = "\
+1\t1\tbody\tOriginal body one\t2010-01-01T00:00:00.000\tCC BY-SA 2.5\tEdited body one\t2010-02-01T00:00:00.000\tCC BY-SA 2.5\t2678400\t2678400\tfixed grammar\tfalse\t8\t\t9\tpython|list\t\t\t\t
+2\t1\tbody\tAnswer body\t2010-01-02T00:00:00.000\tCC BY-SA 2.5\tAnswer body edited\t2010-03-01T00:00:00.000\tCC BY-SA 3.0\t5011200\t5097600\tclarify\tfalse\t10\t\t9\t\t1\tTitle one\tOriginal body one\t
+";
+
+/// The Layer3 output of the dump for the Layer2 output above.
+pub const LAYER3_TSV: &str = "\
+1\t1\t1\t0\t2\t0
+2\t1\t0\t0\t0\t0
+";
+
+/// A directory of test files under the system's temporary directory, removed when dropped.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    /// A fresh directory for the test `name`.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("preproc_v2-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Path of `file` in the directory, as given on the command line.
+    pub fn file(&self, file: &str) -> String {
+        self.0.join(file).to_str().unwrap().to_string()
+    }
+
+    /// Writes `contents` to `file` in the directory and returns its path.
+    pub fn write(&self, file: &str, contents: &str) -> String {
+        let path = self.file(file);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Opens `file` in the directory for reading.
+    pub fn open(&self, file: &str) -> BufReader<File> {
+        BufReader::new(File::open(self.0.join(file)).unwrap())
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Parses the arguments of a subcommand as given on the command line.
+pub fn parse_args<T: Args + FromArgMatches>(args: &[&str]) -> T {
+    let matches = T::augment_args(Command::new("test"))
+        .try_get_matches_from(std::iter::once("test").chain(args.iter().copied()))
+        .unwrap();
+    T::from_arg_matches(&matches).unwrap()
+}
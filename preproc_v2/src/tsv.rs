@@ -0,0 +1,148 @@
+//! Tab-separated files passed between layers.
+//!
+//! Rows are one line each. The outputs of Layers 1 to 3 and 5 have no header; their column
+//! layout is given by the `Display` and `FromStr` implementations of the row type. The Layer4
+//! splits start with a header of column names instead, which they are read back by, since their
+//! columns depend on the task and the column options.
+
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
+use std::marker::PhantomData;
+use std::str::FromStr;
+use crate::error::RowError;
+
+/// A TSV line that could not be parsed into a row.
+#[derive(Debug)]
+pub struct RowParseError {
+    pub row: &'static str,
+    pub reason: String,
+}
+
+impl RowParseError {
+    pub fn new(row: &'static str, reason: impl Into<String>) -> Self {
+        RowParseError { row, reason: reason.into() }
+    }
+}
+
+impl Display for RowParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "malformed {} row: {}", self.row, self.reason)
+    }
+}
+
+impl std::error::Error for RowParseError {}
+
+/// Splits `line` on tabs, checking that there are exactly `N` columns.
+pub fn split_columns<'a, const N: usize>(row: &'static str, line: &'a str) -> Result<[&'a str; N], RowParseError> {
+    let columns = line.split('\t').collect::<Vec<_>>();
+    columns.try_into()
+        .map_err(|columns: Vec<_>| RowParseError::new(row, format!("expected {N} columns, found {}", columns.len())))
+}
+
+/// Parses a single column, naming it in the error.
+pub fn parse_column<T: FromStr>(row: &'static str, name: &str, value: &str) -> Result<T, RowParseError>
+where T::Err: Display {
+    T::from_str(value)
+        .map_err(|e| RowParseError::new(row, format!("bad {name} {value:?}: {e}")))
+}
+
+/// Iterator over the rows of a TSV file, yielding each with the byte offset of its line.
+pub struct TsvReader<R, T> {
+    reader: R,
+    offset: u64,
+    buf: Vec<u8>,
+    _row: PhantomData<T>,
+}
+
+impl<R: BufRead, T> TsvReader<R, T> {
+    pub fn new(reader: R) -> Self {
+        TsvReader {
+            reader,
+            offset: 0,
+            buf: Vec::new(),
+            _row: PhantomData,
+        }
+    }
+
+    /// Byte offset of the next line.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl<R: BufRead, T> Iterator for TsvReader<R, T>
+where T: FromStr<Err = RowParseError> {
+    type Item = Result<T, RowError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buf.clear();
+        let offset = self.offset;
+        match self.reader.read_until(b'\n', &mut self.buf) {
+            Err(e) => return Some(Err(RowError::new(offset, e))),
+            Ok(0) => return None,
+            Ok(n) => self.offset += n as u64,
+        }
+        let line = self.buf.strip_suffix(b"\n").unwrap_or(&self.buf);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let row = std::str::from_utf8(line)
+            .map_err(|e| RowParseError::new("TSV", e.to_string()))
+            .and_then(T::from_str);
+        Some(row.map_err(|e| RowError::new(offset, e)))
+    }
+}
+
+/// An output that may have to write a trailer after its last row, e.g. a compressed stream.
+pub trait Finish: Write {
+    fn finish(self) -> std::io::Result<()>;
+}
+
+impl Finish for File {
+    fn finish(mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+}
+
+/// Buffered TSV output that flushes every `flush_interval` bytes.
+pub struct TsvWriter<W: Write> {
+    writer: BufWriter<W>,
+    flush_interval: usize,
+    rows: u64,
+}
+
+impl<W: Write> TsvWriter<W> {
+    pub fn new(writer: W, flush_interval: usize) -> Self {
+        TsvWriter {
+            writer: BufWriter::new(writer),
+            flush_interval,
+            rows: 0,
+        }
+    }
+
+    pub fn write_row<T: Display>(&mut self, row: &T) -> std::io::Result<()> {
+        writeln!(self.writer, "{row}")?;
+        self.rows += 1;
+        if self.writer.buffer().len() >= self.flush_interval {
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes a line that is not counted as a row, e.g. a column header.
+    pub fn write_header(&mut self, header: &str) -> std::io::Result<()> {
+        writeln!(self.writer, "{header}")
+    }
+
+    /// Number of rows written so far.
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Flushes the remaining output and returns the number of rows written.
+    pub fn finish(self) -> std::io::Result<u64> where W: Finish {
+        self.writer.into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(W::finish)?;
+        Ok(self.rows)
+    }
+}
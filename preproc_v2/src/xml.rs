@@ -0,0 +1,104 @@
+//! Row-by-row reading of the Stack Exchange XML dumps.
+//!
+//! Every dump file is a single root element holding one self-closing `<row ... />` element per
+//! record, with the record's fields as attributes.
+
+use std::io::{BufRead, ErrorKind};
+use std::str::FromStr;
+use chrono::NaiveDateTime;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use crate::error::{Error, Result, RowError};
+
+pub struct RowReader<R: BufRead> {
+    reader: Reader<R>,
+    buf: Vec<u8>,
+    base_offset: u64,
+}
+
+impl<R: BufRead> RowReader<R> {
+    pub fn new(reader: R) -> Self {
+        RowReader {
+            reader: Reader::from_reader(reader),
+            buf: Vec::new(),
+            base_offset: 0,
+        }
+    }
+
+    /// Reads rows from a stream positioned at byte `offset` of a dump, between two rows. Offsets
+    /// are reported relative to the start of the dump.
+    pub fn resume_at(reader: R, offset: u64) -> Self {
+        let mut reader = Reader::from_reader(reader);
+        // the root element was opened before `offset`, so its closing tag has no match
+        reader.check_end_names(false);
+        RowReader {
+            reader,
+            buf: Vec::new(),
+            base_offset: offset,
+        }
+    }
+
+    /// Byte offset of the next unread event.
+    pub fn position(&self) -> u64 {
+        self.base_offset + self.reader.buffer_position() as u64
+    }
+
+    /// Reads rows until `f` maps one to `Some`, returning the result with the byte offset of the
+    /// row, or `None` at the end of the dump. Errors from `f` and malformed XML are returned as
+    /// errors of the row at which they occurred; reading may continue after them.
+    pub fn next_map<T>(
+        &mut self,
+        mut f: impl FnMut(&BytesStart) -> Result<Option<T>>,
+    ) -> Option<std::result::Result<(u64, T), RowError>> {
+        loop {
+            self.buf.clear();
+            let offset = self.position();
+            let mapped = match self.reader.read_event_into(&mut self.buf) {
+                Err(e) => {
+                    let mut e = Error::from(e);
+                    if self.position() <= offset && !matches!(e, Error::Io(_)) {
+                        // the reader cannot get past this error, so skipping it would loop forever
+                        e = Error::Io(std::io::Error::new(ErrorKind::InvalidData, format!("at byte {offset}: {e}")));
+                    }
+                    return Some(Err(RowError::new(offset, e)));
+                }
+                Ok(Event::Eof) => return None,
+                Ok(Event::Empty(element)) if element.name().as_ref() == b"row" => f(&element),
+                _ => Ok(None),
+            };
+            match mapped {
+                Ok(Some(t)) => return Some(Ok((offset, t))),
+                Ok(None) => {}
+                Err(e) => return Some(Err(RowError::new(offset, e))),
+            }
+        }
+    }
+}
+
+/// Parses the value of attribute `name`.
+pub fn parse_attribute<T: FromStr>(name: &'static str, value: &[u8]) -> Result<T>
+where T::Err: std::fmt::Display {
+    let s = std::str::from_utf8(value)
+        .map_err(|e| Error::bad_attribute(name, value, e))?;
+    T::from_str(s)
+        .map_err(|e| Error::bad_attribute(name, value, e))
+}
+
+/// Parses the value of date attribute `name`, in [`crate::DATE_FORMAT`].
+pub fn parse_date_attribute(name: &'static str, value: &[u8]) -> Result<NaiveDateTime> {
+    let s = std::str::from_utf8(value)
+        .map_err(|e| Error::bad_attribute(name, value, e))?;
+    NaiveDateTime::parse_from_str(s, crate::DATE_FORMAT)
+        .map_err(|e| Error::bad_attribute(name, value, e))
+}
+
+/// Parses the value of GUID attribute `name`, such as a `RevisionGUID`, into its 128 bits.
+pub fn parse_guid_attribute(name: &'static str, value: &[u8]) -> Result<u128> {
+    let s = std::str::from_utf8(value)
+        .map_err(|e| Error::bad_attribute(name, value, e))?;
+    if s.len() != 36 {
+        return Err(Error::bad_attribute(name, value, "expected 32 hex digits in 5 groups"));
+    }
+    u128::from_str_radix(&s.replace('-', ""), 16)
+        .map_err(|e| Error::bad_attribute(name, value, e))
+}